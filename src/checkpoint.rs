@@ -0,0 +1,149 @@
+use crate::interrupt::is_interrupted;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// first word of a checkpoint file, followed by the fingerprint of the input chunks
+const HEADER: &str = "mbtiles_tool-checkpoint";
+
+// The sidecar file of an output archive.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+  let mut path = output.as_os_str().to_owned();
+  path.push(".checkpoint");
+  PathBuf::from(path)
+}
+
+// Records the input chunks whose tiles have all been committed to the output, one chunk index
+// per line, so a later run can skip them.
+pub struct Checkpoint {
+  path: PathBuf,
+  resume: bool,
+  file: Mutex<Option<File>>,
+  // set when a tile is lost to a panic, nothing is recorded from then on
+  failed: AtomicBool,
+}
+
+impl Checkpoint {
+  pub fn new(path: PathBuf, resume: bool) -> Arc<Checkpoint> {
+    if resume && !path.exists() {
+      panic!(
+        "There is no checkpoint to resume from at {}",
+        path.display()
+      );
+    }
+    Arc::new(Checkpoint {
+      path,
+      resume,
+      file: Mutex::new(None),
+      failed: AtomicBool::new(false),
+    })
+  }
+
+  // Called by the reader once it split the input into chunks. Returns the chunks a resumed run
+  // can skip, and starts a new checkpoint with them.
+  pub fn start(&self, fingerprint: &str) -> HashSet<usize> {
+    let mut done = HashSet::new();
+    if self.resume {
+      let mut lines = BufReader::new(File::open(&self.path).unwrap()).lines();
+      let header = lines.next().map(|line| line.unwrap()).unwrap_or_default();
+      if header != format!("{} {}", HEADER, fingerprint) {
+        panic!(
+          "The checkpoint {} was made for a different input, run again without --resume",
+          self.path.display()
+        );
+      }
+      // the last line may have been cut by a crash
+      done.extend(lines.filter_map(|line| line.ok()?.parse::<usize>().ok()));
+    }
+
+    let mut contents = format!("{} {}\n", HEADER, fingerprint);
+    for chunk in done.iter() {
+      contents.push_str(&format!("{}\n", chunk));
+    }
+    std::fs::write(&self.path, contents).unwrap();
+    let file = OpenOptions::new().append(true).open(&self.path).unwrap();
+    *self.file.lock().unwrap() = Some(file);
+    done
+  }
+
+  fn record(&self, chunk: usize) {
+    if self.failed.load(Ordering::SeqCst) || is_interrupted() {
+      return;
+    }
+    if let Some(file) = self.file.lock().unwrap().as_mut() {
+      file.write_all(format!("{}\n", chunk).as_bytes()).unwrap();
+    }
+  }
+
+  // Removes the checkpoint once the whole input made it to the output.
+  pub fn finish(&self) {
+    if !self.failed.load(Ordering::SeqCst) && !is_interrupted() {
+      *self.file.lock().unwrap() = None;
+      std::fs::remove_file(&self.path).unwrap();
+    }
+  }
+}
+
+struct ChunkToken {
+  chunk: usize,
+  checkpoint: Arc<Checkpoint>,
+}
+
+impl Drop for ChunkToken {
+  fn drop(&mut self) {
+    self.checkpoint.record(self.chunk);
+  }
+}
+
+// Held by every tile read from a chunk and every tile made from them. Writers keep them until
+// the tiles are committed, so the chunk is recorded once the last one is dropped.
+#[derive(Clone)]
+pub struct ChunkRef(Arc<ChunkToken>);
+
+impl ChunkRef {
+  pub fn new(chunk: usize, checkpoint: Arc<Checkpoint>) -> ChunkRef {
+    ChunkRef(Arc::new(ChunkToken { chunk, checkpoint }))
+  }
+}
+
+impl Drop for ChunkRef {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.0.checkpoint.failed.store(true, Ordering::SeqCst);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_checkpoint() {
+    let path = std::env::temp_dir().join(format!("checkpoint-test-{}", std::process::id()));
+
+    let checkpoint = Checkpoint::new(path.clone(), false);
+    assert!(checkpoint.start("abc").is_empty());
+    let first = ChunkRef::new(0, checkpoint.clone());
+    let second = ChunkRef::new(1, checkpoint.clone());
+    let tile = first.clone();
+    drop(first);
+    drop(second);
+    // a tile of the first chunk is still waiting to be committed
+    assert_eq!(
+      std::fs::read_to_string(&path).unwrap(),
+      "mbtiles_tool-checkpoint abc\n1\n"
+    );
+    drop(tile);
+
+    let resumed = Checkpoint::new(path.clone(), true);
+    assert_eq!(resumed.start("abc"), HashSet::from([0, 1]));
+    resumed.finish();
+    assert!(!path.exists());
+  }
+}
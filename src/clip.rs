@@ -0,0 +1,252 @@
+use crate::region::{Coverage, Region, TileCoverage};
+use crate::vector_tile_ops::{self, TileError};
+use crate::writer;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use mbtiles_tool::interrupt;
+use mbtiles_tool::reader::{self, Reader};
+use mbtiles_tool::tilebelt::{self, Tile};
+use mbtiles_tool::vector_tile;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// used to index the region when the input doesn't tell its maxzoom, deeper tiles
+// are clipped against the region around their closest indexed ancestor
+const DEFAULT_INDEX_ZOOM: u8 = 14;
+
+fn clip_tile(
+  tile: vector_tile::Tile,
+  xyz: &Tile,
+  region: &Region,
+  coverage: &TileCoverage,
+) -> Result<vector_tile::Tile, TileError> {
+  let mut out = tile;
+  for layer in out.layers.iter_mut() {
+    let extent = layer.extent();
+    let rectangle = region.tile_rectangle(xyz, extent);
+    let rings = match rectangle {
+      Some(_) => vec![],
+      None => coverage.tile_rings(xyz, extent),
+    };
+
+    let mut features: Vec<vector_tile::tile::Feature> = Vec::with_capacity(layer.features.len());
+    for mut feature in layer.features.drain(..) {
+      let geom_type = feature
+        .r#type
+        .unwrap_or(vector_tile::tile::GeomType::Unknown as i32);
+      if geom_type == vector_tile::tile::GeomType::Unknown as i32 {
+        // without a type the geometry can't be clipped, so it can't be kept inside the region
+        continue;
+      }
+      let clipped_geometry = match rectangle {
+        Some(bbox) => vector_tile_ops::clip_geometry_to_bbox(geom_type, &feature.geometry, bbox)?,
+        None => vector_tile_ops::clip_geometry_to_rings(geom_type, &feature.geometry, &rings)?,
+      };
+      if clipped_geometry.is_empty() {
+        // this feature is completely outside of the region
+        continue;
+      }
+      feature.geometry = clipped_geometry;
+      features.push(feature);
+    }
+    layer.features = features;
+  }
+  out.layers.retain(|layer| !layer.features.is_empty());
+  Ok(out)
+}
+
+fn initialize_processors(
+  process_queue_rx: crossbeam_channel::Receiver<reader::TileData>,
+  output_queue_tx: crossbeam_channel::Sender<reader::TileData>,
+  region: Arc<Region>,
+  coverage: Arc<TileCoverage>,
+  clip_geometry: bool,
+  kept_count: Arc<AtomicU64>,
+) -> Vec<thread::JoinHandle<()>> {
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let mut processor_thread_handles = Vec::with_capacity(max_workers);
+
+  for worker_id in 0..max_workers {
+    let thread_process_queue_rx = process_queue_rx.clone();
+    let thread_output_queue_tx = output_queue_tx.clone();
+    let thread_region = region.clone();
+    let thread_coverage = coverage.clone();
+    let thread_kept_count = kept_count.clone();
+    processor_thread_handles.push(thread::spawn(move || {
+      while let Ok(tile_data) = thread_process_queue_rx.recv() {
+        if interrupt::is_interrupted() {
+          continue;
+        }
+        let tile_data = match thread_coverage.get(&tile_data.tile) {
+          Coverage::Outside => continue,
+          Coverage::Partial if clip_geometry => {
+            let clipped_tile = vector_tile_ops::decode_tile(&tile_data.data)
+              .and_then(|tile| clip_tile(tile, &tile_data.tile, &thread_region, &thread_coverage));
+            match clipped_tile {
+              // the tile only touched the region with its buffer
+              Ok(clipped_tile) if clipped_tile.layers.is_empty() => continue,
+              Ok(clipped_tile) => {
+                let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+                gz.write_all(&clipped_tile.encode_to_vec()).unwrap();
+                reader::TileData {
+                  tile: tile_data.tile,
+                  data: Arc::new(gz.finish().unwrap()),
+                  chunk: tile_data.chunk.clone(),
+                }
+              }
+              Err(err) => {
+                let (x, y, z) = tile_data.tile;
+                println!("Keeping tile {}/{}/{} unclipped: {}", z, x, y, err);
+                tile_data
+              }
+            }
+          }
+          _ => tile_data,
+        };
+        thread_kept_count.fetch_add(1, Ordering::Relaxed);
+        thread_output_queue_tx.send(tile_data).unwrap();
+      }
+      println!("Worker {} finished.", worker_id);
+    }));
+  }
+  processor_thread_handles
+}
+
+// Shrinks the `bounds` to the region, and moves the `center` into them if it falls outside.
+fn clip_metadata(metadata: &mut HashMap<String, String>, region: &Region) {
+  let (west, south, east, north) = region.lng_lat_bbox();
+  if west > east {
+    // regions across the antimeridian keep the original bounds
+    return;
+  }
+  let bounds: Vec<f64> = metadata
+    .get("bounds")
+    .and_then(|b| b.split(',').map(|v| v.trim().parse::<f64>().ok()).collect())
+    .unwrap_or_else(|| vec![-180.0, -85.0511, 180.0, 85.0511]);
+  if bounds.len() != 4 {
+    return;
+  }
+  let clipped = [
+    bounds[0].max(west),
+    bounds[1].max(south),
+    bounds[2].min(east),
+    bounds[3].min(north),
+  ];
+  if clipped[0] > clipped[2] || clipped[1] > clipped[3] {
+    // the region doesn't overlap the input at all, there are no tiles to describe
+    return;
+  }
+  metadata.insert(
+    "bounds".to_string(),
+    clipped
+      .iter()
+      .map(|v| v.to_string())
+      .collect::<Vec<String>>()
+      .join(","),
+  );
+
+  if let Some(center) = metadata.get("center").cloned() {
+    let mut values: Vec<String> = center.split(',').map(|v| v.trim().to_string()).collect();
+    let position: Vec<f64> = values.iter().filter_map(|v| v.parse().ok()).collect();
+    if values.len() == 3 && position.len() == 3 {
+      let (lng, lat) = (position[0], position[1]);
+      if lng < clipped[0] || lng > clipped[2] || lat < clipped[1] || lat > clipped[3] {
+        values[0] = ((clipped[0] + clipped[2]) / 2.0).to_string();
+        values[1] = ((clipped[1] + clipped[3]) / 2.0).to_string();
+        metadata.insert("center".to_string(), values.join(","));
+      }
+    }
+  }
+}
+
+pub fn clip(input: PathBuf, output: PathBuf, region: Region, clip_geometry: bool, dedup: bool) {
+  let mut reader = Reader::new(input);
+  let mut metadata_rows = reader.read_metadata();
+  let maxzoom = metadata_rows
+    .get("maxzoom")
+    .and_then(|z| z.parse::<u8>().ok())
+    .unwrap_or(DEFAULT_INDEX_ZOOM);
+
+  let is_vector = matches!(
+    metadata_rows.get("format").map(|f| f.as_str()),
+    None | Some("pbf") | Some("mvt")
+  );
+  if clip_geometry && !is_vector {
+    println!("Input file does not contain vector tiles, only whole tiles will be clipped");
+  }
+
+  println!("Indexing the region down to z{}...", maxzoom);
+  let coverage = region.tile_coverage(maxzoom);
+  clip_metadata(&mut metadata_rows, &region);
+
+  println!("Clipping tiles and saving to {}...", output.display());
+
+  let (process_queue_tx, process_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(reader.queue_capacity());
+  let (output_queue_tx, output_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(reader.queue_capacity());
+
+  let kept_count = Arc::new(AtomicU64::new(0));
+  let processor_thread_handles = initialize_processors(
+    process_queue_rx,
+    output_queue_tx,
+    Arc::new(region),
+    Arc::new(coverage),
+    clip_geometry && is_vector,
+    kept_count.clone(),
+  );
+  let writer_handle =
+    writer::initialize_writer(output.clone(), output_queue_rx, metadata_rows, dedup, false);
+
+  let mut tile_count = 0;
+  for tile in reader.iter() {
+    tile_count += 1;
+    // the region is indexed on XYZ tiles
+    let flipped_tile = tilebelt::flip_x(tile.tile);
+    process_queue_tx
+      .send(reader::TileData {
+        tile: flipped_tile,
+        data: tile.data,
+        chunk: tile.chunk,
+      })
+      .unwrap();
+  }
+  drop(process_queue_tx);
+
+  for handle in processor_thread_handles {
+    handle.join().unwrap();
+  }
+  writer_handle.join().unwrap();
+
+  println!(
+    "Kept {} of {} tiles in {}",
+    kept_count.load(Ordering::Relaxed),
+    tile_count,
+    output.display()
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_clip_metadata() {
+    let mut metadata: HashMap<String, String> = [
+      ("bounds", "-180,-85.0511,180,85.0511"),
+      ("center", "-122.4,37.8,10"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    clip_metadata(&mut metadata, &Region::from_bbox((5.9, 45.8, 10.5, 47.8)));
+    assert_eq!(metadata["bounds"], "5.9,45.8,10.5,47.8");
+    assert_eq!(metadata["center"], "8.2,46.8,10");
+  }
+}
@@ -5,7 +5,7 @@ use std::{path::Path, sync::Arc};
 use flate2::{write::GzEncoder, Compression};
 use walkdir::WalkDir;
 
-use crate::tilebelt;
+use mbtiles_tool::{interrupt, reader, tilebelt};
 
 fn maybe_compress(data: Vec<u8>) -> Vec<u8> {
   if data[0] != 0x1f && data[1] != 0x8b {
@@ -20,9 +20,9 @@ fn maybe_compress(data: Vec<u8>) -> Vec<u8> {
 fn initialize_processors(
   input: &Path,
   process_queue_rx: crossbeam_channel::Receiver<PathBuf>,
-  output_queue_tx: crossbeam_channel::Sender<tilebelt::TileData>,
+  output_queue_tx: crossbeam_channel::Sender<reader::TileData>,
 ) -> Vec<thread::JoinHandle<()>> {
-  let max_workers = std::cmp::max(num_cpus::get() - 2, 2);
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
   let mut processor_thread_handles = Vec::with_capacity(max_workers);
 
   for worker_id in 0..max_workers {
@@ -31,6 +31,9 @@ fn initialize_processors(
     let thread_output_queue_tx = output_queue_tx.clone();
     processor_thread_handles.push(thread::spawn(move || {
       while let Ok(path) = thread_process_queue_rx.recv() {
+        if interrupt::is_interrupted() {
+          continue;
+        }
         if path.is_dir() {
           continue;
         }
@@ -49,9 +52,10 @@ fn initialize_processors(
         let data = std::fs::read(path).unwrap();
         let compressed_data = maybe_compress(data);
         thread_output_queue_tx
-          .send(tilebelt::TileData {
+          .send(reader::TileData {
             tile,
             data: Arc::new(compressed_data),
+            chunk: None,
           })
           .unwrap();
       }
@@ -61,7 +65,7 @@ fn initialize_processors(
   processor_thread_handles
 }
 
-pub fn convert(input: PathBuf, output: PathBuf) {
+pub fn convert(input: PathBuf, output: PathBuf, dedup: bool) {
   let metadata_json = input.join("metadata.json");
   let mut metadata: HashMap<String, String> = HashMap::new();
   if metadata_json.exists() {
@@ -78,13 +82,20 @@ pub fn convert(input: PathBuf, output: PathBuf) {
     });
   }
 
-  let (process_queue_tx, process_queue_rx) = crossbeam_channel::unbounded::<PathBuf>();
-  let (tile_queue_tx, tile_queue_rx) = crossbeam_channel::unbounded::<tilebelt::TileData>();
-  let writer_handle = crate::writer::initialize_writer(output, tile_queue_rx, metadata);
+  // the tile size isn't known before reading the files
+  let queue_capacity = reader::queue_capacity(reader::DEFAULT_TILE_SIZE);
+  let (process_queue_tx, process_queue_rx) = crossbeam_channel::bounded::<PathBuf>(queue_capacity);
+  let (tile_queue_tx, tile_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(queue_capacity);
+  let writer_handle =
+    crate::writer::initialize_writer(output, tile_queue_rx, metadata, dedup, false);
   let processor_handles = initialize_processors(&input, process_queue_rx, tile_queue_tx);
 
   let files = WalkDir::new(input).min_depth(1).max_depth(3);
   for entry in files {
+    if interrupt::is_interrupted() {
+      break;
+    }
     process_queue_tx
       .send(entry.unwrap().path().to_path_buf())
       .unwrap();
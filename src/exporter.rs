@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use mbtiles_tool::interrupt;
+use mbtiles_tool::reader::{self, try_decompress, Reader};
+use mbtiles_tool::tilebelt;
+
+fn initialize_processors(
+  output: &Path,
+  process_queue_rx: crossbeam_channel::Receiver<reader::TileData>,
+  extension: &str,
+  decompress: bool,
+  exported_count: Arc<AtomicU64>,
+  skipped_count: Arc<AtomicU64>,
+) -> Vec<thread::JoinHandle<()>> {
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let mut processor_thread_handles = Vec::with_capacity(max_workers);
+
+  for worker_id in 0..max_workers {
+    let thread_output = output.to_path_buf();
+    let thread_extension = extension.to_string();
+    let thread_process_queue_rx = process_queue_rx.clone();
+    let thread_exported_count = exported_count.clone();
+    let thread_skipped_count = skipped_count.clone();
+    processor_thread_handles.push(thread::spawn(move || {
+      // the {z}/{x} directories this worker already created
+      let mut created_dirs = HashSet::<(u32, u32)>::new();
+      while let Ok(tile_data) = thread_process_queue_rx.recv() {
+        if interrupt::is_interrupted() {
+          // drain the queue without writing
+          continue;
+        }
+        // mbtiles rows are TMS, the directory tree is XYZ
+        let (x, y, z) = tilebelt::flip_x(tile_data.tile);
+        let decompressed = if decompress {
+          match try_decompress(tile_data.data.to_vec()) {
+            Ok(data) => Some(data),
+            Err(err) => {
+              println!(
+                "Skipping export of tile {}/{}/{}: invalid gzip data: {}",
+                z, x, y, err
+              );
+              thread_skipped_count.fetch_add(1, Ordering::Relaxed);
+              continue;
+            }
+          }
+        } else {
+          None
+        };
+        let dir = thread_output.join(z.to_string()).join(x.to_string());
+        if created_dirs.insert((z, x)) {
+          std::fs::create_dir_all(&dir).unwrap();
+        }
+        let path = dir.join(format!("{}.{}", y, thread_extension));
+        std::fs::write(path, decompressed.as_deref().unwrap_or(&tile_data.data)).unwrap();
+        thread_exported_count.fetch_add(1, Ordering::Relaxed);
+      }
+      println!("Worker {} finished.", worker_id);
+    }));
+  }
+  processor_thread_handles
+}
+
+// The metadata is written last, so an interrupted export is marked as partial like an archive.
+fn write_metadata(output: &Path, metadata: &HashMap<String, String>) {
+  let mut sorted_metadata: BTreeMap<&str, &str> = metadata
+    .iter()
+    .map(|(name, value)| (name.as_str(), value.as_str()))
+    .collect();
+  if interrupt::is_interrupted() {
+    sorted_metadata.insert(interrupt::PARTIAL_METADATA_KEY, "true");
+  }
+  // values are written as strings so `convert` reads them back unchanged
+  let metadata_json = serde_json::to_string_pretty(&sorted_metadata).unwrap();
+  std::fs::write(output.join("metadata.json"), metadata_json).unwrap();
+}
+
+pub fn export(input: PathBuf, output: PathBuf, extension: Option<String>, decompress: bool) {
+  let mut reader = Reader::new(input);
+  let metadata = reader.read_metadata();
+
+  let extension = extension
+    .or_else(|| metadata.get("format").cloned())
+    .unwrap_or_else(|| "pbf".to_string());
+
+  println!(
+    "Exporting tiles to {}/{{z}}/{{x}}/{{y}}.{}...",
+    output.display(),
+    extension
+  );
+
+  let (process_queue_tx, process_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(reader.queue_capacity());
+  let exported_count = Arc::new(AtomicU64::new(0));
+  let skipped_count = Arc::new(AtomicU64::new(0));
+  let processor_handles = initialize_processors(
+    &output,
+    process_queue_rx,
+    &extension,
+    decompress,
+    exported_count.clone(),
+    skipped_count.clone(),
+  );
+
+  for tile in reader.iter() {
+    process_queue_tx.send(tile).unwrap();
+  }
+  drop(process_queue_tx);
+
+  for handle in processor_handles {
+    handle.join().unwrap();
+  }
+  write_metadata(&output, &metadata);
+
+  let skipped_count = skipped_count.load(Ordering::Relaxed);
+  if skipped_count > 0 {
+    println!(
+      "{} tiles could not be decompressed and were left out",
+      skipped_count
+    );
+  }
+  println!(
+    "Exported {} tiles to {}",
+    exported_count.load(Ordering::Relaxed),
+    output.display()
+  );
+}
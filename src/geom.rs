@@ -1,11 +1,137 @@
+// Tile coordinates are i32 like in MVT geometries. Clipping also works on i64 coordinates, for
+// geometries that are scaled up before they are clipped.
+pub trait Coordinate: Copy + Ord + Into<i128> + TryFrom<i128> {}
+
+impl Coordinate for i32 {}
+impl Coordinate for i64 {}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Point {
-  pub x: i32,
-  pub y: i32,
+pub struct Point<T = i32> {
+  pub x: T,
+  pub y: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineString<T = i32> {
+  pub points: Vec<Point<T>>,
 }
 
+// a closed ring, the closing point is implied and not repeated at the end
+pub type Ring<T = i32> = LineString<T>;
+
+// A polygon following the MVT v2 winding rules: the exterior ring has a positive area in tile
+// coordinates (clockwise, since y points down), its interior rings have a negative area.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct LineString {
-  pub points: Vec<Point>,
+pub struct Polygon<T = i32> {
+  pub exterior: Ring<T>,
+  pub interiors: Vec<Ring<T>>,
+}
+
+pub type MultiPolygon = Vec<Polygon>;
+
+// Twice the signed area of a ring with the surveyor's formula, in i64 so it can't overflow.
+pub fn signed_area(ring: &Ring) -> i64 {
+  let points = &ring.points;
+  let mut area: i64 = 0;
+  for (i, a) in points.iter().enumerate() {
+    let b = points[(i + 1) % points.len()];
+    area += a.x as i64 * b.y as i64 - b.x as i64 * a.y as i64;
+  }
+  area
+}
+
+// Removes repeated points and the closing point, if any.
+pub fn clean_ring(ring: Ring) -> Ring {
+  let mut points = ring.points;
+  points.dedup();
+  if points.len() > 1 && points.first() == points.last() {
+    points.pop();
+  }
+  Ring { points }
+}
+
+// A ring needs at least 3 distinct points and some area to be drawn.
+pub fn is_valid_ring(ring: &Ring) -> bool {
+  ring.points.len() >= 3 && signed_area(ring) != 0
+}
+
+// Groups rings into polygons, each exterior ring is followed by its interior rings. Like
+// vector-tile-js, the winding of the first ring decides which rings are exterior, so tiles
+// written with the opposite winding order (MVT v1) are read correctly too. Degenerate rings are
+// dropped.
+pub fn classify_rings(rings: Vec<Ring>) -> MultiPolygon {
+  let mut polygons = MultiPolygon::new();
+  let mut exterior_sign: Option<bool> = None;
+  for ring in rings {
+    let ring = clean_ring(ring);
+    if !is_valid_ring(&ring) {
+      continue;
+    }
+    let positive = signed_area(&ring) > 0;
+    let is_exterior = *exterior_sign.get_or_insert(positive) == positive;
+    if is_exterior {
+      polygons.push(Polygon {
+        exterior: ring,
+        interiors: vec![],
+      });
+    } else {
+      // the first ring is always exterior, so there is a polygon to add the hole to
+      polygons.last_mut().unwrap().interiors.push(ring);
+    }
+  }
+  polygons
+}
+
+// Reverses the ring if needed, so it has a positive area when `exterior`, negative otherwise.
+pub fn orient_ring(ring: &mut Ring, exterior: bool) {
+  if (signed_area(ring) > 0) != exterior {
+    ring.points.reverse();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ring(points: &[(i32, i32)]) -> Ring {
+    Ring {
+      points: points.iter().map(|&(x, y)| Point { x, y }).collect(),
+    }
+  }
+
+  #[test]
+  fn test_classify_rings() {
+    // clockwise in tile coordinates
+    let outer = ring(&[(0, 0), (10, 0), (10, 10), (0, 10)]);
+    let hole = ring(&[(2, 2), (2, 8), (8, 8), (8, 2)]);
+    let second = ring(&[(20, 0), (30, 0), (30, 10), (20, 10), (20, 0)]);
+    let degenerate = ring(&[(0, 0), (5, 5), (10, 10)]);
+    assert_eq!(signed_area(&outer), 200);
+    assert_eq!(signed_area(&hole), -72);
+
+    let polygons = classify_rings(vec![
+      outer.clone(),
+      hole.clone(),
+      degenerate,
+      second.clone(),
+    ]);
+    assert_eq!(polygons.len(), 2);
+    assert_eq!(polygons[0].exterior, outer);
+    assert_eq!(polygons[0].interiors, vec![hole.clone()]);
+    // the closing point is removed
+    assert_eq!(polygons[1].exterior.points.len(), 4);
+    assert!(polygons[1].interiors.is_empty());
+
+    // reversed winding order
+    let mut reversed_outer = outer.clone();
+    reversed_outer.points.reverse();
+    let mut reversed_hole = hole.clone();
+    reversed_hole.points.reverse();
+    let polygons = classify_rings(vec![reversed_outer, reversed_hole]);
+    assert_eq!(polygons.len(), 1);
+    assert_eq!(polygons[0].interiors.len(), 1);
+
+    // a ring on its own is an exterior ring, whatever its winding
+    assert_eq!(classify_rings(vec![hole]).len(), 1);
+  }
 }
-pub type Polygon = LineString;
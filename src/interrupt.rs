@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// set in the metadata of archives written by an interrupted run
+pub const PARTIAL_METADATA_KEY: &str = "partial";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// On the first Ctrl-C the readers stop handing out tiles, so the pipeline drains and the output
+// is closed properly. A second Ctrl-C exits right away.
+pub fn install_handler() {
+  ctrlc::set_handler(|| {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+      std::process::exit(130);
+    }
+    eprintln!("Interrupted, finishing the tiles in progress. Press Ctrl-C again to exit now.");
+  })
+  .unwrap();
+}
+
+pub fn is_interrupted() -> bool {
+  INTERRUPTED.load(Ordering::Relaxed)
+}
+
+// Marks a mbtiles output as partial if the run was interrupted, or clears the marker left by an
+// earlier interrupted run.
+pub fn mark_partial_output(connection: &sqlite::Connection) {
+  let statement = if is_interrupted() {
+    format!(
+      "INSERT OR REPLACE INTO metadata (name, value) VALUES ('{}', 'true');",
+      PARTIAL_METADATA_KEY
+    )
+  } else {
+    format!(
+      "DELETE FROM metadata WHERE name = '{}';",
+      PARTIAL_METADATA_KEY
+    )
+  };
+  connection.execute(statement).unwrap();
+}
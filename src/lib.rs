@@ -1,3 +1,10 @@
+pub mod checkpoint;
+pub mod interrupt;
+pub mod pmtiles;
+pub mod reader;
+pub mod statistics;
+pub mod tilebelt;
+
 pub mod vector_tile {
   include!(concat!(env!("OUT_DIR"), "/vector_tile.rs"));
 }
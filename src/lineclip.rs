@@ -45,19 +45,19 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 // Cohen-Sutherland line clipping algorithm, adapted to efficiently
 // handle polylines rather than just segments
 
-use crate::geom::{LineString, Point, Polygon};
+use crate::geom::{Coordinate, LineString, Point, Ring};
 
-type BoundingBox = (i32, i32, i32, i32);
+type BoundingBox<T> = (T, T, T, T);
 
-pub fn lineclip(input: LineString, bbox: BoundingBox) -> Vec<LineString> {
-  let coords: Vec<Point> = input.points;
+pub fn lineclip<T: Coordinate>(input: LineString<T>, bbox: BoundingBox<T>) -> Vec<LineString<T>> {
+  let coords: Vec<Point<T>> = input.points;
   let len = coords.len();
   let mut code_a = bit_code(coords[0], &bbox);
-  let mut part = Vec::<Point>::new();
+  let mut part = Vec::<Point<T>>::new();
   let mut last_code: u8;
   let mut code_b: u8;
 
-  let mut result = Vec::<LineString>::new();
+  let mut result = Vec::<LineString<T>>::new();
 
   for i in 1..len {
     let mut a = coords[i - 1];
@@ -113,11 +113,11 @@ pub fn lineclip(input: LineString, bbox: BoundingBox) -> Vec<LineString> {
 
 // Sutherland-Hodgeman polygon clipping algorithm
 
-pub fn polygonclip(input: Polygon, bbox: BoundingBox) -> Polygon {
+pub fn polygonclip<T: Coordinate>(input: Ring<T>, bbox: BoundingBox<T>) -> Ring<T> {
   let mut points = input.points;
 
-  let mut result: Vec<Point>;
-  let mut prev: Point;
+  let mut result: Vec<Point<T>>;
+  let mut prev: Point<T>;
   let mut prev_inside: bool;
 
   for edge in [1, 2, 4, 8].iter() {
@@ -143,35 +143,50 @@ pub fn polygonclip(input: Polygon, bbox: BoundingBox) -> Polygon {
     }
   }
 
-  Polygon { points }
+  Ring { points }
+}
+
+// the value of one axis where the segment crosses `edge` on the other axis. Computed in i128,
+// the product of two coordinate differences overflows an i64 far outside of the tile. The
+// crossing lies between `a` and `b`, so the result fits in the coordinate type again.
+fn interpolate<T: Coordinate>(a: T, b: T, a_other: T, b_other: T, edge: T) -> T {
+  let (a, b, a_other, b_other, edge): (i128, i128, i128, i128, i128) = (
+    a.into(),
+    b.into(),
+    a_other.into(),
+    b_other.into(),
+    edge.into(),
+  );
+  let crossing = a + (b - a) * (edge - a_other) / (b_other - a_other);
+  T::try_from(crossing).ok().unwrap()
 }
 
 // intersect a segment against one of the 4 lines that make up the bbox
 
-fn intersect(a: Point, b: Point, edge: u8, bbox: &BoundingBox) -> Point {
+fn intersect<T: Coordinate>(a: Point<T>, b: Point<T>, edge: u8, bbox: &BoundingBox<T>) -> Point<T> {
   if edge & 8 > 0 {
     // top
     return Point {
-      x: a.x + ((b.x - a.x) * (bbox.3 - a.y) / (b.y - a.y)),
+      x: interpolate(a.x, b.x, a.y, b.y, bbox.3),
       y: bbox.3,
     };
   } else if edge & 4 > 0 {
     // bottom
     return Point {
-      x: a.x + ((b.x - a.x) * (bbox.1 - a.y) / (b.y - a.y)),
+      x: interpolate(a.x, b.x, a.y, b.y, bbox.1),
       y: bbox.1,
     };
   } else if edge & 2 > 0 {
     // right
     return Point {
       x: bbox.2,
-      y: a.y + ((b.y - a.y) * (bbox.2 - a.x) / (b.x - a.x)),
+      y: interpolate(a.y, b.y, a.x, b.x, bbox.2),
     };
   } else if edge & 1 > 0 {
     // left
     return Point {
       x: bbox.0,
-      y: a.y + ((b.y - a.y) * (bbox.0 - a.x) / (b.x - a.x)),
+      y: interpolate(a.y, b.y, a.x, b.x, bbox.0),
     };
   }
 
@@ -185,7 +200,7 @@ fn intersect(a: Point, b: Point, edge: u8, bbox: &BoundingBox) -> Point {
 //    mid  0001  0000  0010
 // bottom  0101  0100  0110
 
-fn bit_code(coords: Point, bbox: &BoundingBox) -> u8 {
+fn bit_code<T: Coordinate>(coords: Point<T>, bbox: &BoundingBox<T>) -> u8 {
   let mut code: u8 = 0;
 
   if coords.x < bbox.0 {
@@ -292,7 +307,7 @@ mod tests {
   fn test_polygonclip() {
     assert_eq!(
       polygonclip(
-        Polygon {
+        Ring {
           points: vec![
             Point { x: -10, y: 10 },
             Point { x: 0, y: 10 },
@@ -314,7 +329,7 @@ mod tests {
         },
         (0, 0, 30, 30)
       ),
-      Polygon {
+      Ring {
         points: vec![
           Point { x: 0, y: 10 },
           Point { x: 0, y: 10 },
@@ -334,5 +349,38 @@ mod tests {
         ]
       }
     );
+
+    // coordinates saturated at the i32 range don't overflow the interpolation against a
+    // buffered bbox
+    assert_eq!(
+      polygonclip(
+        Ring {
+          points: vec![
+            Point {
+              x: i32::MAX,
+              y: i32::MIN
+            },
+            Point {
+              x: i32::MIN,
+              y: i32::MAX
+            },
+            Point {
+              x: i32::MAX,
+              y: i32::MAX
+            },
+          ]
+        },
+        (-64, -64, 4160, 4160)
+      ),
+      Ring {
+        points: vec![
+          Point { x: 4160, y: 4160 },
+          Point { x: 4160, y: -64 },
+          Point { x: 63, y: -64 },
+          Point { x: -64, y: 63 },
+          Point { x: -64, y: 4160 },
+        ]
+      }
+    );
   }
 }
@@ -1,19 +1,29 @@
+mod clip;
 mod converter;
+mod exporter;
 mod geom;
 mod lineclip;
+mod merge;
 mod overzoom;
-mod reader;
-mod statistics;
+mod region;
+mod regionclip;
+mod server;
 mod subdivide;
-mod tilebelt;
+mod validate;
 mod vector_tile_ops;
 mod writer;
 
 use clap::{Parser, Subcommand};
+use mbtiles_tool::{interrupt, statistics};
 use std::io;
 use std::io::Write;
 use std::path::PathBuf;
 
+// distinct from validation errors (1) and panics (101), so CI can tell them apart
+const BUDGET_EXCEEDED_EXIT_CODE: i32 = 2;
+// the conventional exit code of a process stopped by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 #[derive(Debug, Parser)]
 #[clap(
   name = "mbtiles_tool",
@@ -32,17 +42,47 @@ enum Commands {
     about = "Subdivide a mbtiles archive into smaller archives on tile boundaries"
   )]
   Subdivide {
-    /// Subdivision configuration file
-    #[clap(value_parser)]
-    config: PathBuf,
+    /// Subdivision configuration file, input and output. The configuration file is left out
+    /// with --auto or --max-size
+    #[clap(
+      value_parser,
+      value_name = "PATHS",
+      min_values = 2,
+      max_values = 3,
+      required = true
+    )]
+    paths: Vec<PathBuf>,
 
-    /// Input
-    #[clap(value_parser)]
-    input: PathBuf,
+    #[clap(
+      short,
+      long,
+      value_parser = ["mbtiles", "pmtiles"],
+      default_value = "mbtiles",
+      help = "the format of the subdivided archives"
+    )]
+    format: String,
 
-    /// Output
-    #[clap(value_parser)]
-    output: PathBuf,
+    #[clap(
+      long,
+      value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+      conflicts_with = "max-size",
+      help = "split into this many archives of roughly equal size"
+    )]
+    auto: Option<usize>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "split into as few archives as possible, each at most this size (e.g. 2GB)"
+    )]
+    max_size: Option<String>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "continue an interrupted or crashed run, skipping the input it already wrote"
+    )]
+    resume: bool,
   },
 
   #[clap(
@@ -60,6 +100,58 @@ enum Commands {
 
     #[clap(short, long, value_parser, help = "the target zoom level")]
     target_zoom: u8,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "keep the extent of the source tiles and scale up the coordinates instead"
+    )]
+    keep_extent: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "generate the missing tiles with features up to the target zoom from their closest existing ancestor"
+    )]
+    fill_gaps: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      allow_hyphen_values = true,
+      conflicts_with_all = &["geojson", "tiles"],
+      help = "only extend tiles in a lon/lat bounding box: west,south,east,north"
+    )]
+    bbox: Option<String>,
+
+    #[clap(
+      long,
+      value_parser,
+      conflicts_with = "tiles",
+      help = "only extend tiles in the (Multi)Polygons of a GeoJSON file"
+    )]
+    geojson: Option<PathBuf>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "only extend tiles below the tiles of a JSON list of [x, y, z] tiles"
+    )]
+    tiles: Option<PathBuf>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "store identical tiles only once in a mbtiles output, with the map/images schema of mbutil and tippecanoe"
+    )]
+    dedup: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "continue an interrupted or crashed run, skipping the input it already wrote"
+    )]
+    resume: bool,
   },
 
   #[clap(name = "statistics", about = "Show statistics about a mbtiles archive")]
@@ -67,6 +159,44 @@ enum Commands {
     /// Input
     #[clap(value_parser)]
     input: PathBuf,
+
+    #[clap(
+      long,
+      value_parser = ["table", "json", "csv"],
+      default_value = "table",
+      help = "the output format"
+    )]
+    format: String,
+
+    #[clap(
+      long,
+      help = "decode the tiles to calculate statistics per zoom level and layer"
+    )]
+    layers: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "the maximum size of a tile at any zoom level, like 500KB"
+    )]
+    max_size: Option<String>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "the maximum tile size per zoom levels, like z0-6=200KB,z7-14=500KB. Takes precedence over --max-size"
+    )]
+    budget: Option<String>,
+  },
+
+  #[clap(
+    name = "validate",
+    about = "Check a mbtiles archive against the MBTiles 1.3 and MVT 2.1 specifications"
+  )]
+  Validate {
+    /// Input
+    #[clap(value_parser)]
+    input: PathBuf,
   },
 
   // Convert a directory of tiles to an mbtiles archive
@@ -83,38 +213,249 @@ enum Commands {
     /// Output
     #[clap(value_parser)]
     output: PathBuf,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "store identical tiles only once in a mbtiles output, with the map/images schema of mbutil and tippecanoe"
+    )]
+    dedup: bool,
+  },
+
+  // Export a mbtiles archive to a directory of tiles
+  // Similar to `mb-util <mbtiles> <directory>`
+  #[clap(
+    name = "export",
+    about = "Export a mbtiles archive to a directory of tiles"
+  )]
+  Export {
+    /// Input
+    #[clap(value_parser)]
+    input: PathBuf,
+
+    /// Output
+    #[clap(value_parser)]
+    output: PathBuf,
+
+    #[clap(
+      short,
+      long,
+      value_parser,
+      help = "the file extension for tiles (defaults to the `format` metadata, or pbf)"
+    )]
+    extension: Option<String>,
+
+    #[clap(long, value_parser, help = "write tiles uncompressed, stripping gzip")]
+    decompress: bool,
+  },
+
+  // Merge several mbtiles archives into one, combining layers in tiles that exist in more than one archive
+  // Similar to `tile-join -o <output> <input>...`
+  #[clap(
+    name = "merge",
+    about = "Merge several mbtiles archives into one, combining their layers"
+  )]
+  Merge {
+    /// Inputs
+    #[clap(value_parser, required = true, min_values = 2)]
+    inputs: Vec<PathBuf>,
+
+    #[clap(short, long, value_parser, help = "the output mbtiles archive")]
+    output: PathBuf,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "overzoom inputs with a lower maxzoom up to the highest maxzoom before merging"
+    )]
+    align_zoom: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "store identical tiles only once in a mbtiles output, with the map/images schema of mbutil and tippecanoe"
+    )]
+    dedup: bool,
+  },
+
+  // Clip a mbtiles archive to a region, keeping only the tiles that intersect it
+  #[clap(
+    name = "clip",
+    about = "Clip a mbtiles archive to a bounding box or a GeoJSON polygon"
+  )]
+  Clip {
+    /// Input
+    #[clap(value_parser)]
+    input: PathBuf,
+
+    /// Output
+    #[clap(value_parser)]
+    output: PathBuf,
+
+    #[clap(
+      long,
+      value_parser,
+      allow_hyphen_values = true,
+      required_unless_present = "geojson",
+      conflicts_with = "geojson",
+      help = "the region as a lon/lat bounding box: west,south,east,north"
+    )]
+    bbox: Option<String>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "the region as a GeoJSON file with (Multi)Polygon geometries"
+    )]
+    geojson: Option<PathBuf>,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "also clip feature geometry at the region boundary"
+    )]
+    clip_geometry: bool,
+
+    #[clap(
+      long,
+      value_parser,
+      help = "store identical tiles only once in a mbtiles output, with the map/images schema of mbutil and tippecanoe"
+    )]
+    dedup: bool,
+  },
+
+  #[clap(name = "serve", about = "Serve a mbtiles archive over HTTP")]
+  Serve {
+    /// Input
+    #[clap(value_parser)]
+    input: PathBuf,
+
+    #[clap(
+      long,
+      value_parser,
+      default_value = "127.0.0.1",
+      help = "the address to listen on, 0.0.0.0 serves the archive on every interface"
+    )]
+    host: String,
+
+    /// Port
+    #[clap(
+      short,
+      long,
+      value_parser,
+      default_value_t = 3000,
+      help = "the port to listen on"
+    )]
+    port: u16,
   },
-  // #[clap(
-  //   name = "serve",
-  //   about = "Serve a mbtiles archive over HTTP"
-  // )]
-  // Serve {
-  //   /// Input
-  //   #[clap(value_parser)]
-  //   input: PathBuf,
-
-  //   /// Port
-  //   #[clap(short, long, value_parser, help = "the port to listen on")]
-  //   port: u16,
-  // },
+}
+
+// Runs a command that writes an archive, stopping it cleanly on Ctrl-C. The handler is installed
+// after the overwrite prompts, so Ctrl-C still aborts those right away.
+fn run_interruptible(command: impl FnOnce()) {
+  interrupt::install_handler();
+  command();
+  if interrupt::is_interrupted() {
+    eprintln!("Interrupted, the output is incomplete and marked as partial in its metadata");
+    std::process::exit(INTERRUPTED_EXIT_CODE);
+  }
 }
 
 fn main() {
   let args = Cli::parse();
   match args.command {
     Commands::Subdivide {
-      config,
+      paths,
+      format,
+      auto,
+      max_size,
+      resume,
+    } => {
+      let auto_target = match (auto, max_size) {
+        (Some(count), _) => Some(subdivide::AutoTarget::Count(count)),
+        (None, Some(max_size)) => Some(subdivide::AutoTarget::MaxSize(statistics::parse_size(
+          &max_size,
+        ))),
+        (None, None) => None,
+      };
+      let (config, input, output) = match (paths.as_slice(), &auto_target) {
+        ([config, input, output], None) => (Some(config.clone()), input.clone(), output.clone()),
+        ([input, output], Some(_)) => (None, input.clone(), output.clone()),
+        (_, None) => panic!("A configuration file is required without --auto or --max-size"),
+        (_, Some(_)) => panic!("A configuration file can't be used with --auto or --max-size"),
+      };
+
+      // fail if input file does not exist
+      if !input.exists() {
+        panic!("Input file does not exist");
+      }
+
+      if resume {
+        if !subdivide::checkpoint_path(&output).exists() {
+          panic!("Output directory has no checkpoint to resume from");
+        }
+      } else {
+        // ask if we should overwrite the output directory
+        if output.exists() {
+          print!("Output directory already exists. Overwrite? (y/n) ");
+          io::stdout().flush().unwrap();
+          let mut input = String::new();
+          io::stdin().read_line(&mut input).unwrap();
+          if input.trim() != "y" {
+            panic!("Aborted");
+          }
+          // remove the output directory
+          std::fs::remove_dir_all(&output).unwrap();
+        }
+        std::fs::create_dir(&output).unwrap();
+      }
+
+      let config = match (config, auto_target) {
+        (Some(config), _) => config,
+        // the outputs being resumed were split with the config of the earlier run
+        (None, Some(_)) if resume => output.join("subdivide_config.json"),
+        (None, Some(target)) => subdivide::write_auto_config(&input, &output, target),
+        (None, None) => unreachable!(),
+      };
+      run_interruptible(|| subdivide::subdivide(config, input, output, format, resume));
+    }
+    Commands::Overzoom {
       input,
       output,
+      target_zoom,
+      keep_extent,
+      fill_gaps,
+      bbox,
+      geojson,
+      tiles,
+      dedup,
+      resume,
     } => {
       // fail if input file does not exist
       if !input.exists() {
         panic!("Input file does not exist");
       }
 
-      // ask if we should overwrite the output directory
-      if output.exists() {
-        print!("Output directory already exists. Overwrite? (y/n) ");
+      let area = match (bbox, geojson, tiles) {
+        (Some(bbox), _, _) => {
+          Some(region::Region::from_bbox(region::parse_bbox(&bbox)).tile_coverage(target_zoom))
+        }
+        (None, Some(geojson), _) => {
+          Some(region::Region::from_geojson_file(&geojson).tile_coverage(target_zoom))
+        }
+        (None, None, Some(tiles)) => Some(region::TileCoverage::from_tiles(
+          &overzoom::read_tile_list(&tiles),
+        )),
+        (None, None, None) => None,
+      };
+
+      if resume {
+        if !output.exists() {
+          panic!("Output file does not exist, there is nothing to resume");
+        }
+      } else if output.exists() {
+        // ask if we should overwrite the output file
+        print!("Output file already exists. Overwrite? (y/n) ");
         io::stdout().flush().unwrap();
         let mut input = String::new();
         io::stdin().read_line(&mut input).unwrap();
@@ -122,22 +463,84 @@ fn main() {
           panic!("Aborted");
         }
         // remove the output directory
-        std::fs::remove_dir_all(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
       }
-      std::fs::create_dir(&output).unwrap();
 
-      subdivide::subdivide(config, input, output);
+      run_interruptible(|| {
+        overzoom::overzoom(
+          input,
+          output,
+          target_zoom,
+          keep_extent,
+          fill_gaps,
+          area,
+          dedup,
+          resume,
+        )
+      });
     }
-    Commands::Overzoom {
+    Commands::Statistics {
       input,
-      output,
-      target_zoom,
+      format,
+      layers,
+      max_size,
+      budget,
     } => {
       // fail if input file does not exist
       if !input.exists() {
         panic!("Input file does not exist");
       }
 
+      let mut budget_rules = budget
+        .map(|b| statistics::parse_budget(&b))
+        .unwrap_or_default();
+      if let Some(max_size) = max_size {
+        budget_rules.push(statistics::BudgetRule {
+          min_zoom: 0,
+          max_zoom: u8::MAX,
+          max_size: statistics::parse_size(&max_size),
+        });
+      }
+
+      let mut stats = if layers {
+        statistics::calculate_layer_statistics(input.clone())
+      } else {
+        statistics::calculate_statistics(input.clone())
+      };
+      if !budget_rules.is_empty() {
+        stats.oversized_tiles = Some(statistics::check_budget(&input, &budget_rules));
+      }
+      let oversized_count = stats.oversized_tiles.as_ref().map_or(0, |t| t.len());
+      match format.as_str() {
+        "json" => stats.print_json(),
+        "csv" => stats.print_csv(),
+        _ => stats.print_cli_table(),
+      }
+      if oversized_count > 0 {
+        eprintln!("{} tiles exceed the size budget", oversized_count);
+        std::process::exit(BUDGET_EXCEEDED_EXIT_CODE);
+      }
+    }
+    Commands::Validate { input } => {
+      // fail if input file does not exist
+      if !input.exists() {
+        panic!("Input file does not exist");
+      }
+
+      if !validate::validate(input) {
+        std::process::exit(1);
+      }
+    }
+    Commands::Convert {
+      input,
+      output,
+      dedup,
+    } => {
+      // fail if input directory does not exist
+      if !input.exists() {
+        panic!("Input directory does not exist");
+      }
+
       // ask if we should overwrite the output file
       if output.exists() {
         print!("Output file already exists. Overwrite? (y/n) ");
@@ -147,27 +550,86 @@ fn main() {
         if input.trim() != "y" {
           panic!("Aborted");
         }
-        // remove the output directory
+        // remove the output file
         std::fs::remove_file(&output).unwrap();
       }
 
-      overzoom::overzoom(input, output, target_zoom);
+      run_interruptible(|| converter::convert(input, output, dedup));
     }
-    Commands::Statistics { input } => {
+    Commands::Export {
+      input,
+      output,
+      extension,
+      decompress,
+    } => {
       // fail if input file does not exist
       if !input.exists() {
         panic!("Input file does not exist");
       }
 
-      let stats = statistics::calculate_statistics(input);
-      stats.print_cli_table();
+      // ask if we should overwrite the output directory
+      if output.exists() {
+        print!("Output directory already exists. Overwrite? (y/n) ");
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        if input.trim() != "y" {
+          panic!("Aborted");
+        }
+        // remove the output directory
+        std::fs::remove_dir_all(&output).unwrap();
+      }
+      std::fs::create_dir(&output).unwrap();
+
+      run_interruptible(|| exporter::export(input, output, extension, decompress));
     }
-    Commands::Convert { input, output } => {
-      // fail if input directory does not exist
+    Commands::Merge {
+      inputs,
+      output,
+      align_zoom,
+      dedup,
+    } => {
+      // fail if any input file does not exist
+      for input in inputs.iter() {
+        if !input.exists() {
+          panic!("Input file {} does not exist", input.display());
+        }
+      }
+
+      // ask if we should overwrite the output file
+      if output.exists() {
+        print!("Output file already exists. Overwrite? (y/n) ");
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        if input.trim() != "y" {
+          panic!("Aborted");
+        }
+        // remove the output file
+        std::fs::remove_file(&output).unwrap();
+      }
+
+      run_interruptible(|| merge::merge(inputs, output, align_zoom, dedup));
+    }
+    Commands::Clip {
+      input,
+      output,
+      bbox,
+      geojson,
+      clip_geometry,
+      dedup,
+    } => {
+      // fail if input file does not exist
       if !input.exists() {
-        panic!("Input directory does not exist");
+        panic!("Input file does not exist");
       }
 
+      let region = match (bbox, geojson) {
+        (Some(bbox), _) => region::Region::from_bbox(region::parse_bbox(&bbox)),
+        (None, Some(geojson)) => region::Region::from_geojson_file(&geojson),
+        (None, None) => panic!("Either a bbox or a GeoJSON file is required"),
+      };
+
       // ask if we should overwrite the output file
       if output.exists() {
         print!("Output file already exists. Overwrite? (y/n) ");
@@ -181,7 +643,15 @@ fn main() {
         std::fs::remove_file(&output).unwrap();
       }
 
-      converter::convert(input, output);
+      run_interruptible(|| clip::clip(input, output, region, clip_geometry, dedup));
+    }
+    Commands::Serve { input, host, port } => {
+      // fail if input file does not exist
+      if !input.exists() {
+        panic!("Input file does not exist");
+      }
+
+      server::serve(input, host, port);
     }
   }
 }
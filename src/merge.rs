@@ -0,0 +1,771 @@
+use crate::geom::Point;
+use crate::region::parse_bounds;
+use crate::vector_tile_ops::{self, TileError};
+use crate::writer;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use mbtiles_tool::interrupt;
+use mbtiles_tool::reader::{self, read_metadata, Reader, TileIndex, TileLookup};
+use mbtiles_tool::tilebelt;
+use mbtiles_tool::vector_tile;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const DEFAULT_EXTENT: u32 = 4096;
+
+// Keeps track of the keys and values already present in a layer, so features from other
+// layers can have their tags remapped onto it.
+struct LayerBuilder {
+  layer: vector_tile::tile::Layer,
+  key_idx: HashMap<String, u32>,
+  value_idx: HashMap<Vec<u8>, u32>,
+}
+
+impl LayerBuilder {
+  fn new(layer: vector_tile::tile::Layer) -> LayerBuilder {
+    let key_idx = layer
+      .keys
+      .iter()
+      .enumerate()
+      .map(|(i, k)| (k.clone(), i as u32))
+      .collect();
+    let value_idx = layer
+      .values
+      .iter()
+      .enumerate()
+      .map(|(i, v)| (v.encode_to_vec(), i as u32))
+      .collect();
+    LayerBuilder {
+      layer,
+      key_idx,
+      value_idx,
+    }
+  }
+
+  fn key(&mut self, key: &str) -> u32 {
+    if let Some(idx) = self.key_idx.get(key) {
+      return *idx;
+    }
+    let idx = self.layer.keys.len() as u32;
+    self.layer.keys.push(key.to_string());
+    self.key_idx.insert(key.to_string(), idx);
+    idx
+  }
+
+  fn value(&mut self, value: &vector_tile::tile::Value) -> u32 {
+    let encoded = value.encode_to_vec();
+    if let Some(idx) = self.value_idx.get(&encoded) {
+      return *idx;
+    }
+    let idx = self.layer.values.len() as u32;
+    self.layer.values.push(value.clone());
+    self.value_idx.insert(encoded, idx);
+    idx
+  }
+
+  fn rescale(
+    features: &mut [vector_tile::tile::Feature],
+    from_extent: u32,
+    to_extent: u32,
+  ) -> Result<(), TileError> {
+    for feature in features.iter_mut() {
+      feature.geometry = vector_tile_ops::transform_geometry(&feature.geometry, |p| Point {
+        x: ((p.x as i64 * to_extent as i64) / from_extent as i64) as i32,
+        y: ((p.y as i64 * to_extent as i64) / from_extent as i64) as i32,
+      })?;
+    }
+    Ok(())
+  }
+
+  fn append(&mut self, other: vector_tile::tile::Layer) -> Result<(), TileError> {
+    let own_extent = self.layer.extent.unwrap_or(DEFAULT_EXTENT);
+    let other_extent = other.extent.unwrap_or(DEFAULT_EXTENT);
+    // the combined layer uses the larger extent, so no precision is lost
+    let extent = std::cmp::max(own_extent, other_extent);
+    if own_extent != extent {
+      LayerBuilder::rescale(&mut self.layer.features, own_extent, extent)?;
+      self.layer.extent = Some(extent);
+    }
+    let mut other_features = other.features;
+    if other_extent != extent {
+      LayerBuilder::rescale(&mut other_features, other_extent, extent)?;
+    }
+
+    for mut feature in other_features {
+      let mut tags = Vec::with_capacity(feature.tags.len());
+      for pair in feature.tags.chunks_exact(2) {
+        let (key, value) = match (
+          other.keys.get(pair[0] as usize),
+          other.values.get(pair[1] as usize),
+        ) {
+          (Some(key), Some(value)) => (key, value),
+          // tags pointing outside of the layer's keys or values can't be carried over
+          _ => continue,
+        };
+        tags.push(self.key(key));
+        tags.push(self.value(value));
+      }
+      feature.tags = tags;
+      self.layer.features.push(feature);
+    }
+    Ok(())
+  }
+}
+
+// Combines several tiles into one. Layers are concatenated in the order they appear, and
+// layers that share a name are combined into a single layer.
+pub fn merge_tiles(tiles: Vec<vector_tile::Tile>) -> Result<vector_tile::Tile, TileError> {
+  let mut builders: Vec<LayerBuilder> = Vec::new();
+  let mut layer_idx: HashMap<String, usize> = HashMap::new();
+
+  for tile in tiles {
+    for layer in tile.layers {
+      if let Some(idx) = layer_idx.get(&layer.name) {
+        builders[*idx].append(layer)?;
+      } else {
+        layer_idx.insert(layer.name.clone(), builders.len());
+        builders.push(LayerBuilder::new(layer));
+      }
+    }
+  }
+
+  Ok(vector_tile::Tile {
+    layers: builders.into_iter().map(|b| b.layer).collect(),
+  })
+}
+
+fn merge_vector_layers(
+  vector_layers: &mut Vec<serde_json::Value>,
+  other_vector_layers: &[serde_json::Value],
+) {
+  for other_layer in other_vector_layers {
+    let id = other_layer.get("id");
+    let existing = vector_layers.iter_mut().find(|l| l.get("id") == id);
+    let layer = match existing {
+      Some(layer) => layer,
+      None => {
+        vector_layers.push(other_layer.clone());
+        continue;
+      }
+    };
+
+    if let (Some(fields), Some(other_fields)) = (
+      layer.get_mut("fields").and_then(|f| f.as_object_mut()),
+      other_layer.get("fields").and_then(|f| f.as_object()),
+    ) {
+      for (name, field_type) in other_fields {
+        fields
+          .entry(name.clone())
+          .or_insert_with(|| field_type.clone());
+      }
+    }
+    if let Some(other_minzoom) = other_layer.get("minzoom").and_then(|z| z.as_u64()) {
+      let minzoom = layer.get("minzoom").and_then(|z| z.as_u64());
+      if minzoom.map_or(true, |z| other_minzoom < z) {
+        layer["minzoom"] = other_minzoom.into();
+      }
+    }
+    if let Some(other_maxzoom) = other_layer.get("maxzoom").and_then(|z| z.as_u64()) {
+      let maxzoom = layer.get("maxzoom").and_then(|z| z.as_u64());
+      if maxzoom.map_or(true, |z| other_maxzoom > z) {
+        layer["maxzoom"] = other_maxzoom.into();
+      }
+    }
+  }
+}
+
+// Merges the metadata of several archives. The first archive's metadata is used as a base,
+// and `minzoom`, `maxzoom`, `bounds` and the `vector_layers` in `json` are combined.
+pub fn merge_metadata(metadatas: &[HashMap<String, String>]) -> HashMap<String, String> {
+  let mut out = metadatas.first().cloned().unwrap_or_default();
+
+  let minzoom = metadatas
+    .iter()
+    .filter_map(|m| m.get("minzoom").and_then(|z| z.parse::<u8>().ok()))
+    .min();
+  if let Some(minzoom) = minzoom {
+    out.insert("minzoom".to_string(), minzoom.to_string());
+  }
+  let maxzoom = metadatas
+    .iter()
+    .filter_map(|m| m.get("maxzoom").and_then(|z| z.parse::<u8>().ok()))
+    .max();
+  if let Some(maxzoom) = maxzoom {
+    out.insert("maxzoom".to_string(), maxzoom.to_string());
+  }
+
+  let bounds = metadatas
+    .iter()
+    .filter_map(|m| m.get("bounds").and_then(|b| parse_bounds(b)))
+    .reduce(|a, b| {
+      [
+        a[0].min(b[0]),
+        a[1].min(b[1]),
+        a[2].max(b[2]),
+        a[3].max(b[3]),
+      ]
+    });
+  if let Some(bounds) = bounds {
+    out.insert(
+      "bounds".to_string(),
+      bounds
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<String>>()
+        .join(","),
+    );
+  }
+
+  let mut vector_layers: Vec<serde_json::Value> = Vec::new();
+  let mut json = serde_json::Map::new();
+  for metadata in metadatas {
+    let parsed = metadata
+      .get("json")
+      .and_then(|j| serde_json::from_str::<serde_json::Value>(j).ok());
+    if let Some(serde_json::Value::Object(parsed)) = parsed {
+      if let Some(serde_json::Value::Array(other_vector_layers)) = parsed.get("vector_layers") {
+        merge_vector_layers(&mut vector_layers, other_vector_layers);
+      }
+      if json.is_empty() {
+        json = parsed;
+      }
+    }
+  }
+  if !vector_layers.is_empty() {
+    json.insert("vector_layers".to_string(), vector_layers.into());
+    // tilestats describe a single source and would be wrong for the merged archive
+    json.remove("tilestats");
+    out.insert(
+      "json".to_string(),
+      serde_json::Value::Object(json).to_string(),
+    );
+  }
+
+  out
+}
+
+// After overzooming, every layer reaches the target zoom.
+fn set_vector_layers_maxzoom(metadata: &mut HashMap<String, String>, maxzoom: u32) {
+  let json = metadata
+    .get("json")
+    .and_then(|j| serde_json::from_str::<serde_json::Value>(j).ok());
+  if let Some(mut json) = json {
+    if let Some(serde_json::Value::Array(vector_layers)) = json.get_mut("vector_layers") {
+      for layer in vector_layers.iter_mut() {
+        layer["maxzoom"] = maxzoom.into();
+      }
+    }
+    metadata.insert("json".to_string(), json.to_string());
+  }
+}
+
+// A tile from one of the inputs, either straight from the archive or generated by overzooming
+// one of its ancestors.
+enum SourceTile {
+  Raw(Arc<Vec<u8>>),
+  Decoded(vector_tile::Tile),
+  // the part of an ancestor this tile covers, only scaled when the tile is actually merged
+  Descendant {
+    ancestor: Arc<vector_tile::Tile>,
+    steps: u32,
+    rel_x: u32,
+    rel_y: u32,
+  },
+}
+
+impl SourceTile {
+  fn clone_tile(&self) -> Result<vector_tile::Tile, TileError> {
+    match self {
+      SourceTile::Raw(data) => vector_tile_ops::decode_tile(data),
+      SourceTile::Decoded(tile) => Ok(tile.clone()),
+      SourceTile::Descendant {
+        ancestor,
+        steps,
+        rel_x,
+        rel_y,
+      } => vector_tile_ops::scale_tile((**ancestor).clone(), *steps, *rel_x, *rel_y),
+    }
+  }
+
+  fn into_tile(self) -> Result<vector_tile::Tile, TileError> {
+    match self {
+      SourceTile::Decoded(tile) => Ok(tile),
+      other => other.clone_tile(),
+    }
+  }
+}
+
+struct MergeSource<'a> {
+  lookup: TileLookup<'a>,
+  // tiles above this zoom are generated from their ancestor at this zoom
+  base_zoom: u32,
+  // children of the same ancestor are usually processed one after another, so the last decoded
+  // ancestor is kept around
+  cached_ancestor: Option<(tilebelt::Tile, Option<Arc<vector_tile::Tile>>)>,
+}
+
+impl<'a> MergeSource<'a> {
+  fn fetch(&mut self, tile: &tilebelt::Tile) -> Option<Vec<u8>> {
+    self.lookup.get(tile)
+  }
+
+  // Tiles above the base zoom are handed out as the part of their ancestor they cover, they are
+  // only scaled once they are merged or checked.
+  fn get(&mut self, tile: &tilebelt::Tile) -> Result<Option<SourceTile>, TileError> {
+    if tile.2 <= self.base_zoom {
+      return Ok(self.fetch(tile).map(|data| SourceTile::Raw(Arc::new(data))));
+    }
+
+    let (ancestor, steps, (rel_x, rel_y)) =
+      tilebelt::get_relative_position_in_ancestor(tile, self.base_zoom as u8);
+    let cache_hit = matches!(&self.cached_ancestor, Some((cached, _)) if *cached == ancestor);
+    if !cache_hit {
+      let parsed = match self.fetch(&ancestor) {
+        Some(data) => Some(Arc::new(vector_tile_ops::decode_tile(&data)?)),
+        None => None,
+      };
+      self.cached_ancestor = Some((ancestor, parsed));
+    }
+    let ancestor = self.cached_ancestor.as_ref().unwrap().1.clone();
+    Ok(ancestor.map(|ancestor| SourceTile::Descendant {
+      ancestor,
+      steps,
+      rel_x,
+      rel_y,
+    }))
+  }
+}
+
+fn gzip_tile(tile: &vector_tile::Tile) -> Arc<Vec<u8>> {
+  let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+  gz.write_all(&tile.encode_to_vec()).unwrap();
+  Arc::new(gz.finish().unwrap())
+}
+
+// Bad tiles are reported and left out of the output.
+fn report_bad_tile(action: &str, tile: &tilebelt::Tile, err: &TileError) {
+  println!(
+    "Skipping {} of tile {}/{}/{}: {}",
+    action, tile.2, tile.0, tile.1, err
+  );
+}
+
+// Combines `own` with the tiles the other inputs have at the same coordinate. Returns `None` when
+// an earlier input also has this tile, because it was already merged when that input was read.
+// Copies that can't be decoded are reported when their own input is read, and left out elsewhere,
+// so an earlier input only takes over the tile once its copy decodes.
+fn merge_sources_at(
+  sources: &mut [MergeSource],
+  input_idx: usize,
+  tile: &tilebelt::Tile,
+  own: SourceTile,
+) -> Result<Option<Arc<Vec<u8>>>, TileError> {
+  let mut others: Vec<vector_tile::Tile> = Vec::new();
+  for (other_idx, source) in sources.iter_mut().enumerate() {
+    if other_idx == input_idx {
+      continue;
+    }
+    let other = match source
+      .get(tile)
+      .and_then(|other| other.map(SourceTile::into_tile).transpose())
+    {
+      Ok(Some(other)) => other,
+      _ => continue,
+    };
+    if other_idx < input_idx {
+      return Ok(None);
+    }
+    others.push(other);
+  }
+
+  if others.is_empty() {
+    // only one archive has this tile, so it can be passed through untouched
+    return match own {
+      SourceTile::Raw(data) => Ok(Some(data)),
+      own => Ok(Some(gzip_tile(&own.into_tile()?))),
+    };
+  }
+
+  let mut tiles = Vec::with_capacity(others.len() + 1);
+  tiles.push(own.into_tile()?);
+  tiles.extend(others);
+  Ok(Some(gzip_tile(&merge_tiles(tiles)?)))
+}
+
+fn initialize_processors(
+  indexes: Arc<Vec<TileIndex>>,
+  base_zooms: Vec<u32>,
+  target_zoom: u32,
+  process_queue_rx: crossbeam_channel::Receiver<(usize, reader::TileData)>,
+  output_queue_tx: crossbeam_channel::Sender<reader::TileData>,
+  skipped_count: Arc<AtomicU64>,
+) -> Vec<thread::JoinHandle<()>> {
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let mut processor_thread_handles = Vec::with_capacity(max_workers);
+
+  for worker_id in 0..max_workers {
+    let thread_indexes = indexes.clone();
+    let thread_base_zooms = base_zooms.clone();
+    let thread_process_queue_rx = process_queue_rx.clone();
+    let thread_output_queue_tx = output_queue_tx.clone();
+    let thread_skipped_count = skipped_count.clone();
+    processor_thread_handles.push(thread::spawn(move || {
+      let connections: Vec<_> = thread_indexes.iter().map(|index| index.open()).collect();
+      let mut sources: Vec<MergeSource> = connections
+        .iter()
+        .zip(thread_base_zooms.iter())
+        .map(|(connection, base_zoom)| MergeSource {
+          lookup: connection.lookup(),
+          base_zoom: *base_zoom,
+          cached_ancestor: None,
+        })
+        .collect();
+
+      while let Ok((input_idx, tile_data)) = thread_process_queue_rx.recv() {
+        if interrupt::is_interrupted() {
+          continue;
+        }
+        let tile = tilebelt::flip_x(tile_data.tile);
+        let base_zoom = thread_base_zooms[input_idx];
+
+        let mut work = vec![(tile, SourceTile::Raw(tile_data.data))];
+        if tile.2 == base_zoom && base_zoom < target_zoom {
+          // this input stops at a lower zoom than the others, so this tile is used to generate
+          // higher resolution tiles until target_zoom. A bad tile is still merged as it is.
+          let children = work[0].1.clone_tile().and_then(|parsed_tile| {
+            tilebelt::get_children_until_zoom(&tile, target_zoom as u8)
+              .into_iter()
+              .map(|child| {
+                let (_, steps, (rel_x, rel_y)) =
+                  tilebelt::get_relative_position_in_ancestor(&child, base_zoom as u8);
+                let scaled_tile =
+                  vector_tile_ops::scale_tile(parsed_tile.clone(), steps, rel_x, rel_y)?;
+                Ok((child, SourceTile::Decoded(scaled_tile)))
+              })
+              .collect::<Result<Vec<_>, TileError>>()
+          });
+          match children {
+            Ok(children) => work.extend(children),
+            Err(err) => report_bad_tile("overzoom", &tile, &err),
+          }
+        }
+
+        for (tile, own) in work {
+          match merge_sources_at(&mut sources, input_idx, &tile, own) {
+            Ok(Some(data)) => thread_output_queue_tx
+              .send(reader::TileData {
+                tile,
+                data,
+                chunk: None,
+              })
+              .unwrap(),
+            Ok(None) => {}
+            Err(err) => {
+              report_bad_tile("merge", &tile, &err);
+              thread_skipped_count.fetch_add(1, Ordering::Relaxed);
+            }
+          }
+        }
+      }
+      println!("Worker {} finished.", worker_id);
+    }));
+  }
+  processor_thread_handles
+}
+
+pub fn merge(inputs: Vec<PathBuf>, output: PathBuf, align_zoom: bool, dedup: bool) {
+  let metadatas: Vec<HashMap<String, String>> =
+    inputs.iter().map(|input| read_metadata(input)).collect();
+  let mut metadata_rows = merge_metadata(&metadatas);
+
+  let mut target_zoom = u32::MAX;
+  let mut base_zooms = vec![u32::MAX; inputs.len()];
+  if align_zoom {
+    base_zooms = metadatas
+      .iter()
+      .zip(inputs.iter())
+      .map(|(metadata, input)| {
+        metadata
+          .get("maxzoom")
+          .and_then(|z| z.parse::<u32>().ok())
+          .unwrap_or_else(|| panic!("Input file {} has no maxzoom metadata", input.display()))
+      })
+      .collect();
+    target_zoom = *base_zooms.iter().max().unwrap();
+    for (input, base_zoom) in inputs.iter().zip(base_zooms.iter()) {
+      if *base_zoom < target_zoom {
+        println!(
+          "Extending tiles in {} from z{} to z{}",
+          input.display(),
+          base_zoom,
+          target_zoom
+        );
+      }
+    }
+    set_vector_layers_maxzoom(&mut metadata_rows, target_zoom);
+  }
+
+  println!(
+    "Merging {} archives and saving to {}...",
+    inputs.len(),
+    output.display()
+  );
+
+  // sized for the input with the largest tiles
+  let queue_capacity = inputs
+    .iter()
+    .map(|input| reader::queue_capacity(reader::average_tile_size(input)))
+    .min()
+    .unwrap();
+  let (process_queue_tx, process_queue_rx) =
+    crossbeam_channel::bounded::<(usize, reader::TileData)>(queue_capacity);
+  let (output_queue_tx, output_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(queue_capacity);
+
+  let skipped_count = Arc::new(AtomicU64::new(0));
+  // PMTiles directories are read once, not by every worker
+  let indexes = Arc::new(inputs.iter().map(|input| TileIndex::new(input)).collect());
+  let processor_thread_handles = initialize_processors(
+    indexes,
+    base_zooms,
+    target_zoom,
+    process_queue_rx,
+    output_queue_tx,
+    skipped_count.clone(),
+  );
+  let writer_handle =
+    writer::initialize_writer(output.clone(), output_queue_rx, metadata_rows, dedup, false);
+
+  for (input_idx, input) in inputs.iter().enumerate() {
+    println!("Reading {}...", input.display());
+    let mut reader = Reader::new(input.clone());
+    for tile in reader.iter() {
+      process_queue_tx.send((input_idx, tile)).unwrap();
+    }
+  }
+  drop(process_queue_tx);
+
+  for handle in processor_thread_handles {
+    handle.join().unwrap();
+  }
+  writer_handle.join().unwrap();
+
+  let skipped_count = skipped_count.load(Ordering::Relaxed);
+  if skipped_count > 0 {
+    println!(
+      "{} tiles could not be decoded and were left out",
+      skipped_count
+    );
+  }
+  println!("Merged {} archives into {}", inputs.len(), output.display());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Path;
+  use vector_tile::tile::{Feature, Layer, Value};
+
+  fn string_value(s: &str) -> Value {
+    Value {
+      string_value: Some(s.to_string()),
+      ..Default::default()
+    }
+  }
+
+  fn layer(name: &str, keys: &[&str], values: &[&str], tags: Vec<u32>, extent: u32) -> Layer {
+    Layer {
+      version: 2,
+      name: name.to_string(),
+      features: vec![Feature {
+        id: Some(1),
+        tags,
+        r#type: Some(vector_tile::tile::GeomType::Point as i32),
+        geometry: vec![9, vector_tile_ops::zz_enc(10), vector_tile_ops::zz_enc(20)],
+      }],
+      keys: keys.iter().map(|k| k.to_string()).collect(),
+      values: values.iter().map(|v| string_value(v)).collect(),
+      extent: Some(extent),
+    }
+  }
+
+  #[test]
+  fn test_merge_tiles() {
+    let a = vector_tile::Tile {
+      layers: vec![
+        layer("water", &["kind"], &["ocean"], vec![0, 0], 4096),
+        layer("roads", &["kind"], &["highway"], vec![0, 0], 4096),
+      ],
+    };
+    let b = vector_tile::Tile {
+      layers: vec![
+        layer(
+          "water",
+          &["name", "kind"],
+          &["lake", "pacific"],
+          vec![1, 0, 0, 1],
+          4096,
+        ),
+        layer("places", &["name"], &["tokyo"], vec![0, 0], 4096),
+      ],
+    };
+
+    let merged = merge_tiles(vec![a, b]).unwrap();
+    let names: Vec<&str> = merged.layers.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(names, vec!["water", "roads", "places"]);
+
+    let water = &merged.layers[0];
+    assert_eq!(water.features.len(), 2);
+    assert_eq!(water.keys, vec!["kind", "name"]);
+    assert_eq!(
+      water.values,
+      vec![
+        string_value("ocean"),
+        string_value("lake"),
+        string_value("pacific")
+      ]
+    );
+    assert_eq!(water.features[0].tags, vec![0, 0]);
+    // kind=lake, name=pacific
+    assert_eq!(water.features[1].tags, vec![0, 1, 1, 2]);
+  }
+
+  #[test]
+  fn test_merge_tiles_rescales_extent() {
+    let a = vector_tile::Tile {
+      layers: vec![layer("water", &[], &[], vec![], 512)],
+    };
+    let b = vector_tile::Tile {
+      layers: vec![layer("water", &[], &[], vec![], 4096)],
+    };
+    let merged = merge_tiles(vec![a, b]).unwrap();
+    assert_eq!(merged.layers[0].extent, Some(4096));
+    assert_eq!(
+      merged.layers[0].features[0].geometry,
+      vec![9, vector_tile_ops::zz_enc(80), vector_tile_ops::zz_enc(160)]
+    );
+    assert_eq!(
+      merged.layers[0].features[1].geometry,
+      vec![9, vector_tile_ops::zz_enc(10), vector_tile_ops::zz_enc(20)]
+    );
+  }
+
+  // Writes XYZ tiles to a mbtiles or PMTiles archive, depending on the extension of `output`.
+  fn write_archive(output: &Path, tiles: Vec<(tilebelt::Tile, Vec<u8>)>) {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let metadata = HashMap::from([("maxzoom".to_string(), "1".to_string())]);
+    let handle = if output.extension().unwrap() == "pmtiles" {
+      mbtiles_tool::pmtiles::initialize_writer(output.to_path_buf(), rx, metadata)
+    } else {
+      writer::initialize_writer(output.to_path_buf(), rx, metadata, false, false)
+    };
+    for (tile, data) in tiles {
+      tx.send(reader::TileData {
+        tile,
+        data: Arc::new(data),
+        chunk: None,
+      })
+      .unwrap();
+    }
+    drop(tx);
+    handle.join().unwrap();
+  }
+
+  #[test]
+  fn test_merge_archives() {
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let bad = dir.join(format!("merge_bad_{}.mbtiles", id));
+    let good = dir.join(format!("merge_good_{}.pmtiles", id));
+    let output = dir.join(format!("merge_out_{}.mbtiles", id));
+    for path in [&bad, &good, &output] {
+      let _ = std::fs::remove_file(path);
+    }
+
+    let water = vector_tile::Tile {
+      layers: vec![layer("water", &["kind"], &["ocean"], vec![0, 0], 4096)],
+    }
+    .encode_to_vec();
+    // the first input can't be decoded, the second one has the same tile in a PMTiles archive
+    write_archive(&bad, vec![((0, 0, 0), vec![0xff])]);
+    write_archive(
+      &good,
+      vec![((0, 0, 0), water.clone()), ((0, 0, 1), water.clone())],
+    );
+    merge(
+      vec![bad.clone(), good.clone()],
+      output.clone(),
+      false,
+      false,
+    );
+
+    let connection = sqlite::open(&output).unwrap();
+    let mut stmt = connection
+      .prepare("SELECT zoom_level, tile_data FROM tiles ORDER BY zoom_level;")
+      .unwrap();
+    let mut tiles = Vec::new();
+    while let sqlite::State::Row = stmt.next().unwrap() {
+      tiles.push((
+        stmt.read::<i64>(0).unwrap(),
+        stmt.read::<Vec<u8>>(1).unwrap(),
+      ));
+    }
+    assert_eq!(tiles, vec![(0, water.clone()), (1, water)]);
+    drop(stmt);
+    drop(connection);
+    for path in [&bad, &good, &output] {
+      std::fs::remove_file(path).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_merge_metadata() {
+    let a: HashMap<String, String> = [
+      ("name", "a"),
+      ("minzoom", "0"),
+      ("maxzoom", "14"),
+      ("bounds", "-10,-10,10,10"),
+      (
+        "json",
+        r#"{"vector_layers":[{"id":"water","fields":{"kind":"String"},"minzoom":0,"maxzoom":14}],"tilestats":{}}"#,
+      ),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let b: HashMap<String, String> = [
+      ("name", "b"),
+      ("minzoom", "4"),
+      ("maxzoom", "16"),
+      ("bounds", "0,0,20,5"),
+      (
+        "json",
+        r#"{"vector_layers":[{"id":"water","fields":{"name":"String"},"minzoom":4,"maxzoom":16},{"id":"places","fields":{}}]}"#,
+      ),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    let merged = merge_metadata(&[a, b]);
+    assert_eq!(merged["name"], "a");
+    assert_eq!(merged["minzoom"], "0");
+    assert_eq!(merged["maxzoom"], "16");
+    assert_eq!(merged["bounds"], "-10,-10,20,10");
+
+    let json: serde_json::Value = serde_json::from_str(&merged["json"]).unwrap();
+    assert!(json.get("tilestats").is_none());
+    assert_eq!(
+      json["vector_layers"],
+      serde_json::json!([
+        {"id": "water", "fields": {"kind": "String", "name": "String"}, "minzoom": 0, "maxzoom": 16},
+        {"id": "places", "fields": {}}
+      ])
+    );
+  }
+}
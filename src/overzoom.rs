@@ -1,62 +1,175 @@
-use crate::reader::Reader;
-use crate::{tilebelt, vector_tile_ops, writer};
-use flate2::read::GzDecoder;
+use crate::region::{self, Coverage, TileCoverage};
+use crate::vector_tile_ops::{self, TileError};
+use crate::writer;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use mbtiles_tool::vector_tile;
+use mbtiles_tool::checkpoint::{self, Checkpoint};
+use mbtiles_tool::interrupt;
+use mbtiles_tool::pmtiles;
+use mbtiles_tool::reader::{self, Reader, TileIndex};
+use mbtiles_tool::tilebelt::{self, Tile};
 use prost::Message;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
 
-fn maybe_decompress(data: Vec<u8>) -> Vec<u8> {
-  if data[0] == 0x1f && data[1] == 0x8b {
-    let mut out = Vec::with_capacity(data.len() * 2);
-    let mut zlib = GzDecoder::new(data.as_slice());
-    zlib.read_to_end(&mut out).unwrap();
-    return out;
+// Walks the descendants of `tile` down to `target_zoom` that are missing from the archive, and so
+// have `tile` as their closest existing ancestor. They are visited one at a time, depth first,
+// and `generate` returns whether the tiles below the one it was given are needed as well.
+fn walk_missing_descendants(
+  tile: &Tile,
+  target_zoom: u8,
+  mut exists: impl FnMut(&Tile) -> bool,
+  mut generate: impl FnMut(&Tile) -> Result<bool, TileError>,
+) -> Result<(), TileError> {
+  let mut stack = Vec::new();
+  if (tile.2 as u8) < target_zoom {
+    stack.extend(tilebelt::get_children(tile));
+  }
+  while let Some(child) = stack.pop() {
+    if exists(&child) {
+      // the child exists, it fills its own gaps
+      continue;
+    }
+    if generate(&child)? && (child.2 as u8) < target_zoom {
+      stack.extend(tilebelt::get_children(&child));
+    }
   }
-  data
+  Ok(())
 }
 
-fn initialize_processors(
-  process_queue_rx: crossbeam_channel::Receiver<tilebelt::TileData>,
-  output_queue_tx: crossbeam_channel::Sender<tilebelt::TileData>,
+fn intersects_bounds(tile: &Tile, bounds: &[f64; 4]) -> bool {
+  let (west, south, east, north) = tilebelt::tile_to_bbox(tile);
+  west < bounds[2] && east > bounds[0] && south < bounds[3] && north > bounds[1]
+}
+
+// Bad tiles are copied to the output as they are, but nothing is generated from them.
+fn report_bad_tile(tile: &Tile, err: &TileError) {
+  println!(
+    "Skipping overzoom of tile {}/{}/{}: {}",
+    tile.2, tile.0, tile.1, err
+  );
+}
+
+// How tiles are generated, shared by all workers.
+struct OverzoomSettings {
   maxzoom: u8,
   target_zoom: u8,
+  keep_extent: bool,
+  // the tiles of the input, only looked up when filling gaps
+  existing_tiles: Option<TileIndex>,
+  // the `bounds` of the input, gaps are only filled inside of them
+  bounds: Option<[f64; 4]>,
+  // when set, only tiles in this area are extended
+  area: Option<TileCoverage>,
+}
+
+fn initialize_processors(
+  process_queue_rx: crossbeam_channel::Receiver<reader::TileData>,
+  output_queue_tx: crossbeam_channel::Sender<reader::TileData>,
+  settings: Arc<OverzoomSettings>,
+  skipped_count: Arc<AtomicU64>,
 ) -> Vec<thread::JoinHandle<()>> {
-  let max_workers = std::cmp::max(num_cpus::get() - 2, 2);
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
   let mut processor_thread_handles = Vec::with_capacity(max_workers);
 
   for worker_id in 0..max_workers {
     let thread_process_queue_rx = process_queue_rx.clone();
     let thread_output_queue_tx = output_queue_tx.clone();
+    let thread_settings = settings.clone();
+    let thread_skipped_count = skipped_count.clone();
     processor_thread_handles.push(thread::spawn(move || {
+      let OverzoomSettings {
+        maxzoom,
+        target_zoom,
+        keep_extent,
+        existing_tiles,
+        bounds,
+        area,
+      } = &*thread_settings;
+      let fill_gaps = existing_tiles.is_some();
+      let existing_connection = existing_tiles.as_ref().map(|index| index.open());
+      let mut existing_tiles = existing_connection
+        .as_ref()
+        .map(|connection| connection.lookup());
       while let Ok(tile_data) = thread_process_queue_rx.recv() {
+        if interrupt::is_interrupted() {
+          // drain the queue without processing
+          continue;
+        }
         // first, pass the original tile through to the output
         thread_output_queue_tx.send(tile_data.clone()).unwrap();
-        if (tile_data.tile.2 as u8) == maxzoom {
-          // because this tile is the maximum available resolution, we use it to generate
-          // higher resolution tiles until target_zoom.
-          let raw_tile_data = maybe_decompress(tile_data.data.to_vec());
-          let parsed_tile = vector_tile::Tile::decode(&*raw_tile_data).unwrap();
-          let tiles_to_generate = tilebelt::get_children_until_zoom(&tile_data.tile, target_zoom);
-          for tile in tiles_to_generate.iter() {
-            let (ancestor, steps, (rel_x, rel_y)) =
-              tilebelt::get_relative_position_in_ancestor(tile, maxzoom);
-            assert_eq!(tile_data.tile, ancestor);
-            let scaled_tile = vector_tile_ops::scale_tile(parsed_tile.clone(), steps, rel_x, rel_y);
-            let scaled_tile_data = scaled_tile.encode_to_vec();
-            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
-            gz.write_all(&scaled_tile_data).unwrap();
-            let compressed_data = gz.finish().unwrap();
-            let tile_data = tilebelt::TileData {
+        if let Some(area) = area {
+          if area.get(&tile_data.tile) == Coverage::Outside {
+            // nothing below this tile is extended
+            continue;
+          }
+        }
+        // when filling gaps, every tile generates the missing tiles below it. Otherwise, the tiles
+        // of maxzoom are the maximum available resolution, and we use them to generate higher
+        // resolution tiles until target_zoom.
+        if !fill_gaps && (tile_data.tile.2 as u8) != *maxzoom {
+          continue;
+        }
+
+        // decoded once the first child is generated
+        let mut parsed_tile = None;
+        let generate = |tile: &Tile| -> Result<bool, TileError> {
+          if let Some(bounds) = bounds {
+            if !intersects_bounds(tile, bounds) {
+              return Ok(false);
+            }
+          }
+          if let Some(area) = area {
+            if area.get(tile) == Coverage::Outside {
+              return Ok(false);
+            }
+          }
+          if parsed_tile.is_none() {
+            parsed_tile = Some(vector_tile_ops::decode_tile(&tile_data.data)?);
+          }
+          let parsed_tile = parsed_tile.as_ref().unwrap();
+          let (ancestor, steps, (rel_x, rel_y)) =
+            tilebelt::get_relative_position_in_ancestor(tile, tile_data.tile.2 as u8);
+          assert_eq!(tile_data.tile, ancestor);
+          let scaled_tile = if *keep_extent {
+            vector_tile_ops::rescale_tile(parsed_tile.clone(), steps, rel_x, rel_y)?
+          } else {
+            vector_tile_ops::scale_tile(parsed_tile.clone(), steps, rel_x, rel_y)?
+          };
+          if fill_gaps && scaled_tile.layers.iter().all(|l| l.features.is_empty()) {
+            // nothing below this gap has features either
+            return Ok(false);
+          }
+          let scaled_tile_data = scaled_tile.encode_to_vec();
+          let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+          gz.write_all(&scaled_tile_data).unwrap();
+          let compressed_data = gz.finish().unwrap();
+          thread_output_queue_tx
+            .send(reader::TileData {
               tile: *tile,
               data: Arc::new(compressed_data),
-            };
-            thread_output_queue_tx.send(tile_data).unwrap();
-          }
+              chunk: tile_data.chunk.clone(),
+            })
+            .unwrap();
+          Ok(true)
+        };
+        let walked = match &mut existing_tiles {
+          Some(existing_tiles) => walk_missing_descendants(
+            &tile_data.tile,
+            *target_zoom,
+            |tile| existing_tiles.contains(tile),
+            generate,
+          ),
+          // the input has no tiles past maxzoom
+          None => walk_missing_descendants(&tile_data.tile, *target_zoom, |_| false, generate),
+        };
+        if let Err(err) = walked {
+          // the same geometry breaks every other child as well
+          report_bad_tile(&tile_data.tile, &err);
+          thread_skipped_count.fetch_add(1, Ordering::Relaxed);
         }
       }
       println!("Worker {} finished.", worker_id);
@@ -65,41 +178,115 @@ fn initialize_processors(
   processor_thread_handles
 }
 
-pub fn overzoom(input: PathBuf, output: PathBuf, target_zoom: u8) {
-  let mut reader = Reader::new(input);
+// A list of XYZ tiles, in the same format as the `tiles` of a subdivide output.
+pub fn read_tile_list(path: &Path) -> Vec<Tile> {
+  let file = std::fs::File::open(path).unwrap();
+  serde_json::from_reader(file).unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn overzoom(
+  input: PathBuf,
+  output: PathBuf,
+  target_zoom: u8,
+  keep_extent: bool,
+  fill_gaps: bool,
+  area: Option<TileCoverage>,
+  dedup: bool,
+  resume: bool,
+) {
+  let existing_tiles = if fill_gaps {
+    Some(TileIndex::new(&input))
+  } else {
+    None
+  };
+
+  // PMTiles archives are only written at the end, there is nothing to resume
+  let checkpoint = if pmtiles::is_pmtiles(&output) {
+    if resume {
+      panic!("Only mbtiles outputs can be resumed");
+    }
+    None
+  } else {
+    Some(Checkpoint::new(checkpoint::sidecar_path(&output), resume))
+  };
+  let mut reader = match &checkpoint {
+    Some(checkpoint) => Reader::with_checkpoint(input, checkpoint.clone()),
+    None => Reader::new(input),
+  };
   let mut metadata_rows = reader.read_metadata();
-  let maxzoom = metadata_rows["maxzoom"]
-    .parse::<u8>()
-    .unwrap_or(u8::max_value());
-  if maxzoom >= target_zoom {
-    panic!("Input file is already at or above target zoom level");
-  }
-  if maxzoom == u8::max_value() {
-    panic!("Input file has no maxzoom metadata");
-  }
+  let maxzoom = metadata_rows
+    .get("maxzoom")
+    .and_then(|zoom| zoom.parse().ok())
+    .unwrap_or(u8::MAX);
+  let bounds = if fill_gaps {
+    metadata_rows
+      .get("bounds")
+      .and_then(|bounds| region::parse_bounds(bounds))
+  } else {
+    None
+  };
+  if fill_gaps {
+    println!(
+      "Filling missing tiles up to z{} and saving to {}...",
+      target_zoom,
+      output.display()
+    );
+    if maxzoom == u8::MAX || maxzoom < target_zoom {
+      metadata_rows.insert("maxzoom".to_string(), target_zoom.to_string());
+    }
+  } else {
+    if maxzoom >= target_zoom {
+      panic!("Input file is already at or above target zoom level");
+    }
+    if maxzoom == u8::MAX {
+      panic!("Input file has no maxzoom metadata");
+    }
 
-  println!(
-    "Extending tiles from z{} to z{} and saving to {}...",
-    maxzoom,
-    target_zoom,
-    output.display()
-  );
+    println!(
+      "Extending tiles from z{} to z{} and saving to {}...",
+      maxzoom,
+      target_zoom,
+      output.display()
+    );
 
-  metadata_rows.insert("maxzoom".to_string(), target_zoom.to_string());
+    metadata_rows.insert("maxzoom".to_string(), target_zoom.to_string());
+  }
 
-  let (process_queue_tx, process_queue_rx) = crossbeam_channel::unbounded::<tilebelt::TileData>();
-  let (output_queue_tx, output_queue_rx) = crossbeam_channel::unbounded::<tilebelt::TileData>();
+  let (process_queue_tx, process_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(reader.queue_capacity());
+  let (output_queue_tx, output_queue_rx) =
+    crossbeam_channel::bounded::<reader::TileData>(reader.queue_capacity());
 
-  let processor_thread_handles =
-    initialize_processors(process_queue_rx, output_queue_tx, maxzoom, target_zoom);
-  let writer_handle = writer::initialize_writer(output.clone(), output_queue_rx, metadata_rows);
+  let skipped_count = Arc::new(AtomicU64::new(0));
+  let processor_thread_handles = initialize_processors(
+    process_queue_rx,
+    output_queue_tx,
+    Arc::new(OverzoomSettings {
+      maxzoom,
+      target_zoom,
+      keep_extent,
+      existing_tiles,
+      bounds,
+      area,
+    }),
+    skipped_count.clone(),
+  );
+  let writer_handle = writer::initialize_writer(
+    output.clone(),
+    output_queue_rx,
+    metadata_rows,
+    dedup,
+    resume,
+  );
 
   for tile in reader.iter() {
     let flipped_tile = tilebelt::flip_x(tile.tile);
     process_queue_tx
-      .send(tilebelt::TileData {
+      .send(reader::TileData {
         tile: flipped_tile,
         data: tile.data,
+        chunk: tile.chunk,
       })
       .unwrap();
   }
@@ -109,6 +296,94 @@ pub fn overzoom(input: PathBuf, output: PathBuf, target_zoom: u8) {
     handle.join().unwrap();
   }
   writer_handle.join().unwrap();
+  if let Some(checkpoint) = checkpoint {
+    checkpoint.finish();
+  }
 
+  let skipped_count = skipped_count.load(Ordering::Relaxed);
+  if skipped_count > 0 {
+    println!(
+      "{} tiles could not be decoded and were not overzoomed",
+      skipped_count
+    );
+  }
   println!("Filled {} with all the good things", output.display());
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn walk(tile: &Tile, target_zoom: u8, existing_tiles: &[Tile], needed: &[Tile]) -> Vec<Tile> {
+    let mut generated = Vec::new();
+    walk_missing_descendants(
+      tile,
+      target_zoom,
+      |tile| existing_tiles.contains(tile),
+      |tile| {
+        generated.push(*tile);
+        Ok(needed.contains(tile))
+      },
+    )
+    .unwrap();
+    generated.sort();
+    generated
+  }
+
+  #[test]
+  fn test_walk_missing_descendants() {
+    // z1 has a single tile, and one of its children was dropped at z2
+    let existing_tiles = [(0, 0, 0), (0, 0, 1), (0, 0, 2), (1, 0, 2), (0, 1, 2)];
+
+    // only the other z1 tiles are generated, none of them has children with data
+    assert_eq!(
+      walk(&(0, 0, 0), 2, &existing_tiles, &[]),
+      vec![(0, 1, 1), (1, 0, 1), (1, 1, 1)]
+    );
+    // the walk goes on below the tiles that are needed
+    assert_eq!(
+      walk(&(0, 0, 0), 2, &existing_tiles, &[(1, 0, 1)]),
+      vec![
+        (0, 1, 1),
+        (1, 0, 1),
+        (1, 1, 1),
+        (2, 0, 2),
+        (2, 1, 2),
+        (3, 0, 2),
+        (3, 1, 2)
+      ]
+    );
+    // the gap at z2 is filled from z1
+    assert_eq!(walk(&(0, 0, 1), 2, &existing_tiles, &[]), vec![(1, 1, 2)]);
+    // z2 tiles are already at the target zoom
+    assert!(walk(&(0, 0, 2), 2, &existing_tiles, &[]).is_empty());
+    // extending past the deepest tile generates the descendants that are needed
+    assert_eq!(
+      walk(&(0, 0, 2), 4, &existing_tiles, &[(0, 0, 3)]).len(),
+      4 + 4
+    );
+
+    // a tile that can't be scaled stops the walk
+    let mut generated = 0;
+    let walked = walk_missing_descendants(
+      &(0, 0, 0),
+      2,
+      |_| false,
+      |_| {
+        generated += 1;
+        Err(TileError::MissingMoveTo { offset: 0 })
+      },
+    );
+    assert!(walked.is_err());
+    assert_eq!(generated, 1);
+  }
+
+  #[test]
+  fn test_intersects_bounds() {
+    let bounds = [5.0, 45.0, 10.0, 48.0];
+    assert!(intersects_bounds(&(0, 0, 0), &bounds));
+    assert!(intersects_bounds(&(1, 0, 1), &bounds));
+    assert!(!intersects_bounds(&(0, 0, 1), &bounds));
+    assert!(!intersects_bounds(&(1, 1, 1), &bounds));
+  }
+}
@@ -0,0 +1,1007 @@
+use crate::interrupt;
+use crate::reader::EXTENT_CHUNK_TILE_COUNT;
+use crate::reader::{self, maybe_decompress, try_decompress};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::{thread, time};
+
+pub const HEADER_LENGTH: usize = 127;
+// the header and the root directory have to fit in the first 16KiB of the archive
+const ROOT_DIRECTORY_MAX_LENGTH: usize = 16_384 - HEADER_LENGTH;
+
+pub const COMPRESSION_UNKNOWN: u8 = 0;
+pub const COMPRESSION_NONE: u8 = 1;
+pub const COMPRESSION_GZIP: u8 = 2;
+
+pub const TILE_TYPE_UNKNOWN: u8 = 0;
+pub const TILE_TYPE_MVT: u8 = 1;
+pub const TILE_TYPE_PNG: u8 = 2;
+pub const TILE_TYPE_JPEG: u8 = 3;
+pub const TILE_TYPE_WEBP: u8 = 4;
+
+pub fn is_pmtiles(path: &Path) -> bool {
+  path.extension().map_or(false, |ext| ext == "pmtiles")
+}
+
+fn rotate(n: u64, x: &mut u64, y: &mut u64, rx: u64, ry: u64) {
+  if ry == 0 {
+    if rx == 1 {
+      *x = n - 1 - *x;
+      *y = n - 1 - *y;
+    }
+    std::mem::swap(x, y);
+  }
+}
+
+// Tile IDs count every tile in the lower zoom levels, then walk the zoom level along a Hilbert curve.
+pub fn zxy_to_tile_id(z: u8, x: u32, y: u32) -> u64 {
+  let n: u64 = 1 << z;
+  let mut acc: u64 = ((1u64 << (z as u64 * 2)) - 1) / 3;
+  let (mut x, mut y) = (x as u64, y as u64);
+  let mut s = n / 2;
+  while s > 0 {
+    let rx = ((x & s) > 0) as u64;
+    let ry = ((y & s) > 0) as u64;
+    acc += s * s * ((3 * rx) ^ ry);
+    rotate(n, &mut x, &mut y, rx, ry);
+    s /= 2;
+  }
+  acc
+}
+
+// Tiles up to z31 have x and y that fit in a u32, the IDs of z32 start at (4^32 - 1) / 3.
+pub const TILE_ID_LIMIT: u64 = u64::MAX / 3;
+
+pub fn tile_id_to_zxy(tile_id: u64) -> (u8, u32, u32) {
+  try_tile_id_to_zxy(tile_id).unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn try_tile_id_to_zxy(tile_id: u64) -> Result<(u8, u32, u32), String> {
+  if tile_id >= TILE_ID_LIMIT {
+    return Err(format!("PMTiles tile ID {} is past z31", tile_id));
+  }
+  let mut z: u8 = 0;
+  let mut acc: u64 = 0;
+  loop {
+    let tiles_at_zoom = 1u64 << (z as u64 * 2);
+    if acc + tiles_at_zoom > tile_id {
+      break;
+    }
+    acc += tiles_at_zoom;
+    z += 1;
+  }
+
+  let n: u64 = 1 << z;
+  let mut t = tile_id - acc;
+  let (mut x, mut y) = (0u64, 0u64);
+  let mut s = 1;
+  while s < n {
+    let rx = 1 & (t / 2);
+    let ry = 1 & (t ^ rx);
+    rotate(s, &mut x, &mut y, rx, ry);
+    x += s * rx;
+    y += s * ry;
+    t /= 4;
+    s *= 2;
+  }
+  Ok((z, x as u32, y as u32))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+  pub tile_id: u64,
+  pub offset: u64,
+  pub length: u32,
+  // 0 means this entry points to a leaf directory
+  pub run_length: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+  pub root_directory_offset: u64,
+  pub root_directory_length: u64,
+  pub metadata_offset: u64,
+  pub metadata_length: u64,
+  pub leaf_directories_offset: u64,
+  pub leaf_directories_length: u64,
+  pub tile_data_offset: u64,
+  pub tile_data_length: u64,
+  pub addressed_tiles_count: u64,
+  pub tile_entries_count: u64,
+  pub tile_contents_count: u64,
+  pub clustered: bool,
+  pub internal_compression: u8,
+  pub tile_compression: u8,
+  pub tile_type: u8,
+  pub min_zoom: u8,
+  pub max_zoom: u8,
+  pub min_lon_e7: i32,
+  pub min_lat_e7: i32,
+  pub max_lon_e7: i32,
+  pub max_lat_e7: i32,
+  pub center_zoom: u8,
+  pub center_lon_e7: i32,
+  pub center_lat_e7: i32,
+}
+
+impl Header {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LENGTH);
+    out.extend_from_slice(b"PMTiles");
+    out.push(3);
+    for n in [
+      self.root_directory_offset,
+      self.root_directory_length,
+      self.metadata_offset,
+      self.metadata_length,
+      self.leaf_directories_offset,
+      self.leaf_directories_length,
+      self.tile_data_offset,
+      self.tile_data_length,
+      self.addressed_tiles_count,
+      self.tile_entries_count,
+      self.tile_contents_count,
+    ] {
+      out.extend_from_slice(&n.to_le_bytes());
+    }
+    out.push(self.clustered as u8);
+    out.push(self.internal_compression);
+    out.push(self.tile_compression);
+    out.push(self.tile_type);
+    out.push(self.min_zoom);
+    out.push(self.max_zoom);
+    for n in [
+      self.min_lon_e7,
+      self.min_lat_e7,
+      self.max_lon_e7,
+      self.max_lat_e7,
+    ] {
+      out.extend_from_slice(&n.to_le_bytes());
+    }
+    out.push(self.center_zoom);
+    out.extend_from_slice(&self.center_lon_e7.to_le_bytes());
+    out.extend_from_slice(&self.center_lat_e7.to_le_bytes());
+    out
+  }
+
+  pub fn try_from_bytes(bytes: &[u8]) -> Result<Header, String> {
+    if bytes.len() < HEADER_LENGTH || &bytes[0..7] != b"PMTiles" {
+      return Err("Input file is not a PMTiles archive".to_string());
+    }
+    if bytes[7] != 3 {
+      return Err(format!("Unsupported PMTiles version {}", bytes[7]));
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    let i32_at = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    Ok(Header {
+      root_directory_offset: u64_at(8),
+      root_directory_length: u64_at(16),
+      metadata_offset: u64_at(24),
+      metadata_length: u64_at(32),
+      leaf_directories_offset: u64_at(40),
+      leaf_directories_length: u64_at(48),
+      tile_data_offset: u64_at(56),
+      tile_data_length: u64_at(64),
+      addressed_tiles_count: u64_at(72),
+      tile_entries_count: u64_at(80),
+      tile_contents_count: u64_at(88),
+      clustered: bytes[96] == 1,
+      internal_compression: bytes[97],
+      tile_compression: bytes[98],
+      tile_type: bytes[99],
+      min_zoom: bytes[100],
+      max_zoom: bytes[101],
+      min_lon_e7: i32_at(102),
+      min_lat_e7: i32_at(106),
+      max_lon_e7: i32_at(110),
+      max_lat_e7: i32_at(114),
+      center_zoom: bytes[118],
+      center_lon_e7: i32_at(119),
+      center_lat_e7: i32_at(123),
+    })
+  }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+  while n >= 0x80 {
+    out.push((n as u8 & 0x7f) | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, String> {
+  let mut n: u64 = 0;
+  let mut shift = 0;
+  loop {
+    let byte = *bytes
+      .get(*pos)
+      .ok_or_else(|| "PMTiles directory is truncated".to_string())?;
+    *pos += 1;
+    n |= ((byte & 0x7f) as u64) << shift;
+    if byte < 0x80 {
+      return Ok(n);
+    }
+    shift += 7;
+    if shift >= 64 {
+      return Err("PMTiles directory has an invalid varint".to_string());
+    }
+  }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+  let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+  gz.write_all(data).unwrap();
+  gz.finish().unwrap()
+}
+
+// Directories are stored column by column, with tile IDs delta encoded and offsets omitted (0)
+// when an entry directly follows the previous one.
+fn serialize_entries(entries: &[Entry]) -> Vec<u8> {
+  let mut out = Vec::new();
+  write_varint(&mut out, entries.len() as u64);
+
+  let mut last_id = 0;
+  for entry in entries {
+    write_varint(&mut out, entry.tile_id - last_id);
+    last_id = entry.tile_id;
+  }
+  for entry in entries {
+    write_varint(&mut out, entry.run_length as u64);
+  }
+  for entry in entries {
+    write_varint(&mut out, entry.length as u64);
+  }
+  for (i, entry) in entries.iter().enumerate() {
+    if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+      write_varint(&mut out, 0);
+    } else {
+      write_varint(&mut out, entry.offset + 1);
+    }
+  }
+
+  gzip(&out)
+}
+
+fn deserialize_entries(data: &[u8], internal_compression: u8) -> Result<Vec<Entry>, String> {
+  let bytes = match internal_compression {
+    COMPRESSION_NONE => data.to_vec(),
+    COMPRESSION_GZIP => decompress(data.to_vec())?,
+    _ => {
+      return Err(format!(
+        "Unsupported PMTiles internal compression {}",
+        internal_compression
+      ))
+    }
+  };
+  let mut pos = 0;
+  let count = read_varint(&bytes, &mut pos)? as usize;
+  // every entry takes at least one byte per column
+  if count > bytes.len() {
+    return Err("PMTiles directory is truncated".to_string());
+  }
+  let mut entries = vec![
+    Entry {
+      tile_id: 0,
+      offset: 0,
+      length: 0,
+      run_length: 0,
+    };
+    count
+  ];
+
+  let mut last_id = 0u64;
+  for entry in entries.iter_mut() {
+    last_id = last_id
+      .checked_add(read_varint(&bytes, &mut pos)?)
+      .ok_or_else(|| "PMTiles directory has an invalid tile ID".to_string())?;
+    entry.tile_id = last_id;
+  }
+  for entry in entries.iter_mut() {
+    entry.run_length = read_varint(&bytes, &mut pos)? as u32;
+  }
+  for entry in entries.iter_mut() {
+    entry.length = read_varint(&bytes, &mut pos)? as u32;
+  }
+  for i in 0..count {
+    let offset = read_varint(&bytes, &mut pos)?;
+    entries[i].offset = if offset == 0 && i > 0 {
+      entries[i - 1]
+        .offset
+        .checked_add(entries[i - 1].length as u64)
+        .ok_or_else(|| "PMTiles directory has an invalid offset".to_string())?
+    } else {
+      offset
+        .checked_sub(1)
+        .ok_or_else(|| "PMTiles directory has an invalid offset".to_string())?
+    };
+  }
+  Ok(entries)
+}
+
+fn build_roots_leaves(entries: &[Entry], leaf_size: usize) -> (Vec<u8>, Vec<u8>, usize) {
+  let mut root_entries = Vec::<Entry>::new();
+  let mut leaves = Vec::<u8>::new();
+  let mut leaf_count = 0;
+
+  for chunk in entries.chunks(leaf_size) {
+    let serialized = serialize_entries(chunk);
+    root_entries.push(Entry {
+      tile_id: chunk[0].tile_id,
+      offset: leaves.len() as u64,
+      length: serialized.len() as u32,
+      run_length: 0,
+    });
+    leaves.extend(serialized);
+    leaf_count += 1;
+  }
+
+  (serialize_entries(&root_entries), leaves, leaf_count)
+}
+
+// Returns the serialized root directory and leaf directories. Leaf directories are only used
+// when the entries don't fit in the root directory.
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>, usize) {
+  if entries.len() < 16_384 {
+    let root = serialize_entries(entries);
+    if root.len() <= ROOT_DIRECTORY_MAX_LENGTH {
+      return (root, Vec::new(), 0);
+    }
+  }
+
+  let mut leaf_size = 4096;
+  loop {
+    let (root, leaves, leaf_count) = build_roots_leaves(entries, leaf_size);
+    if root.len() <= ROOT_DIRECTORY_MAX_LENGTH {
+      return (root, leaves, leaf_count);
+    }
+    leaf_size *= 2;
+  }
+}
+
+fn tile_type(format: Option<&String>) -> u8 {
+  match format.map(|f| f.as_str()) {
+    Some("pbf") | Some("mvt") => TILE_TYPE_MVT,
+    Some("png") => TILE_TYPE_PNG,
+    Some("jpg") | Some("jpeg") => TILE_TYPE_JPEG,
+    Some("webp") => TILE_TYPE_WEBP,
+    _ => TILE_TYPE_UNKNOWN,
+  }
+}
+
+fn parse_e7_list(value: Option<&String>) -> Option<Vec<f64>> {
+  value?
+    .split(',')
+    .map(|n| n.trim().parse::<f64>().ok())
+    .collect()
+}
+
+fn to_e7(n: f64) -> i32 {
+  (n * 10_000_000.0).round() as i32
+}
+
+// PMTiles keeps bounds, center and the zoom range in the header, everything else goes in the
+// JSON metadata. The contents of the mbtiles `json` row are moved to the top level.
+fn build_metadata_json(metadata: &HashMap<String, String>) -> Vec<u8> {
+  let mut out = serde_json::Map::new();
+  for (name, value) in metadata.iter() {
+    match name.as_str() {
+      "bounds" | "center" | "minzoom" | "maxzoom" => {}
+      "json" => {
+        if let Ok(serde_json::Value::Object(json)) = serde_json::from_str(value) {
+          out.extend(json);
+        }
+      }
+      _ => {
+        out.insert(name.clone(), value.clone().into());
+      }
+    }
+  }
+  serde_json::Value::Object(out).to_string().into_bytes()
+}
+
+fn decompress(data: Vec<u8>) -> Result<Vec<u8>, String> {
+  try_decompress(data).map_err(|err| format!("invalid gzip data: {}", err))
+}
+
+fn read_range(file: &mut File, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+  // a broken header can point anywhere, so nothing is allocated past the end of the file
+  let file_length = file.metadata().map_err(|err| err.to_string())?.len();
+  if offset
+    .checked_add(length)
+    .map_or(true, |end| end > file_length)
+  {
+    return Err(format!(
+      "PMTiles archive is truncated, it ends before {} bytes at offset {}",
+      length, offset
+    ));
+  }
+  let mut buf = vec![0; length as usize];
+  file
+    .seek(SeekFrom::Start(offset))
+    .and_then(|_| file.read_exact(&mut buf))
+    .map_err(|err| err.to_string())?;
+  Ok(buf)
+}
+
+fn read_header(file: &mut File) -> Result<Header, String> {
+  let bytes = read_range(file, 0, HEADER_LENGTH as u64)
+    .map_err(|_| "Input file is not a PMTiles archive".to_string())?;
+  Header::try_from_bytes(&bytes)
+}
+
+// Reads the root directory and every leaf directory, returning the entries that point to tiles.
+pub fn read_entries(input: &Path) -> (Header, Vec<Entry>) {
+  try_read_entries(input).unwrap_or_else(|err| panic!("{}", err))
+}
+
+// Like `read_entries`, for archives that may be truncated or corrupt.
+pub fn try_read_entries(input: &Path) -> Result<(Header, Vec<Entry>), String> {
+  let mut file = File::open(input).map_err(|err| err.to_string())?;
+  let header = read_header(&mut file)?;
+
+  let mut entries = Vec::<Entry>::new();
+  let root = read_range(
+    &mut file,
+    header.root_directory_offset,
+    header.root_directory_length,
+  )?;
+  let mut directories = vec![deserialize_entries(&root, header.internal_compression)?];
+  while let Some(directory) = directories.pop() {
+    for entry in directory {
+      if entry.run_length > 0 {
+        // the tiles of the run are counted from its tile ID, they have to stay within z31
+        let end = entry.tile_id.checked_add(entry.run_length as u64);
+        if end.map_or(true, |end| end > TILE_ID_LIMIT) {
+          return Err(format!(
+            "PMTiles entry at tile ID {} with a run length of {} is past z31",
+            entry.tile_id, entry.run_length
+          ));
+        }
+        entries.push(entry);
+        continue;
+      }
+      let leaf = read_range(
+        &mut file,
+        header.leaf_directories_offset.saturating_add(entry.offset),
+        entry.length as u64,
+      )?;
+      directories.push(deserialize_entries(&leaf, header.internal_compression)?);
+    }
+  }
+  // a directory can mix tiles and leaf directories, whose tiles are only read after the rest of
+  // it, so the entries are put back in tile ID order at the end
+  entries.sort_by_key(|entry| entry.tile_id);
+
+  Ok((header, entries))
+}
+
+fn format_from_tile_type(tile_type: u8) -> Option<&'static str> {
+  match tile_type {
+    TILE_TYPE_MVT => Some("pbf"),
+    TILE_TYPE_PNG => Some("png"),
+    TILE_TYPE_JPEG => Some("jpg"),
+    TILE_TYPE_WEBP => Some("webp"),
+    _ => None,
+  }
+}
+
+// Builds mbtiles style metadata rows from the header and JSON metadata, the reverse of
+// `build_metadata_json`.
+pub fn read_metadata(input: &Path) -> HashMap<String, String> {
+  try_read_metadata(input).unwrap_or_else(|err| panic!("{}", err))
+}
+
+// Like `read_metadata`, for archives that may be truncated or corrupt.
+pub fn try_read_metadata(input: &Path) -> Result<HashMap<String, String>, String> {
+  let mut file = File::open(input).map_err(|err| err.to_string())?;
+  let header = read_header(&mut file)?;
+  let metadata_bytes = read_range(&mut file, header.metadata_offset, header.metadata_length)?;
+  let metadata_json = match header.internal_compression {
+    COMPRESSION_GZIP => decompress(metadata_bytes)?,
+    _ => metadata_bytes,
+  };
+
+  let mut metadata = HashMap::<String, String>::new();
+  let mut json = serde_json::Map::new();
+  if let Ok(serde_json::Value::Object(parsed)) = serde_json::from_slice(&metadata_json) {
+    for (name, value) in parsed {
+      match value {
+        serde_json::Value::String(value) => {
+          metadata.insert(name, value);
+        }
+        value => {
+          json.insert(name, value);
+        }
+      }
+    }
+  }
+  if !json.is_empty() {
+    metadata.insert(
+      "json".to_string(),
+      serde_json::Value::Object(json).to_string(),
+    );
+  }
+
+  let from_e7 = |n: i32| (n as f64 / 10_000_000.0).to_string();
+  metadata.insert("minzoom".to_string(), header.min_zoom.to_string());
+  metadata.insert("maxzoom".to_string(), header.max_zoom.to_string());
+  metadata.insert(
+    "bounds".to_string(),
+    [
+      header.min_lon_e7,
+      header.min_lat_e7,
+      header.max_lon_e7,
+      header.max_lat_e7,
+    ]
+    .iter()
+    .map(|n| from_e7(*n))
+    .collect::<Vec<String>>()
+    .join(","),
+  );
+  metadata.insert(
+    "center".to_string(),
+    format!(
+      "{},{},{}",
+      from_e7(header.center_lon_e7),
+      from_e7(header.center_lat_e7),
+      header.center_zoom
+    ),
+  );
+  if let Some(format) = format_from_tile_type(header.tile_type) {
+    metadata
+      .entry("format".to_string())
+      .or_insert_with(|| format.to_string());
+  }
+  Ok(metadata)
+}
+
+struct TempTile {
+  tile_id: u64,
+  // offset and length in the temporary file. Identical tiles share the same offset.
+  offset: u64,
+  length: u32,
+}
+
+fn write_archive(
+  output: &Path,
+  temp_path: &Path,
+  mut tiles: Vec<TempTile>,
+  mut header: Header,
+  metadata: &HashMap<String, String>,
+) {
+  // the sort is stable, so a tile that was written twice keeps its last version like it does in
+  // mbtiles outputs
+  tiles.sort_by_key(|t| t.tile_id);
+  let mut unique_tiles = Vec::<TempTile>::with_capacity(tiles.len());
+  for tile in tiles {
+    match unique_tiles.last_mut() {
+      Some(last) if last.tile_id == tile.tile_id => *last = tile,
+      _ => unique_tiles.push(tile),
+    }
+  }
+  let tiles = unique_tiles;
+
+  // tile data is written in tile ID order (clustered), with each unique tile stored once. Empty
+  // tiles take no space, so they share their offset with the next tile.
+  let mut entries = Vec::<Entry>::with_capacity(tiles.len());
+  let mut final_offsets = HashMap::<(u64, u32), u64>::new();
+  let mut temp_file = File::open(temp_path).unwrap();
+  let data_path = output.with_extension("pmtiles.data");
+  let mut data_file = BufWriter::new(File::create(&data_path).unwrap());
+  let mut tile_data_length: u64 = 0;
+  let mut buf = Vec::<u8>::new();
+
+  for tile in tiles.iter() {
+    let offset = match final_offsets.get(&(tile.offset, tile.length)) {
+      Some(offset) => *offset,
+      None => {
+        buf.resize(tile.length as usize, 0);
+        temp_file.seek(SeekFrom::Start(tile.offset)).unwrap();
+        temp_file.read_exact(&mut buf).unwrap();
+        data_file.write_all(&buf).unwrap();
+        let offset = tile_data_length;
+        tile_data_length += tile.length as u64;
+        final_offsets.insert((tile.offset, tile.length), offset);
+        offset
+      }
+    };
+
+    if let Some(last) = entries.last_mut() {
+      if last.offset == offset
+        && last.length == tile.length
+        && last.tile_id + last.run_length as u64 == tile.tile_id
+      {
+        // a run of identical tiles is stored as a single entry
+        last.run_length += 1;
+        continue;
+      }
+    }
+    entries.push(Entry {
+      tile_id: tile.tile_id,
+      offset,
+      length: tile.length,
+      run_length: 1,
+    });
+  }
+  data_file.flush().unwrap();
+  drop(data_file);
+
+  let (root, leaves, leaf_count) = build_directories(&entries);
+  let metadata_json = gzip(&build_metadata_json(metadata));
+
+  header.root_directory_offset = HEADER_LENGTH as u64;
+  header.root_directory_length = root.len() as u64;
+  header.metadata_offset = header.root_directory_offset + header.root_directory_length;
+  header.metadata_length = metadata_json.len() as u64;
+  header.leaf_directories_offset = header.metadata_offset + header.metadata_length;
+  header.leaf_directories_length = leaves.len() as u64;
+  header.tile_data_offset = header.leaf_directories_offset + header.leaf_directories_length;
+  header.tile_data_length = tile_data_length;
+  header.addressed_tiles_count = tiles.len() as u64;
+  header.tile_entries_count = entries.len() as u64;
+  header.tile_contents_count = final_offsets.len() as u64;
+  header.clustered = true;
+  header.internal_compression = COMPRESSION_GZIP;
+
+  let mut out = BufWriter::new(File::create(output).unwrap());
+  out.write_all(&header.to_bytes()).unwrap();
+  out.write_all(&root).unwrap();
+  out.write_all(&metadata_json).unwrap();
+  out.write_all(&leaves).unwrap();
+  std::io::copy(&mut File::open(&data_path).unwrap(), &mut out).unwrap();
+  out.flush().unwrap();
+
+  std::fs::remove_file(&data_path).unwrap();
+  println!(
+    "Wrote {} tile entries ({} unique tiles, {} leaf directories)",
+    entries.len(),
+    final_offsets.len(),
+    leaf_count
+  );
+}
+
+pub fn initialize_writer(
+  output: PathBuf,
+  queue: crossbeam_channel::Receiver<reader::TileData>,
+  mut metadata: HashMap<String, String>,
+) -> thread::JoinHandle<()> {
+  thread::spawn(move || {
+    let mut last_ts = time::Instant::now();
+    let mut tile_count = 0;
+
+    // tiles arrive in no particular order, so they are collected in a temporary file first and
+    // sorted by tile ID once everything has been received.
+    let temp_path = output.with_extension("pmtiles.tmp");
+    let mut temp_file = BufWriter::new(File::create(&temp_path).unwrap());
+    let mut temp_length: u64 = 0;
+    let mut tiles = Vec::<TempTile>::new();
+    let mut hashes = HashMap::<[u8; 16], u64>::new();
+
+    let tile_type = tile_type(metadata.get("format"));
+    let mut tile_compression = COMPRESSION_UNKNOWN;
+    let mut min_zoom = u8::MAX;
+    let mut max_zoom = 0;
+
+    while let Ok(work) = queue.recv() {
+      tile_count += 1;
+
+      let (x, y, z) = work.tile;
+      min_zoom = std::cmp::min(min_zoom, z as u8);
+      max_zoom = std::cmp::max(max_zoom, z as u8);
+
+      // PMTiles has a single compression for the whole archive, based on the first tile
+      let gzipped = work.data.len() >= 2 && work.data[0] == 0x1f && work.data[1] == 0x8b;
+      if tile_compression == COMPRESSION_UNKNOWN {
+        tile_compression = if gzipped {
+          COMPRESSION_GZIP
+        } else {
+          COMPRESSION_NONE
+        };
+      }
+      let data = if tile_compression == COMPRESSION_GZIP && !gzipped {
+        gzip(&work.data)
+      } else if tile_compression == COMPRESSION_NONE && gzipped {
+        maybe_decompress(work.data.to_vec())
+      } else {
+        work.data.to_vec()
+      };
+
+      let hash = md5::compute(&data).0;
+      let offset = match hashes.get(&hash) {
+        Some(offset) => *offset,
+        None => {
+          temp_file.write_all(&data).unwrap();
+          let offset = temp_length;
+          temp_length += data.len() as u64;
+          hashes.insert(hash, offset);
+          offset
+        }
+      };
+      tiles.push(TempTile {
+        tile_id: zxy_to_tile_id(z as u8, x, y),
+        offset,
+        length: data.len() as u32,
+      });
+
+      if tile_count % EXTENT_CHUNK_TILE_COUNT == 0 {
+        let ts = time::Instant::now();
+        let elapsed = ts.duration_since(last_ts);
+        println!(
+          "[output] {} tiles in {}ms ({:.4}ms/tile, {} tiles queued)",
+          tile_count,
+          elapsed.as_millis(),
+          elapsed.as_millis() as f64 / (EXTENT_CHUNK_TILE_COUNT as f64),
+          queue.len(),
+        );
+        last_ts = ts;
+      }
+    }
+    temp_file.flush().unwrap();
+    drop(temp_file);
+    drop(hashes);
+
+    let mut header = Header {
+      tile_compression,
+      tile_type,
+      min_zoom: if tile_count > 0 { min_zoom } else { 0 },
+      max_zoom,
+      min_lon_e7: to_e7(-180.0),
+      min_lat_e7: to_e7(-85.0),
+      max_lon_e7: to_e7(180.0),
+      max_lat_e7: to_e7(85.0),
+      ..Default::default()
+    };
+    if let Some(bounds) = parse_e7_list(metadata.get("bounds")).filter(|b| b.len() == 4) {
+      header.min_lon_e7 = to_e7(bounds[0]);
+      header.min_lat_e7 = to_e7(bounds[1]);
+      header.max_lon_e7 = to_e7(bounds[2]);
+      header.max_lat_e7 = to_e7(bounds[3]);
+    }
+    header.center_lon_e7 = (header.min_lon_e7 / 2) + (header.max_lon_e7 / 2);
+    header.center_lat_e7 = (header.min_lat_e7 / 2) + (header.max_lat_e7 / 2);
+    header.center_zoom = header.min_zoom;
+    if let Some(center) = parse_e7_list(metadata.get("center")).filter(|c| c.len() >= 2) {
+      header.center_lon_e7 = to_e7(center[0]);
+      header.center_lat_e7 = to_e7(center[1]);
+      if let Some(zoom) = center.get(2) {
+        header.center_zoom = *zoom as u8;
+      }
+    }
+
+    if interrupt::is_interrupted() {
+      metadata.insert(
+        interrupt::PARTIAL_METADATA_KEY.to_string(),
+        "true".to_string(),
+      );
+    }
+    write_archive(&output, &temp_path, tiles, header, &metadata);
+    std::fs::remove_file(&temp_path).unwrap();
+
+    println!("Output finished, {} tiles", tile_count);
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_zxy_to_tile_id() {
+    assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+    assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+    assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+    assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+    assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+    assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+    assert_eq!(zxy_to_tile_id(12, 3423, 1763), 19078479);
+    assert_eq!(zxy_to_tile_id(20, 0, 0), 366503875925);
+  }
+
+  #[test]
+  fn test_tile_id_to_zxy() {
+    assert_eq!(tile_id_to_zxy(0), (0, 0, 0));
+    assert_eq!(tile_id_to_zxy(1), (1, 0, 0));
+    assert_eq!(tile_id_to_zxy(2), (1, 0, 1));
+    assert_eq!(tile_id_to_zxy(3), (1, 1, 1));
+    assert_eq!(tile_id_to_zxy(4), (1, 1, 0));
+    assert_eq!(tile_id_to_zxy(5), (2, 0, 0));
+    assert_eq!(tile_id_to_zxy(19078479), (12, 3423, 1763));
+
+    for tile_id in 0..2000 {
+      let (z, x, y) = tile_id_to_zxy(tile_id);
+      assert_eq!(zxy_to_tile_id(z, x, y), tile_id);
+    }
+
+    // the last tile of z31, and the first one that doesn't fit anymore
+    let last = (1u32 << 31) - 1;
+    assert_eq!(zxy_to_tile_id(31, last, 0), TILE_ID_LIMIT - 1);
+    assert_eq!(tile_id_to_zxy(TILE_ID_LIMIT - 1), (31, last, 0));
+    assert!(try_tile_id_to_zxy(TILE_ID_LIMIT).is_err());
+    assert!(try_tile_id_to_zxy(u64::MAX).is_err());
+  }
+
+  #[test]
+  fn test_header_roundtrip() {
+    let header = Header {
+      root_directory_offset: 127,
+      root_directory_length: 300,
+      tile_data_length: u64::MAX,
+      clustered: true,
+      internal_compression: COMPRESSION_GZIP,
+      tile_type: TILE_TYPE_PNG,
+      min_lat_e7: -850_511_287,
+      center_zoom: 3,
+      center_lat_e7: -1,
+      ..Default::default()
+    };
+    assert_eq!(Header::try_from_bytes(&header.to_bytes()).unwrap(), header);
+  }
+
+  #[test]
+  fn test_serialize_entries_roundtrip() {
+    let entries = vec![
+      Entry {
+        tile_id: 0,
+        offset: 0,
+        length: 100,
+        run_length: 1,
+      },
+      Entry {
+        tile_id: 1,
+        offset: 100,
+        length: 20,
+        run_length: 3,
+      },
+      Entry {
+        tile_id: 10,
+        // points back at an earlier tile
+        offset: 0,
+        length: 100,
+        run_length: 1,
+      },
+      Entry {
+        tile_id: 1_000_000,
+        offset: 120,
+        length: 1,
+        run_length: 0,
+      },
+    ];
+    let serialized = serialize_entries(&entries);
+    assert_eq!(
+      deserialize_entries(&serialized, COMPRESSION_GZIP).unwrap(),
+      entries
+    );
+  }
+
+  #[test]
+  fn test_header_to_bytes() {
+    let header = Header {
+      root_directory_offset: 127,
+      tile_type: TILE_TYPE_MVT,
+      max_zoom: 14,
+      min_lon_e7: -1_800_000_000,
+      center_lat_e7: 1,
+      ..Default::default()
+    };
+    let bytes = header.to_bytes();
+    assert_eq!(bytes.len(), HEADER_LENGTH);
+    assert_eq!(&bytes[0..8], b"PMTiles\x03");
+    assert_eq!(&bytes[8..16], &127u64.to_le_bytes());
+    assert_eq!(bytes[99], TILE_TYPE_MVT);
+    assert_eq!(bytes[101], 14);
+    assert_eq!(&bytes[102..106], &(-1_800_000_000i32).to_le_bytes());
+    assert_eq!(&bytes[123..127], &1i32.to_le_bytes());
+  }
+
+  #[test]
+  fn test_build_directories() {
+    let small: Vec<Entry> = (0..100)
+      .map(|i| Entry {
+        tile_id: i,
+        offset: i * 10,
+        length: 10,
+        run_length: 1,
+      })
+      .collect();
+    let (_, leaves, leaf_count) = build_directories(&small);
+    assert!(leaves.is_empty());
+    assert_eq!(leaf_count, 0);
+
+    // sparse tile IDs with scattered offsets don't compress well, so they need leaf directories
+    let large: Vec<Entry> = (0..100_000u64)
+      .map(|i| Entry {
+        tile_id: i * 7919,
+        offset: (i * 104_729) % 1_000_003,
+        length: (i % 1000) as u32 + 1,
+        run_length: 1,
+      })
+      .collect();
+    let (root, leaves, leaf_count) = build_directories(&large);
+    assert!(root.len() <= ROOT_DIRECTORY_MAX_LENGTH);
+    assert!(!leaves.is_empty());
+    assert!(leaf_count > 1);
+  }
+
+  #[test]
+  fn test_read_mixed_directories() {
+    let input = std::env::temp_dir().join(format!("mixed_{}.pmtiles", std::process::id()));
+    let tile = |tile_id| Entry {
+      tile_id,
+      offset: 0,
+      length: 1,
+      run_length: 1,
+    };
+    let leaf = serialize_entries(&[tile(1), tile(2)]);
+    // a tile, a leaf directory with the next two tiles, and another tile after them
+    let root = serialize_entries(&[
+      tile(0),
+      Entry {
+        tile_id: 1,
+        offset: 0,
+        length: leaf.len() as u32,
+        run_length: 0,
+      },
+      tile(3),
+    ]);
+    let header = Header {
+      root_directory_offset: HEADER_LENGTH as u64,
+      root_directory_length: root.len() as u64,
+      leaf_directories_offset: (HEADER_LENGTH + root.len()) as u64,
+      leaf_directories_length: leaf.len() as u64,
+      internal_compression: COMPRESSION_GZIP,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes();
+    bytes.extend(&root);
+    bytes.extend(&leaf);
+    std::fs::write(&input, bytes).unwrap();
+
+    let (_, entries) = read_entries(&input);
+    let tile_ids: Vec<u64> = entries.iter().map(|entry| entry.tile_id).collect();
+    assert_eq!(tile_ids, vec![0, 1, 2, 3]);
+    std::fs::remove_file(&input).unwrap();
+  }
+
+  #[test]
+  fn test_writer() {
+    let output = std::env::temp_dir().join(format!("writer_{}.pmtiles", std::process::id()));
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let handle = initialize_writer(output.clone(), rx, HashMap::new());
+    // an empty tile first, then a tile that is written twice
+    for (tile, data) in [
+      ((0, 0, 0), vec![]),
+      ((0, 0, 1), vec![1, 2, 3]),
+      ((0, 0, 1), vec![4, 5]),
+      ((0, 1, 1), vec![4, 5]),
+    ] {
+      tx.send(reader::TileData {
+        tile,
+        data: std::sync::Arc::new(data),
+        chunk: None,
+      })
+      .unwrap();
+    }
+    drop(tx);
+    handle.join().unwrap();
+
+    let (header, entries) = read_entries(&output);
+    assert_eq!(header.addressed_tiles_count, 3);
+    assert_eq!(
+      entries
+        .iter()
+        .map(|e| (e.tile_id, e.length, e.run_length))
+        .collect::<Vec<_>>(),
+      vec![(0, 0, 1), (1, 2, 2)]
+    );
+    let mut file = File::open(&output).unwrap();
+    let data = read_range(&mut file, header.tile_data_offset + entries[1].offset, 2).unwrap();
+    assert_eq!(data, vec![4, 5]);
+    std::fs::remove_file(&output).unwrap();
+  }
+}
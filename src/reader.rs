@@ -1,14 +1,97 @@
-use crate::tilebelt::TileData;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::checkpoint::{Checkpoint, ChunkRef};
+use crate::interrupt::is_interrupted;
+use crate::pmtiles;
+use crate::tilebelt::{flip_x, Tile};
+use flate2::read::GzDecoder;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
+// A tile on its way from a reader through the pipeline to the writer.
+#[derive(Clone)]
+pub struct TileData {
+  pub tile: Tile,
+  pub data: Arc<Vec<u8>>,
+  // the input chunk the tile comes from, in jobs that record a checkpoint
+  pub chunk: Option<ChunkRef>,
+}
+
 pub const EXTENT_CHUNK_TILE_COUNT: u64 = u64::pow(2, 15);
 
+// The size of the tiles that can wait in each queue between the stages of a pipeline, so a
+// stage that falls behind blocks the ones before it instead of filling up the memory.
+pub const QUEUE_MEMORY_BUDGET: u64 = 256 * 1024 * 1024;
+
+// assumed when the input can't tell its tile size
+pub const DEFAULT_TILE_SIZE: u64 = 64 * 1024;
+
+// The number of tiles of the given average size that fit in QUEUE_MEMORY_BUDGET.
+pub fn queue_capacity(average_tile_size: u64) -> usize {
+  (QUEUE_MEMORY_BUDGET / std::cmp::max(average_tile_size, 1)).clamp(256, 1 << 20) as usize
+}
+
+// Estimates the average tile size from the PMTiles header, or from a sample of mbtiles tiles.
+pub fn average_tile_size(input: &Path) -> u64 {
+  match sample_tile_size(input) {
+    Some(average) if average > 0 => average,
+    // the input is empty or can't be read, whoever reads it next reports why
+    _ => DEFAULT_TILE_SIZE,
+  }
+}
+
+fn sample_tile_size(input: &Path) -> Option<u64> {
+  if pmtiles::is_pmtiles(input) {
+    let mut bytes = vec![0; pmtiles::HEADER_LENGTH];
+    File::open(input).ok()?.read_exact(&mut bytes).ok()?;
+    let header = pmtiles::Header::try_from_bytes(&bytes).ok()?;
+    return Some(header.tile_data_length / std::cmp::max(header.tile_contents_count, 1));
+  }
+  let connection = sqlite::open(input).ok()?;
+  connection.execute("PRAGMA query_only = true;").ok()?;
+  let mut stmt = connection
+    .prepare("SELECT AVG(LENGTH(tile_data)) FROM (SELECT tile_data FROM tiles LIMIT 1024);")
+    .ok()?;
+  stmt.next().ok()?;
+  Some(stmt.read::<Option<f64>>(0).ok()?.unwrap_or(0.0) as u64)
+}
+
+pub fn maybe_decompress(data: Vec<u8>) -> Vec<u8> {
+  try_decompress(data).unwrap()
+}
+
+// Like `maybe_decompress`, for tiles that may be broken.
+pub fn try_decompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+  // empty tiles are valid, and too short to be gzipped
+  if data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut zlib = GzDecoder::new(data.as_slice());
+    zlib.read_to_end(&mut out)?;
+    return Ok(out);
+  }
+  Ok(data)
+}
+
+// How the mbtiles reader splits the tiles between its workers. PMTiles archives are always
+// read in tile ID order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadStrategy {
+  // rowid ranges when the tiles have rowids, extents otherwise
+  Auto,
+  // bounding boxes of tile coordinates at each zoom level, which can be mostly empty in
+  // sparse archives
+  Extents,
+  // contiguous rowid ranges of `tiles`, or of `map` in deduplicated archives, with about the
+  // same number of tiles in each range
+  RowidRanges,
+}
+
 #[derive(Debug, Clone, Copy)]
 struct InputTileZoomExtent {
   zoom: u8,
@@ -91,7 +174,7 @@ fn split_tile_extent_recursive(e: InputTileZoomExtent) -> Vec<InputTileZoomExten
 }
 
 fn initialize_extents(input: PathBuf) -> Vec<InputTileZoomExtent> {
-  println!("Querying mbtiles for tile extents...");
+  eprintln!("Querying mbtiles for tile extents...");
   let mut input_extents = Vec::<InputTileZoomExtent>::new();
   let connection = sqlite::open(&input).unwrap();
   connection.execute("PRAGMA query_only = true;").unwrap();
@@ -139,20 +222,197 @@ fn initialize_extents(input: PathBuf) -> Vec<InputTileZoomExtent> {
   extents
 }
 
+// The table that can be read by rowid ranges and the query reading a range of it, `tiles` is a
+// view in deduplicated archives.
+fn rowid_table(connection: &sqlite::Connection) -> Option<(&'static str, &'static str)> {
+  let mut stmt = connection
+    .prepare("SELECT type FROM sqlite_master WHERE name = 'tiles';")
+    .unwrap();
+  let tiles_type = match stmt.next().unwrap() {
+    sqlite::State::Row => stmt.read::<String>(0).unwrap(),
+    sqlite::State::Done => return None,
+  };
+  let (table, query) = if tiles_type == "table" {
+    (
+      "tiles",
+      "
+      SELECT zoom_level, tile_column, tile_row, tile_data
+      FROM tiles
+      WHERE rowid >= ? AND rowid <= ?
+    ",
+    )
+  } else {
+    (
+      "map",
+      "
+      SELECT map.zoom_level, map.tile_column, map.tile_row, images.tile_data
+      FROM map
+      JOIN images ON images.tile_id = map.tile_id
+      WHERE map.rowid >= ? AND map.rowid <= ?
+    ",
+    )
+  };
+  // fails on views of other layouts and WITHOUT ROWID tables
+  connection.prepare(query).ok()?;
+  Some((table, query))
+}
+
+// Splits the rowids of `table` into ranges of at most EXTENT_CHUNK_TILE_COUNT tiles.
+fn initialize_rowid_ranges(connection: &sqlite::Connection, table: &str) -> Vec<(i64, i64)> {
+  let mut stmt = connection
+    .prepare(format!(
+      "SELECT MIN(rowid), MAX(rowid), COUNT(*) FROM {};",
+      table
+    ))
+    .unwrap();
+  stmt.next().unwrap();
+  let count = stmt.read::<i64>(2).unwrap();
+  if count == 0 {
+    return vec![];
+  }
+  let min_rowid = stmt.read::<i64>(0).unwrap();
+  let max_rowid = stmt.read::<i64>(1).unwrap();
+  let chunk_size = EXTENT_CHUNK_TILE_COUNT as i64;
+
+  if (max_rowid - min_rowid) / 2 < count {
+    // rowids are dense enough that fixed ranges are balanced
+    return (min_rowid..=max_rowid)
+      .step_by(chunk_size as usize)
+      .map(|start| (start, std::cmp::min(start + chunk_size - 1, max_rowid)))
+      .collect();
+  }
+
+  // many rows were deleted, step over the rowids to find where each range starts
+  let mut ranges = Vec::new();
+  let mut stmt = connection
+    .prepare(format!(
+      "SELECT rowid FROM {} WHERE rowid >= ? ORDER BY rowid LIMIT 1 OFFSET ?;",
+      table
+    ))
+    .unwrap();
+  let mut start = min_rowid;
+  loop {
+    stmt.bind(1, start).unwrap();
+    stmt.bind(2, chunk_size).unwrap();
+    let next_start = match stmt.next().unwrap() {
+      sqlite::State::Row => stmt.read::<i64>(0).unwrap(),
+      sqlite::State::Done => {
+        ranges.push((start, max_rowid));
+        break;
+      }
+    };
+    stmt.reset().unwrap();
+    ranges.push((start, next_start - 1));
+    start = next_start;
+  }
+  ranges
+}
+
+// Starts the checkpoint of a resumable job on the chunks the input was split into, and returns
+// the chunks an earlier run already committed.
+fn start_checkpoint(checkpoint: Option<&Arc<Checkpoint>>, chunks: String) -> Arc<HashSet<usize>> {
+  let done = match checkpoint {
+    Some(checkpoint) => {
+      let done = checkpoint.start(&format!("{:x}", md5::compute(chunks)));
+      if !done.is_empty() {
+        eprintln!("Skipping {} chunks of an earlier run.", done.len());
+      }
+      done
+    }
+    None => HashSet::new(),
+  };
+  Arc::new(done)
+}
+
+fn initialize_rowid_threads(
+  ranges: Vec<(i64, i64)>,
+  query: &'static str,
+  input: PathBuf,
+  output_queue_tx: crossbeam_channel::Sender<TileData>,
+  checkpoint: Option<Arc<Checkpoint>>,
+) {
+  let done = start_checkpoint(checkpoint.as_ref(), format!("rowid {:?}", ranges));
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  eprintln!(
+    "Spawning {} input workers for {} rowid ranges.",
+    max_workers,
+    ranges.len()
+  );
+
+  // workers take the next range when they are done with one, so they all finish together
+  let (range_queue_tx, range_queue_rx) = crossbeam_channel::unbounded();
+  for (chunk, range) in ranges.into_iter().enumerate() {
+    if !done.contains(&chunk) {
+      range_queue_tx.send((chunk, range)).unwrap();
+    }
+  }
+  drop(range_queue_tx);
+
+  for worker_id in 0..max_workers {
+    let thread_range_queue_rx = range_queue_rx.clone();
+    let thread_input = input.clone();
+    let thread_output_queue_tx = output_queue_tx.clone();
+    let thread_checkpoint = checkpoint.clone();
+    thread::spawn(move || {
+      let connection = sqlite::open(thread_input).unwrap();
+      connection.execute("PRAGMA query_only = true;").unwrap();
+      let mut statement = connection.prepare(query).unwrap();
+
+      while let Ok((chunk, (start, end))) = thread_range_queue_rx.recv() {
+        if is_interrupted() {
+          break;
+        }
+        let chunk_ref = thread_checkpoint
+          .as_ref()
+          .map(|checkpoint| ChunkRef::new(chunk, checkpoint.clone()));
+        statement.bind(1, start).unwrap();
+        statement.bind(2, end).unwrap();
+
+        while let sqlite::State::Row = statement.next().unwrap() {
+          if is_interrupted() {
+            break;
+          }
+          let zoom_level = statement.read::<i64>(0).unwrap() as u32;
+          let tile_column = statement.read::<i64>(1).unwrap() as u32;
+          let tile_row = statement.read::<i64>(2).unwrap() as u32;
+          let tile_data = Arc::new(statement.read::<Vec<u8>>(3).unwrap());
+
+          thread_output_queue_tx
+            .send(TileData {
+              tile: (tile_column, tile_row, zoom_level),
+              data: tile_data,
+              chunk: chunk_ref.clone(),
+            })
+            .unwrap();
+        }
+
+        statement.reset().unwrap();
+      }
+
+      eprintln!("Finished reading tiles ({}).", worker_id);
+    });
+  }
+}
+
 fn initialize_threads(
   extents: Vec<InputTileZoomExtent>,
   input: PathBuf,
   output_queue_tx: crossbeam_channel::Sender<TileData>,
+  checkpoint: Option<Arc<Checkpoint>>,
 ) {
+  let done = start_checkpoint(checkpoint.as_ref(), format!("extents {:?}", extents));
+
   // worker threads
-  let max_workers = std::cmp::max(num_cpus::get() - 2, 2);
-  println!("Spawning {} input workers.", max_workers);
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  eprintln!("Spawning {} input workers.", max_workers);
 
   let shared_extents = Arc::new(extents);
   for worker_id in 0..max_workers {
     let thread_extents = shared_extents.clone();
     let thread_input = input.clone();
     let thread_output_queue_tx = output_queue_tx.clone();
+    let thread_checkpoint = checkpoint.clone();
+    let thread_done = done.clone();
     thread::spawn(move || {
       let connection = sqlite::open(thread_input).unwrap();
       connection.execute("PRAGMA query_only = true;").unwrap();
@@ -179,15 +439,23 @@ fn initialize_threads(
 
       let extent_n = worker_id + 1;
 
-      let mut our_extents: Vec<&InputTileZoomExtent> = thread_extents
+      let mut our_extents: Vec<(usize, &InputTileZoomExtent)> = thread_extents
         .iter()
+        .enumerate()
         .skip(extent_n - 1)
         .step_by(max_workers)
+        .filter(|(chunk, _)| !thread_done.contains(chunk))
         .collect();
       // shuffle our_extents to evenly distribute workload
       our_extents.shuffle(&mut thread_rng());
 
-      for extent in our_extents {
+      for (chunk, extent) in our_extents {
+        if is_interrupted() {
+          break;
+        }
+        let chunk_ref = thread_checkpoint
+          .as_ref()
+          .map(|checkpoint| ChunkRef::new(chunk, checkpoint.clone()));
         statement.bind(1, extent.zoom as i64).unwrap();
         statement.bind(2, extent.min_x as i64).unwrap();
         statement.bind(3, extent.max_x as i64).unwrap();
@@ -195,6 +463,9 @@ fn initialize_threads(
         statement.bind(5, extent.max_y as i64).unwrap();
 
         while let sqlite::State::Row = statement.next().unwrap() {
+          if is_interrupted() {
+            break;
+          }
           let zoom_level = statement.read::<i64>(0).unwrap() as u32;
           let tile_column = statement.read::<i64>(1).unwrap() as u32;
           let tile_row = statement.read::<i64>(2).unwrap() as u32;
@@ -204,6 +475,7 @@ fn initialize_threads(
             .send(TileData {
               tile: (tile_column, tile_row, zoom_level),
               data: tile_data.clone(),
+              chunk: chunk_ref.clone(),
             })
             .unwrap();
         }
@@ -211,7 +483,78 @@ fn initialize_threads(
         statement.reset().unwrap();
       }
 
-      println!("Finished reading tiles ({}).", worker_id);
+      eprintln!("Finished reading tiles ({}).", worker_id);
+    });
+  }
+}
+
+fn initialize_pmtiles_threads(
+  input: PathBuf,
+  output_queue_tx: crossbeam_channel::Sender<TileData>,
+  checkpoint: Option<Arc<Checkpoint>>,
+) {
+  eprintln!("Reading PMTiles directories...");
+  let (header, entries) = pmtiles::read_entries(&input);
+
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  eprintln!("Spawning {} input workers.", max_workers);
+
+  // entries are already ordered by tile ID, so each chunk covers a compact area of the map
+  let chunks: Vec<Vec<pmtiles::Entry>> = entries
+    .chunks(EXTENT_CHUNK_TILE_COUNT as usize)
+    .map(|chunk| chunk.to_vec())
+    .collect();
+  let chunk_ranges: Vec<(u64, u64)> = chunks
+    .iter()
+    .map(|chunk| (chunk[0].tile_id, chunk[chunk.len() - 1].tile_id))
+    .collect();
+  let done = start_checkpoint(checkpoint.as_ref(), format!("pmtiles {:?}", chunk_ranges));
+  let shared_chunks = Arc::new(chunks);
+  for worker_id in 0..max_workers {
+    let thread_chunks = shared_chunks.clone();
+    let thread_input = input.clone();
+    let thread_output_queue_tx = output_queue_tx.clone();
+    let tile_data_offset = header.tile_data_offset;
+    let thread_checkpoint = checkpoint.clone();
+    let thread_done = done.clone();
+    thread::spawn(move || {
+      let mut file = File::open(thread_input).unwrap();
+
+      let our_chunks = thread_chunks
+        .iter()
+        .enumerate()
+        .skip(worker_id)
+        .step_by(max_workers);
+      for (chunk, entries) in our_chunks.filter(|(chunk, _)| !thread_done.contains(chunk)) {
+        let chunk_ref = thread_checkpoint
+          .as_ref()
+          .map(|checkpoint| ChunkRef::new(chunk, checkpoint.clone()));
+        for entry in entries {
+          if is_interrupted() {
+            break;
+          }
+          let mut data = vec![0; entry.length as usize];
+          file
+            .seek(SeekFrom::Start(tile_data_offset + entry.offset))
+            .unwrap();
+          file.read_exact(&mut data).unwrap();
+          let tile_data = Arc::new(data);
+
+          for tile_id in entry.tile_id..(entry.tile_id + entry.run_length as u64) {
+            let (z, x, y) = pmtiles::tile_id_to_zxy(tile_id);
+            // tiles are handed out with TMS rows, the same as the mbtiles reader
+            thread_output_queue_tx
+              .send(TileData {
+                tile: flip_x((x, y, z as u32)),
+                data: tile_data.clone(),
+                chunk: chunk_ref.clone(),
+              })
+              .unwrap();
+          }
+        }
+      }
+
+      eprintln!("Finished reading tiles ({}).", worker_id);
     });
   }
 }
@@ -219,32 +562,345 @@ fn initialize_threads(
 pub struct Reader {
   input: PathBuf,
   output_rx: crossbeam_channel::Receiver<TileData>,
+  queue_capacity: usize,
 }
 
 impl Reader {
   pub fn new(input: PathBuf) -> Reader {
-    let (output_tx, output_rx) = crossbeam_channel::unbounded();
-    let extents = initialize_extents(input.clone());
-    initialize_threads(extents, input.clone(), output_tx);
-    Reader { input, output_rx }
+    Reader::with_strategy(input, ReadStrategy::Auto)
+  }
+
+  pub fn with_strategy(input: PathBuf, strategy: ReadStrategy) -> Reader {
+    Reader::open(input, strategy, None)
+  }
+
+  // Tiles carry the chunk they were read from, which is recorded in `checkpoint` once they are
+  // committed. The chunks it already has from an earlier run are skipped.
+  pub fn with_checkpoint(input: PathBuf, checkpoint: Arc<Checkpoint>) -> Reader {
+    Reader::open(input, ReadStrategy::Auto, Some(checkpoint))
+  }
+
+  fn open(input: PathBuf, strategy: ReadStrategy, checkpoint: Option<Arc<Checkpoint>>) -> Reader {
+    let queue_capacity = queue_capacity(average_tile_size(&input));
+    let (output_tx, output_rx) = crossbeam_channel::bounded(queue_capacity);
+    if pmtiles::is_pmtiles(&input) {
+      initialize_pmtiles_threads(input.clone(), output_tx, checkpoint);
+      return Reader {
+        input,
+        output_rx,
+        queue_capacity,
+      };
+    }
+
+    let rowid_table = match strategy {
+      ReadStrategy::Extents => None,
+      _ => {
+        let connection = sqlite::open(&input).unwrap();
+        connection.execute("PRAGMA query_only = true;").unwrap();
+        rowid_table(&connection).map(|(table, query)| {
+          eprintln!("Querying mbtiles for rowid ranges...");
+          (initialize_rowid_ranges(&connection, table), query)
+        })
+      }
+    };
+    match rowid_table {
+      Some((ranges, query)) => {
+        initialize_rowid_threads(ranges, query, input.clone(), output_tx, checkpoint)
+      }
+      None if strategy == ReadStrategy::RowidRanges => {
+        panic!("The tiles of {} can't be read by rowid", input.display())
+      }
+      None => {
+        let extents = initialize_extents(input.clone());
+        initialize_threads(extents, input.clone(), output_tx, checkpoint);
+      }
+    }
+    Reader {
+      input,
+      output_rx,
+      queue_capacity,
+    }
+  }
+
+  // The capacity for the queues of the pipeline the tiles are read into.
+  pub fn queue_capacity(&self) -> usize {
+    self.queue_capacity
   }
 
-  pub fn iter(&mut self) -> crossbeam_channel::Iter<TileData> {
-    self.output_rx.iter()
+  // Once interrupted, the tiles already read are drained from the queue but not handed out, so
+  // the reader threads can stop.
+  pub fn iter(&mut self) -> impl Iterator<Item = TileData> + '_ {
+    self.output_rx.iter().filter(|_| !is_interrupted())
   }
 
   pub fn read_metadata(&mut self) -> HashMap<String, String> {
-    let connection = sqlite::open(&self.input).unwrap();
-    connection.execute("PRAGMA query_only = true;").unwrap();
-    let mut metadata_stmt = connection
-      .prepare("SELECT name, value FROM metadata;")
+    read_metadata(&self.input)
+  }
+}
+
+pub fn read_metadata(input: &Path) -> HashMap<String, String> {
+  if pmtiles::is_pmtiles(input) {
+    return pmtiles::read_metadata(input);
+  }
+
+  let connection = sqlite::open(input).unwrap();
+  connection.execute("PRAGMA query_only = true;").unwrap();
+  let mut metadata_stmt = connection
+    .prepare("SELECT name, value FROM metadata;")
+    .unwrap();
+  let mut metadata = HashMap::<String, String>::new();
+  while let sqlite::State::Row = metadata_stmt.next().unwrap() {
+    let name = metadata_stmt.read::<String>(0).unwrap();
+    let value = metadata_stmt.read::<String>(1).unwrap();
+    metadata.insert(name, value);
+  }
+  metadata
+}
+
+// Which tiles an archive has and their data, looked up one tile at a time instead of reading all
+// of them. The entries of a PMTiles directory are runs of tiles, so they are read once and shared.
+#[derive(Clone)]
+pub enum TileIndex {
+  Mbtiles(PathBuf),
+  Pmtiles(PmtilesIndex),
+}
+
+#[derive(Clone)]
+pub struct PmtilesIndex {
+  input: PathBuf,
+  tile_data_offset: u64,
+  entries: Arc<Vec<pmtiles::Entry>>,
+}
+
+// A `TileIndex` opened by one thread, its lookups borrow the connection.
+pub enum TileConnection {
+  Mbtiles(sqlite::Connection),
+  Pmtiles(PmtilesIndex),
+}
+
+// The lookups of one thread, with the statements prepared once and reused for every tile.
+pub enum TileLookup<'a> {
+  Mbtiles {
+    contains: sqlite::Statement<'a>,
+    get: sqlite::Statement<'a>,
+  },
+  Pmtiles {
+    index: &'a PmtilesIndex,
+    file: File,
+  },
+}
+
+impl TileIndex {
+  pub fn new(input: &Path) -> TileIndex {
+    if pmtiles::is_pmtiles(input) {
+      let (header, entries) = pmtiles::read_entries(input);
+      return TileIndex::Pmtiles(PmtilesIndex {
+        input: input.to_path_buf(),
+        tile_data_offset: header.tile_data_offset,
+        entries: Arc::new(entries),
+      });
+    }
+    TileIndex::Mbtiles(input.to_path_buf())
+  }
+
+  pub fn open(&self) -> TileConnection {
+    match self {
+      TileIndex::Mbtiles(input) => {
+        let connection = sqlite::open(input).unwrap();
+        connection.execute("PRAGMA query_only = true;").unwrap();
+        TileConnection::Mbtiles(connection)
+      }
+      TileIndex::Pmtiles(index) => TileConnection::Pmtiles(index.clone()),
+    }
+  }
+}
+
+impl PmtilesIndex {
+  // the run of tiles `tile` is part of, `tile` is a XYZ tile
+  fn find(&self, tile: &Tile) -> Option<&pmtiles::Entry> {
+    let tile_id = pmtiles::zxy_to_tile_id(tile.2 as u8, tile.0, tile.1);
+    // the last run that starts at or before the tile
+    let idx = self
+      .entries
+      .partition_point(|entry| entry.tile_id <= tile_id);
+    let entry = self.entries.get(idx.checked_sub(1)?)?;
+    if tile_id < entry.tile_id + entry.run_length as u64 {
+      Some(entry)
+    } else {
+      None
+    }
+  }
+}
+
+impl TileConnection {
+  pub fn lookup(&self) -> TileLookup<'_> {
+    match self {
+      TileConnection::Mbtiles(connection) => {
+        let prepare = |columns: &str| {
+          connection
+            .prepare(format!(
+              "SELECT {} FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?;",
+              columns
+            ))
+            .unwrap()
+        };
+        TileLookup::Mbtiles {
+          contains: prepare("1"),
+          get: prepare("tile_data"),
+        }
+      }
+      TileConnection::Pmtiles(index) => TileLookup::Pmtiles {
+        index,
+        file: File::open(&index.input).unwrap(),
+      },
+    }
+  }
+}
+
+// Runs `stmt` for the TMS row of the XYZ `tile`, reading the first column if there is a row.
+fn query_tile<T: sqlite::Readable>(stmt: &mut sqlite::Statement, tile: &Tile) -> Option<T> {
+  let (tile_column, tile_row, zoom_level) = flip_x(*tile);
+  stmt.bind(1, zoom_level as i64).unwrap();
+  stmt.bind(2, tile_column as i64).unwrap();
+  stmt.bind(3, tile_row as i64).unwrap();
+  let value = match stmt.next().unwrap() {
+    sqlite::State::Row => Some(stmt.read::<T>(0).unwrap()),
+    sqlite::State::Done => None,
+  };
+  stmt.reset().unwrap();
+  value
+}
+
+impl<'a> TileLookup<'a> {
+  // `tile` is a XYZ tile
+  pub fn contains(&mut self, tile: &Tile) -> bool {
+    match self {
+      TileLookup::Mbtiles { contains, .. } => query_tile::<i64>(contains, tile).is_some(),
+      TileLookup::Pmtiles { index, .. } => index.find(tile).is_some(),
+    }
+  }
+
+  // The data of the XYZ `tile`, as it is stored in the archive.
+  pub fn get(&mut self, tile: &Tile) -> Option<Vec<u8>> {
+    match self {
+      TileLookup::Mbtiles { get, .. } => query_tile(get, tile),
+      TileLookup::Pmtiles { index, file } => {
+        let entry = index.find(tile)?;
+        let mut data = vec![0; entry.length as usize];
+        file
+          .seek(SeekFrom::Start(index.tile_data_offset + entry.offset))
+          .unwrap();
+        file.read_exact(&mut data).unwrap();
+        Some(data)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read_tiles(input: &Path, strategy: ReadStrategy) -> Vec<Tile> {
+    let mut reader = Reader::with_strategy(input.to_path_buf(), strategy);
+    let mut tiles: Vec<Tile> = reader.iter().map(|t| t.tile).collect();
+    tiles.sort();
+    tiles
+  }
+
+  #[test]
+  fn test_average_tile_size() {
+    let input = std::env::temp_dir().join(format!("no_tiles_{}.mbtiles", std::process::id()));
+    let _ = std::fs::remove_file(&input);
+    let connection = sqlite::open(&input).unwrap();
+    connection
+      .execute("CREATE TABLE metadata (name text, value text);")
       .unwrap();
-    let mut metadata = HashMap::<String, String>::new();
-    while let sqlite::State::Row = metadata_stmt.next().unwrap() {
-      let name = metadata_stmt.read::<String>(0).unwrap();
-      let value = metadata_stmt.read::<String>(1).unwrap();
-      metadata.insert(name, value);
+    drop(connection);
+    // without a tiles table there is nothing to sample
+    assert_eq!(average_tile_size(&input), DEFAULT_TILE_SIZE);
+    std::fs::remove_file(&input).unwrap();
+  }
+
+  #[test]
+  fn test_maybe_decompress() {
+    assert_eq!(maybe_decompress(vec![]), Vec::<u8>::new());
+    assert_eq!(maybe_decompress(vec![0x1f]), vec![0x1f]);
+    assert!(try_decompress(vec![0x1f, 0x8b, 0, 0]).is_err());
+  }
+
+  #[test]
+  fn test_read_strategies() {
+    let input = std::env::temp_dir().join(format!("sparse_{}.mbtiles", std::process::id()));
+    let _ = std::fs::remove_file(&input);
+    let connection = sqlite::open(&input).unwrap();
+    connection
+      .execute(
+        "
+        CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data blob);
+        CREATE TABLE metadata (name text, value text);
+        ",
+      )
+      .unwrap();
+    // two islands far apart at z16, with most rows deleted afterwards
+    connection.execute("BEGIN TRANSACTION;").unwrap();
+    let mut stmt = connection
+      .prepare("INSERT INTO tiles VALUES (16, ?, ?, x'00');")
+      .unwrap();
+    for (x, y) in (0..400).flat_map(|x| (0..300).map(move |y| (x, y))) {
+      stmt
+        .bind(1, x + if x % 2 == 0 { 0 } else { 60000 })
+        .unwrap();
+      stmt.bind(2, y).unwrap();
+      stmt.next().unwrap();
+      stmt.reset().unwrap();
     }
-    metadata
+    drop(stmt);
+    connection
+      .execute("DELETE FROM tiles WHERE tile_row % 3 != 0; COMMIT;")
+      .unwrap();
+    drop(connection);
+
+    let connection = sqlite::open(&input).unwrap();
+    let (table, _) = rowid_table(&connection).unwrap();
+    let ranges = initialize_rowid_ranges(&connection, table);
+    // the rowids are sparse, so the ranges are found by stepping over them
+    assert_eq!(ranges, vec![(1, 98304), (98305, 119998)]);
+    drop(connection);
+
+    let tiles = read_tiles(&input, ReadStrategy::RowidRanges);
+    assert_eq!(tiles.len(), 400 * 100);
+    assert_eq!(tiles, read_tiles(&input, ReadStrategy::Extents));
+    std::fs::remove_file(&input).unwrap();
+  }
+
+  #[test]
+  fn test_tile_lookup() {
+    let input = std::env::temp_dir().join(format!("lookup_{}.mbtiles", std::process::id()));
+    let _ = std::fs::remove_file(&input);
+    let connection = sqlite::open(&input).unwrap();
+    connection
+      .execute(
+        "
+        CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data blob);
+        INSERT INTO tiles VALUES (0, 0, 0, x'00'), (2, 1, 0, x'01');
+        ",
+      )
+      .unwrap();
+    drop(connection);
+
+    let connection = TileIndex::new(&input).open();
+    let mut lookup = connection.lookup();
+    assert_eq!(lookup.get(&(0, 0, 0)), Some(vec![0]));
+    // tile rows are flipped in mbtiles
+    assert_eq!(lookup.get(&(1, 3, 2)), Some(vec![1]));
+    assert_eq!(lookup.get(&(1, 0, 2)), None);
+    assert!(lookup.contains(&(1, 3, 2)));
+    assert!(!lookup.contains(&(0, 0, 1)));
+    // the statement is reused
+    assert_eq!(lookup.get(&(0, 0, 0)), Some(vec![0]));
+    drop(lookup);
+    drop(connection);
+    std::fs::remove_file(&input).unwrap();
   }
 }
@@ -0,0 +1,557 @@
+use crate::regionclip::{self, ClipRing, FloatBoundingBox, FloatPoint};
+use mbtiles_tool::tilebelt::{self, Tile};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coverage {
+  Outside,
+  Partial,
+  Inside,
+}
+
+// A region is kept in web mercator world coordinates (see `tilebelt::lng_lat_to_world`),
+// so that tiles map to plain rectangles.
+pub struct Region {
+  rings: Vec<ClipRing>,
+  // set when the region is a single bounding box, which can be clipped with `lineclip`
+  rectangle: Option<FloatBoundingBox>,
+  // in order of: west, south, east, north
+  lng_lat_bbox: (f64, f64, f64, f64),
+}
+
+// same fraction of a tile as `vector_tile_ops::clip_geometry` keeps around each tile
+const TILE_BUFFER: f64 = 1.0 / 16.0;
+// region rings are stored clipped to this much around each tile, so they stay small at high zooms
+const RING_BUFFER: f64 = 1.0 / 4.0;
+const INDEX_BANDS: usize = 1024;
+
+fn rectangle_ring(bbox: FloatBoundingBox) -> Vec<FloatPoint> {
+  vec![
+    (bbox.0, bbox.1),
+    (bbox.2, bbox.1),
+    (bbox.2, bbox.3),
+    (bbox.0, bbox.3),
+  ]
+}
+
+fn buffered_tile_bbox(tile: &Tile, buffer: f64) -> FloatBoundingBox {
+  let size = 1.0 / (1u64 << tile.2) as f64;
+  let margin = size * buffer;
+  (
+    tile.0 as f64 * size - margin,
+    tile.1 as f64 * size - margin,
+    (tile.0 + 1) as f64 * size + margin,
+    (tile.1 + 1) as f64 * size + margin,
+  )
+}
+
+pub fn parse_bbox(value: &str) -> (f64, f64, f64, f64) {
+  let values: Vec<f64> = value
+    .split(',')
+    .map(|v| {
+      v.trim()
+        .parse::<f64>()
+        .unwrap_or_else(|_| panic!("Invalid bbox value: {}", v))
+    })
+    .collect();
+  if values.len() != 4 {
+    panic!(
+      "A bbox needs 4 values (west,south,east,north), got {}",
+      value
+    );
+  }
+  (values[0], values[1], values[2], values[3])
+}
+
+// The `bounds` of the metadata of an archive, which can be missing or malformed.
+pub fn parse_bounds(bounds: &str) -> Option<[f64; 4]> {
+  let parts: Vec<f64> = bounds
+    .split(',')
+    .map(|n| n.trim().parse::<f64>())
+    .collect::<Result<Vec<f64>, _>>()
+    .ok()?;
+  if parts.len() != 4 {
+    return None;
+  }
+  Some([parts[0], parts[1], parts[2], parts[3]])
+}
+
+fn parse_ring(value: &serde_json::Value) -> Vec<(f64, f64)> {
+  let mut ring: Vec<(f64, f64)> = value
+    .as_array()
+    .expect("GeoJSON rings must be arrays of positions")
+    .iter()
+    .map(|position| {
+      let lng = position[0].as_f64().expect("Invalid GeoJSON position");
+      let lat = position[1].as_f64().expect("Invalid GeoJSON position");
+      (lng, lat)
+    })
+    .collect();
+  // GeoJSON rings repeat their first position at the end
+  if ring.len() > 1 && ring.first() == ring.last() {
+    ring.pop();
+  }
+  ring
+}
+
+fn collect_polygons(geojson: &serde_json::Value, polygons: &mut Vec<Vec<Vec<(f64, f64)>>>) {
+  match geojson["type"].as_str() {
+    Some("FeatureCollection") => {
+      for feature in geojson["features"].as_array().into_iter().flatten() {
+        collect_polygons(feature, polygons);
+      }
+    }
+    Some("Feature") => collect_polygons(&geojson["geometry"], polygons),
+    Some("GeometryCollection") => {
+      for geometry in geojson["geometries"].as_array().into_iter().flatten() {
+        collect_polygons(geometry, polygons);
+      }
+    }
+    Some("Polygon") => {
+      let polygon = geojson["coordinates"]
+        .as_array()
+        .expect("Invalid GeoJSON Polygon")
+        .iter()
+        .map(parse_ring)
+        .collect();
+      polygons.push(polygon);
+    }
+    Some("MultiPolygon") => {
+      for coordinates in geojson["coordinates"]
+        .as_array()
+        .expect("Invalid GeoJSON MultiPolygon")
+      {
+        polygons.push(
+          coordinates
+            .as_array()
+            .expect("Invalid GeoJSON MultiPolygon")
+            .iter()
+            .map(parse_ring)
+            .collect(),
+        );
+      }
+    }
+    // points and lines don't enclose anything
+    _ => {}
+  }
+}
+
+impl Region {
+  pub fn from_bbox(bbox: (f64, f64, f64, f64)) -> Region {
+    let (west, south, east, north) = bbox;
+    if south >= north {
+      panic!("The south edge of the bbox must be below its north edge");
+    }
+    let (min_x, max_y) = tilebelt::lng_lat_to_world(west, south);
+    let (max_x, min_y) = tilebelt::lng_lat_to_world(east, north);
+
+    if west > east {
+      // crosses the antimeridian, which makes it two boxes
+      let boxes = [(min_x, min_y, 1.0, max_y), (0.0, min_y, max_x, max_y)];
+      return Region {
+        rings: boxes
+          .iter()
+          .map(|b| ClipRing {
+            outer: true,
+            points: rectangle_ring(*b),
+          })
+          .collect(),
+        rectangle: None,
+        lng_lat_bbox: bbox,
+      };
+    }
+
+    let rectangle = (min_x, min_y, max_x, max_y);
+    Region {
+      rings: vec![ClipRing {
+        outer: true,
+        points: rectangle_ring(rectangle),
+      }],
+      rectangle: Some(rectangle),
+      lng_lat_bbox: bbox,
+    }
+  }
+
+  // Accepts a (Multi)Polygon geometry, a Feature or a FeatureCollection, polygons of
+  // all features are combined.
+  pub fn from_geojson(geojson: &serde_json::Value) -> Region {
+    let mut polygons = Vec::new();
+    collect_polygons(geojson, &mut polygons);
+
+    let mut rings = Vec::new();
+    let mut lng_lat_bbox = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for polygon in polygons {
+      for (i, ring) in polygon.into_iter().enumerate() {
+        if ring.len() < 3 {
+          continue;
+        }
+        for &(lng, lat) in ring.iter() {
+          lng_lat_bbox.0 = lng_lat_bbox.0.min(lng);
+          lng_lat_bbox.1 = lng_lat_bbox.1.min(lat);
+          lng_lat_bbox.2 = lng_lat_bbox.2.max(lng);
+          lng_lat_bbox.3 = lng_lat_bbox.3.max(lat);
+        }
+        rings.push(ClipRing {
+          // the first ring of a GeoJSON polygon is its exterior
+          outer: i == 0,
+          points: ring
+            .into_iter()
+            .map(|(lng, lat)| tilebelt::lng_lat_to_world(lng, lat))
+            .collect(),
+        });
+      }
+    }
+    if rings.is_empty() {
+      panic!("The GeoJSON does not contain any Polygon or MultiPolygon");
+    }
+
+    Region {
+      rings,
+      rectangle: None,
+      lng_lat_bbox,
+    }
+  }
+
+  pub fn from_geojson_file(path: &Path) -> Region {
+    let contents = std::fs::read_to_string(path)
+      .unwrap_or_else(|_| panic!("Could not read GeoJSON file {}", path.display()));
+    let geojson: serde_json::Value = serde_json::from_str(&contents)
+      .unwrap_or_else(|_| panic!("Could not parse GeoJSON file {}", path.display()));
+    Region::from_geojson(&geojson)
+  }
+
+  pub fn lng_lat_bbox(&self) -> (f64, f64, f64, f64) {
+    self.lng_lat_bbox
+  }
+
+  // The region as a bounding box in the coordinates of a tile with the given extent,
+  // if it is a single bounding box.
+  pub fn tile_rectangle(&self, tile: &Tile, extent: u32) -> Option<(i32, i32, i32, i32)> {
+    let rectangle = self.rectangle?;
+    let scale = (1u64 << tile.2) as f64;
+    let to_tile =
+      |v: f64, origin: u32| ((v * scale - origin as f64) * extent as f64).round() as i32;
+    Some((
+      to_tile(rectangle.0, tile.0),
+      to_tile(rectangle.1, tile.1),
+      to_tile(rectangle.2, tile.0),
+      to_tile(rectangle.3, tile.1),
+    ))
+  }
+
+  // Classifies every tile that intersects the region boundary down to `maxzoom`.
+  pub fn tile_coverage(&self, maxzoom: u8) -> TileCoverage {
+    let edge_index = EdgeIndex::new(&self.rings);
+    let mut coverage = TileCoverage {
+      maxzoom,
+      coverage: HashMap::new(),
+      rings: HashMap::new(),
+    };
+    let all_edges: Vec<usize> = (0..edge_index.edges.len()).collect();
+    index_tile(
+      &(0, 0, 0),
+      &all_edges,
+      &self.rings,
+      &edge_index,
+      &mut coverage,
+    );
+    coverage
+  }
+}
+
+// All region edges, bucketed by the horizontal bands they span so that
+// point in polygon tests don't have to look at every edge.
+struct EdgeIndex {
+  edges: Vec<(FloatPoint, FloatPoint)>,
+  bands: Vec<Vec<usize>>,
+  min_y: f64,
+  band_height: f64,
+}
+
+impl EdgeIndex {
+  fn new(rings: &[ClipRing]) -> EdgeIndex {
+    let mut edges = Vec::new();
+    for ring in rings {
+      for (i, a) in ring.points.iter().enumerate() {
+        edges.push((*a, ring.points[(i + 1) % ring.points.len()]));
+      }
+    }
+    let min_y = edges.iter().map(|e| e.0 .1).fold(f64::MAX, f64::min);
+    let max_y = edges.iter().map(|e| e.0 .1).fold(f64::MIN, f64::max);
+    let band_height = ((max_y - min_y) / INDEX_BANDS as f64).max(f64::MIN_POSITIVE);
+
+    let mut index = EdgeIndex {
+      edges,
+      bands: vec![Vec::new(); INDEX_BANDS],
+      min_y,
+      band_height,
+    };
+    for (i, (a, b)) in index.edges.iter().enumerate() {
+      let first = index.band(a.1.min(b.1));
+      let last = index.band(a.1.max(b.1));
+      for band in index.bands[first..=last].iter_mut() {
+        band.push(i);
+      }
+    }
+    index
+  }
+
+  fn band(&self, y: f64) -> usize {
+    (((y - self.min_y) / self.band_height).max(0.0) as usize).min(INDEX_BANDS - 1)
+  }
+
+  // even-odd ray casting, only against the edges in the band of the point
+  fn contains(&self, p: FloatPoint) -> bool {
+    if p.1 < self.min_y || p.1 > self.min_y + self.band_height * INDEX_BANDS as f64 {
+      return false;
+    }
+    let mut inside = false;
+    for &i in self.bands[self.band(p.1)].iter() {
+      let (a, b) = self.edges[i];
+      if (a.1 > p.1) != (b.1 > p.1) && p.0 < (b.0 - a.0) * (p.1 - a.1) / (b.1 - a.1) + a.0 {
+        inside = !inside;
+      }
+    }
+    inside
+  }
+}
+
+fn index_tile(
+  tile: &Tile,
+  edges: &[usize],
+  rings: &[ClipRing],
+  edge_index: &EdgeIndex,
+  coverage: &mut TileCoverage,
+) {
+  let bbox = buffered_tile_bbox(tile, TILE_BUFFER);
+  let crossing: Vec<usize> = edges
+    .iter()
+    .copied()
+    .filter(|&i| {
+      let (a, b) = edge_index.edges[i];
+      regionclip::segment_intersects_bbox(a, b, bbox)
+    })
+    .collect();
+
+  if crossing.is_empty() {
+    // no boundary runs through the tile, so it is either completely in or out
+    let center = ((bbox.0 + bbox.2) / 2.0, (bbox.1 + bbox.3) / 2.0);
+    let tile_coverage = if edge_index.contains(center) {
+      Coverage::Inside
+    } else {
+      Coverage::Outside
+    };
+    coverage.coverage.insert(*tile, tile_coverage);
+    return;
+  }
+  coverage.coverage.insert(*tile, Coverage::Partial);
+
+  let ring_bbox = buffered_tile_bbox(tile, RING_BUFFER);
+  let tile_rings: Vec<ClipRing> = rings
+    .iter()
+    .map(|ring| ClipRing {
+      outer: ring.outer,
+      points: regionclip::clip_ring_to_bbox(&ring.points, ring_bbox),
+    })
+    .filter(|ring| ring.points.len() >= 3)
+    .collect();
+
+  if (tile.2 as u8) < coverage.maxzoom {
+    for child in tilebelt::get_children(tile).iter() {
+      index_tile(child, &crossing, &tile_rings, edge_index, coverage);
+    }
+  }
+  coverage.rings.insert(*tile, tile_rings);
+}
+
+pub struct TileCoverage {
+  maxzoom: u8,
+  coverage: HashMap<Tile, Coverage>,
+  // the region rings around each partially covered tile, in world coordinates
+  rings: HashMap<Tile, Vec<ClipRing>>,
+}
+
+impl TileCoverage {
+  // Coverage of a list of tiles: the tiles and everything below them are inside, their
+  // ancestors are partially covered.
+  pub fn from_tiles(tiles: &[Tile]) -> TileCoverage {
+    let mut coverage = TileCoverage {
+      maxzoom: tiles.iter().map(|t| t.2 as u8).max().unwrap_or(0),
+      coverage: HashMap::new(),
+      rings: HashMap::new(),
+    };
+    for tile in tiles {
+      for z in 0..tile.2 {
+        let ancestor = (tile.0 >> (tile.2 - z), tile.1 >> (tile.2 - z), z);
+        coverage.coverage.insert(ancestor, Coverage::Partial);
+      }
+    }
+    // unlike boundary tiles, the unlisted children of partial tiles are not covered at all
+    let partial: Vec<Tile> = coverage.coverage.keys().copied().collect();
+    for tile in partial {
+      for child in tilebelt::get_children(&tile) {
+        coverage.coverage.entry(child).or_insert(Coverage::Outside);
+      }
+    }
+    for tile in tiles {
+      coverage.coverage.insert(*tile, Coverage::Inside);
+    }
+    coverage
+  }
+
+  pub fn get(&self, tile: &Tile) -> Coverage {
+    for z in 0..=tile.2 {
+      let ancestor = (tile.0 >> (tile.2 - z), tile.1 >> (tile.2 - z), z);
+      match self.coverage.get(&ancestor) {
+        Some(Coverage::Partial) => continue,
+        Some(coverage) => return *coverage,
+        // boundary tiles deeper than maxzoom aren't indexed
+        None => return Coverage::Partial,
+      }
+    }
+    Coverage::Partial
+  }
+
+  // The smallest set of tiles that covers the region down to `zoom`: partially covered tiles at
+  // `zoom`, and tiles of lower zooms that are completely inside. Tiles count as covered when
+  // their buffer touches the region, so features crossing the boundary are kept whole.
+  pub fn covering_tiles(&self, zoom: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    let mut stack = vec![(0, 0, 0)];
+    while let Some(tile) = stack.pop() {
+      match self.coverage.get(&tile) {
+        Some(Coverage::Inside) => tiles.push(tile),
+        Some(Coverage::Partial) if tile.2 >= zoom => tiles.push(tile),
+        Some(Coverage::Partial) => stack.extend(tilebelt::get_children(&tile)),
+        _ => {}
+      }
+    }
+    tiles.sort_by_key(|t| (t.2, t.1, t.0));
+    tiles
+  }
+
+  // The region around a tile, in the coordinates of a tile with the given extent.
+  pub fn tile_rings(&self, tile: &Tile, extent: u32) -> Vec<ClipRing> {
+    let world_rings = (0..=tile.2)
+      .rev()
+      .map(|z| (tile.0 >> (tile.2 - z), tile.1 >> (tile.2 - z), z))
+      .find_map(|ancestor| self.rings.get(&ancestor));
+    let scale = (1u64 << tile.2) as f64;
+    world_rings
+      .into_iter()
+      .flatten()
+      .map(|ring| ClipRing {
+        outer: ring.outer,
+        points: ring
+          .points
+          .iter()
+          .map(|p| {
+            (
+              (p.0 * scale - tile.0 as f64) * extent as f64,
+              (p.1 * scale - tile.1 as f64) * extent as f64,
+            )
+          })
+          .collect(),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_bbox() {
+    assert_eq!(parse_bbox("-10.5,35,30,60.25"), (-10.5, 35.0, 30.0, 60.25));
+  }
+
+  #[test]
+  fn test_parse_bounds() {
+    assert_eq!(
+      parse_bounds("-10.5, 35,30,60.25"),
+      Some([-10.5, 35.0, 30.0, 60.25])
+    );
+    assert_eq!(parse_bounds("-10.5,35,30"), None);
+    assert_eq!(parse_bounds("west,35,30,60"), None);
+  }
+
+  #[test]
+  fn test_bbox_coverage() {
+    // roughly Switzerland
+    let region = Region::from_bbox((5.9, 45.8, 10.5, 47.8));
+    let coverage = region.tile_coverage(10);
+
+    assert_eq!(coverage.get(&(0, 0, 0)), Coverage::Partial);
+    assert_eq!(coverage.get(&(33, 22, 6)), Coverage::Partial);
+    assert_eq!(coverage.get(&(0, 0, 6)), Coverage::Outside);
+    // Bern
+    let (x, y) = tilebelt::lng_lat_to_world(7.45, 46.95);
+    assert_eq!(
+      coverage.get(&((x * 1024.0) as u32, (y * 1024.0) as u32, 10)),
+      Coverage::Inside
+    );
+    // everything below an outside tile is outside, deeper than the index too
+    assert_eq!(coverage.get(&(0, 0, 16)), Coverage::Outside);
+
+    assert_eq!(
+      region.tile_coverage(7).covering_tiles(7),
+      vec![(66, 44, 7), (67, 44, 7), (66, 45, 7), (67, 45, 7)]
+    );
+    // completely covered tiles are kept at their lowest zoom
+    let covering = region.tile_coverage(10).covering_tiles(10);
+    assert!(covering.iter().any(|t| t.2 < 10));
+    assert!(covering
+      .iter()
+      .all(|t| t.2 == 10 || coverage.get(t) == Coverage::Inside));
+  }
+
+  #[test]
+  fn test_tile_list_coverage() {
+    let coverage = TileCoverage::from_tiles(&[(8584, 5739, 14), (1, 1, 1)]);
+
+    assert_eq!(coverage.get(&(0, 0, 0)), Coverage::Partial);
+    assert_eq!(coverage.get(&(1, 1, 1)), Coverage::Inside);
+    assert_eq!(coverage.get(&(7, 7, 3)), Coverage::Inside);
+    assert_eq!(coverage.get(&(0, 1, 1)), Coverage::Outside);
+    assert_eq!(coverage.get(&(1073, 717, 11)), Coverage::Partial);
+    assert_eq!(coverage.get(&(1073, 716, 11)), Coverage::Outside);
+    assert_eq!(coverage.get(&(8584, 5739, 14)), Coverage::Inside);
+    assert_eq!(coverage.get(&(17168, 11479, 15)), Coverage::Inside);
+    assert_eq!(coverage.get(&(8585, 5739, 14)), Coverage::Outside);
+    assert_eq!(coverage.get(&(17170, 11478, 15)), Coverage::Outside);
+  }
+
+  #[test]
+  fn test_geojson_coverage() {
+    // a triangle with a hole
+    let geojson = serde_json::json!({
+      "type": "Feature",
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [
+          [[0.0, 0.0], [40.0, 0.0], [0.0, 40.0], [0.0, 0.0]],
+          [[5.0, 5.0], [5.0, 15.0], [15.0, 15.0], [15.0, 5.0], [5.0, 5.0]]
+        ]
+      }
+    });
+    let region = Region::from_geojson(&geojson);
+    assert_eq!(region.lng_lat_bbox(), (0.0, 0.0, 40.0, 40.0));
+    let coverage = region.tile_coverage(8);
+
+    let tile_at = |lng: f64, lat: f64| {
+      let (x, y) = tilebelt::lng_lat_to_world(lng, lat);
+      ((x * 256.0) as u32, (y * 256.0) as u32, 8)
+    };
+    assert_eq!(coverage.get(&tile_at(2.5, 30.0)), Coverage::Inside);
+    assert_eq!(coverage.get(&tile_at(10.0, 10.0)), Coverage::Outside);
+    assert_eq!(coverage.get(&tile_at(30.0, 30.0)), Coverage::Outside);
+    assert_eq!(coverage.get(&tile_at(20.0, 20.0)), Coverage::Partial);
+
+    let rings = coverage.tile_rings(&tile_at(20.0, 20.0), 4096);
+    assert!(!rings.is_empty());
+    assert!(regionclip::point_in_rings((0.0, 4096.0), &rings));
+    assert!(!regionclip::point_in_rings((4096.0, 0.0), &rings));
+  }
+}
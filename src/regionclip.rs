@@ -0,0 +1,740 @@
+// Clipping of tile geometry against an arbitrary region made of rings, as opposed to
+// `lineclip`, which only clips against an axis aligned bounding box.
+//
+// Points and lines are clipped with the even-odd rule over all rings, so holes and
+// multipolygon parts work without having to know which ring is which. Polygon rings
+// are clipped with the Greiner-Hormann algorithm against every outer ring of the region,
+// then the inner rings of the region are subtracted from them.
+
+use crate::geom::{LineString, Point, Polygon, Ring};
+
+pub type FloatPoint = (f64, f64);
+
+// in order of: min x, min y, max x, max y
+pub type FloatBoundingBox = (f64, f64, f64, f64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipRing {
+  pub outer: bool,
+  pub points: Vec<FloatPoint>,
+}
+
+// vertices closer than this to an edge of the other polygon are nudged off it,
+// Greiner-Hormann can't handle intersections that fall exactly on a vertex
+const DEGENERATE_DISTANCE: f64 = 1e-7;
+const PERTURBATION: f64 = 1e-5;
+
+fn to_float(point: &Point) -> FloatPoint {
+  (point.x as f64, point.y as f64)
+}
+
+fn lerp(a: FloatPoint, b: FloatPoint, t: f64) -> FloatPoint {
+  (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+fn cross(o: FloatPoint, a: FloatPoint, b: FloatPoint) -> f64 {
+  (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn edges(ring: &[FloatPoint]) -> impl Iterator<Item = (FloatPoint, FloatPoint)> + '_ {
+  ring
+    .iter()
+    .enumerate()
+    .map(move |(i, a)| (*a, ring[(i + 1) % ring.len()]))
+}
+
+pub fn signed_area(ring: &[FloatPoint]) -> f64 {
+  edges(ring).map(|(a, b)| a.0 * b.1 - b.0 * a.1).sum::<f64>() / 2.0
+}
+
+fn point_in_ring(p: FloatPoint, ring: &[FloatPoint]) -> bool {
+  let mut inside = false;
+  for (a, b) in edges(ring) {
+    if (a.1 > p.1) != (b.1 > p.1) && p.0 < (b.0 - a.0) * (p.1 - a.1) / (b.1 - a.1) + a.0 {
+      inside = !inside;
+    }
+  }
+  inside
+}
+
+pub fn point_in_rings(p: FloatPoint, rings: &[ClipRing]) -> bool {
+  rings
+    .iter()
+    .filter(|ring| point_in_ring(p, &ring.points))
+    .count()
+    % 2
+    == 1
+}
+
+// Returns the positions along a-b and c-d at which the lines through both segments cross.
+fn segment_intersection(
+  a: FloatPoint,
+  b: FloatPoint,
+  c: FloatPoint,
+  d: FloatPoint,
+) -> Option<(f64, f64)> {
+  let r = (b.0 - a.0, b.1 - a.1);
+  let s = (d.0 - c.0, d.1 - c.1);
+  let denominator = r.0 * s.1 - r.1 * s.0;
+  if denominator == 0.0 {
+    return None;
+  }
+  let ac = (c.0 - a.0, c.1 - a.1);
+  let t = (ac.0 * s.1 - ac.1 * s.0) / denominator;
+  let u = (ac.0 * r.1 - ac.1 * r.0) / denominator;
+  Some((t, u))
+}
+
+// Liang-Barsky: whether any part of the segment a-b lies within the bounding box
+pub fn segment_intersects_bbox(a: FloatPoint, b: FloatPoint, bbox: FloatBoundingBox) -> bool {
+  let dx = b.0 - a.0;
+  let dy = b.1 - a.1;
+  let mut t0 = 0.0;
+  let mut t1 = 1.0;
+  for (p, q) in [
+    (-dx, a.0 - bbox.0),
+    (dx, bbox.2 - a.0),
+    (-dy, a.1 - bbox.1),
+    (dy, bbox.3 - a.1),
+  ] {
+    if p == 0.0 {
+      if q < 0.0 {
+        return false;
+      }
+      continue;
+    }
+    let r = q / p;
+    if p < 0.0 {
+      if r > t1 {
+        return false;
+      }
+      if r > t0 {
+        t0 = r;
+      }
+    } else {
+      if r < t0 {
+        return false;
+      }
+      if r < t1 {
+        t1 = r;
+      }
+    }
+  }
+  true
+}
+
+// Sutherland-Hodgman, like `lineclip::polygonclip` but for floating point rings
+pub fn clip_ring_to_bbox(ring: &[FloatPoint], bbox: FloatBoundingBox) -> Vec<FloatPoint> {
+  let mut points = ring.to_vec();
+  let inside_fns: [&dyn Fn(FloatPoint) -> bool; 4] = [
+    &|p| p.0 >= bbox.0,
+    &|p| p.0 <= bbox.2,
+    &|p| p.1 >= bbox.1,
+    &|p| p.1 <= bbox.3,
+  ];
+  let intersect_fns: [&dyn Fn(FloatPoint, FloatPoint) -> FloatPoint; 4] = [
+    &|a, b| lerp(a, b, (bbox.0 - a.0) / (b.0 - a.0)),
+    &|a, b| lerp(a, b, (bbox.2 - a.0) / (b.0 - a.0)),
+    &|a, b| lerp(a, b, (bbox.1 - a.1) / (b.1 - a.1)),
+    &|a, b| lerp(a, b, (bbox.3 - a.1) / (b.1 - a.1)),
+  ];
+
+  for (inside, intersect) in inside_fns.iter().zip(intersect_fns.iter()) {
+    if points.is_empty() {
+      break;
+    }
+    let mut result = Vec::with_capacity(points.len());
+    let mut prev = points[points.len() - 1];
+    let mut prev_inside = inside(prev);
+    for p in points.iter() {
+      let p_inside = inside(*p);
+      if p_inside != prev_inside {
+        result.push(intersect(prev, *p));
+      }
+      if p_inside {
+        result.push(*p);
+      }
+      prev = *p;
+      prev_inside = p_inside;
+    }
+    points = result;
+  }
+  points
+}
+
+// rounds back to tile coordinates, dropping the repeated points this creates
+fn to_points(points: &[FloatPoint]) -> Vec<Point> {
+  let mut out: Vec<Point> = Vec::with_capacity(points.len());
+  for p in points {
+    let point = Point {
+      x: p.0.round() as i32,
+      y: p.1.round() as i32,
+    };
+    if out.last() != Some(&point) {
+      out.push(point);
+    }
+  }
+  out
+}
+
+pub fn clip_points(points: Vec<Point>, rings: &[ClipRing]) -> Vec<Point> {
+  points
+    .into_iter()
+    .filter(|p| point_in_rings(to_float(p), rings))
+    .collect()
+}
+
+pub fn clip_line(line: &LineString, rings: &[ClipRing]) -> Vec<LineString> {
+  let mut result = Vec::<LineString>::new();
+  let mut part = Vec::<FloatPoint>::new();
+
+  let mut flush = |part: &mut Vec<FloatPoint>| {
+    let points = to_points(part);
+    if points.len() >= 2 {
+      result.push(LineString { points });
+    }
+    part.clear();
+  };
+
+  for segment in line.points.windows(2) {
+    let a = to_float(&segment[0]);
+    let b = to_float(&segment[1]);
+
+    // split the segment wherever it crosses the region boundary, every piece is then
+    // either completely inside or completely outside
+    let mut splits = vec![0.0, 1.0];
+    for ring in rings {
+      for (c, d) in edges(&ring.points) {
+        if let Some((t, u)) = segment_intersection(a, b, c, d) {
+          if t > 0.0 && t < 1.0 && (0.0..=1.0).contains(&u) {
+            splits.push(t);
+          }
+        }
+      }
+    }
+    splits.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    for piece in splits.windows(2) {
+      let middle = lerp(a, b, (piece[0] + piece[1]) / 2.0);
+      if point_in_rings(middle, rings) {
+        if part.is_empty() {
+          part.push(lerp(a, b, piece[0]));
+        }
+        part.push(lerp(a, b, piece[1]));
+      } else if !part.is_empty() {
+        flush(&mut part);
+      }
+    }
+  }
+  flush(&mut part);
+
+  result
+}
+
+fn on_segment(p: FloatPoint, a: FloatPoint, b: FloatPoint) -> bool {
+  let length_sq = (b.0 - a.0).powi(2) + (b.1 - a.1).powi(2);
+  if length_sq == 0.0 {
+    return (p.0 - a.0).abs() <= DEGENERATE_DISTANCE && (p.1 - a.1).abs() <= DEGENERATE_DISTANCE;
+  }
+  let dot = (p.0 - a.0) * (b.0 - a.0) + (p.1 - a.1) * (b.1 - a.1);
+  cross(a, b, p).abs() <= DEGENERATE_DISTANCE * length_sq.sqrt()
+    && dot >= -DEGENERATE_DISTANCE
+    && dot <= length_sq + DEGENERATE_DISTANCE
+}
+
+// nudges vertices of `ring` that touch the boundary of `other` so all intersections are proper
+fn perturb(ring: &mut [FloatPoint], other: &[FloatPoint]) {
+  for p in ring.iter_mut() {
+    let mut attempts = 0;
+    while attempts < 8 && edges(other).any(|(a, b)| on_segment(*p, a, b)) {
+      p.0 += PERTURBATION;
+      p.1 += PERTURBATION * 0.5;
+      attempts += 1;
+    }
+  }
+}
+
+struct Node {
+  point: FloatPoint,
+  // id of the intersection this node represents, shared with its neighbor in the other list
+  intersection: Option<usize>,
+  entry: bool,
+  visited: bool,
+}
+
+fn build_nodes(
+  ring: &[FloatPoint],
+  inserts: &mut [Vec<(f64, usize)>],
+  intersections: &[FloatPoint],
+  other: &[FloatPoint],
+) -> (Vec<Node>, Vec<usize>) {
+  let mut nodes = Vec::with_capacity(ring.len() + intersections.len());
+  let mut positions = vec![0; intersections.len()];
+  for (i, point) in ring.iter().enumerate() {
+    nodes.push(Node {
+      point: *point,
+      intersection: None,
+      entry: false,
+      visited: false,
+    });
+    inserts[i].sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    for (_, id) in inserts[i].iter() {
+      positions[*id] = nodes.len();
+      nodes.push(Node {
+        point: intersections[*id],
+        intersection: Some(*id),
+        entry: false,
+        visited: false,
+      });
+    }
+  }
+
+  // intersections alternate between entering and leaving the other polygon
+  let mut entry = !point_in_ring(ring[0], other);
+  for node in nodes.iter_mut().filter(|n| n.intersection.is_some()) {
+    node.entry = entry;
+    entry = !entry;
+  }
+  (nodes, positions)
+}
+
+// How two simple rings overlap, when their boundaries cross it is the traced result rings.
+enum Overlay {
+  Crossing(Vec<Vec<FloatPoint>>),
+  // the subject is inside the clip ring
+  Inside,
+  // the clip ring is inside the subject
+  Contains,
+  Disjoint,
+}
+
+// Greiner-Hormann intersection, or difference of the subject minus the clip ring. When the
+// boundaries cross, neither result has holes, so it is a list of outer rings.
+fn overlay_rings(subject: &[FloatPoint], clip: &[FloatPoint], difference: bool) -> Overlay {
+  let mut subject = subject.to_vec();
+  let mut clip = clip.to_vec();
+  perturb(&mut subject, &clip);
+  perturb(&mut clip, &subject);
+
+  let mut intersections = Vec::<FloatPoint>::new();
+  let mut subject_inserts = vec![Vec::<(f64, usize)>::new(); subject.len()];
+  let mut clip_inserts = vec![Vec::<(f64, usize)>::new(); clip.len()];
+  for (i, (a, b)) in edges(&subject).enumerate() {
+    for (j, (c, d)) in edges(&clip).enumerate() {
+      if let Some((t, u)) = segment_intersection(a, b, c, d) {
+        if t > 0.0 && t < 1.0 && u > 0.0 && u < 1.0 {
+          subject_inserts[i].push((t, intersections.len()));
+          clip_inserts[j].push((u, intersections.len()));
+          intersections.push(lerp(a, b, t));
+        }
+      }
+    }
+  }
+
+  if intersections.is_empty() {
+    if point_in_ring(subject[0], &clip) {
+      return Overlay::Inside;
+    }
+    if point_in_ring(clip[0], &subject) {
+      return Overlay::Contains;
+    }
+    return Overlay::Disjoint;
+  }
+
+  let (subject_nodes, subject_positions) =
+    build_nodes(&subject, &mut subject_inserts, &intersections, &clip);
+  let (clip_nodes, clip_positions) =
+    build_nodes(&clip, &mut clip_inserts, &intersections, &subject);
+  let mut lists = [subject_nodes, clip_nodes];
+  let positions = [subject_positions, clip_positions];
+  let max_length = lists[0].len() + lists[1].len();
+
+  let mut result = Vec::new();
+  while let Some(start) = lists[0]
+    .iter()
+    .position(|n| n.intersection.is_some() && !n.visited)
+  {
+    let mut ring = vec![lists[0][start].point];
+    let mut list = 0;
+    let mut idx = start;
+    while ring.len() <= max_length {
+      let id = lists[list][idx].intersection.unwrap();
+      lists[list][idx].visited = true;
+      lists[1 - list][positions[1 - list][id]].visited = true;
+
+      // a difference follows the subject outside of the clip ring, and the clip ring inside
+      // of the subject
+      let forward = lists[list][idx].entry != (difference && list == 0);
+      let length = lists[list].len();
+      loop {
+        idx = if forward {
+          (idx + 1) % length
+        } else {
+          (idx + length - 1) % length
+        };
+        ring.push(lists[list][idx].point);
+        if lists[list][idx].intersection.is_some() {
+          break;
+        }
+      }
+
+      // continue on the other polygon from the same intersection
+      let id = lists[list][idx].intersection.unwrap();
+      list = 1 - list;
+      idx = positions[list][id];
+      if lists[list][idx].visited {
+        break;
+      }
+    }
+    // the walk ends where it started
+    ring.pop();
+    result.push(ring);
+  }
+  Overlay::Crossing(result)
+}
+
+// Rounds a clipped ring back to tile coordinates with the winding order of `area`, rings that
+// collapse are dropped.
+fn to_ring(mut clipped: Vec<FloatPoint>, area: f64) -> Option<Ring> {
+  if signed_area(&clipped) * area < 0.0 {
+    clipped.reverse();
+  }
+  let mut points = to_points(&clipped);
+  if points.len() > 1 && points.first() == points.last() {
+    points.pop();
+  }
+  let float_points: Vec<FloatPoint> = points.iter().map(to_float).collect();
+  if points.len() >= 3 && signed_area(&float_points) != 0.0 {
+    Some(Ring { points })
+  } else {
+    None
+  }
+}
+
+// Clips a ring to the region. Returns the pieces of the ring inside the region, and the holes of
+// the region that are inside those pieces without touching them.
+fn clip_ring(ring: &Ring, rings: &[ClipRing]) -> (Vec<Ring>, Vec<Ring>) {
+  let mut subject: Vec<FloatPoint> = ring.points.iter().map(to_float).collect();
+  if subject.len() > 1 && subject.first() == subject.last() {
+    subject.pop();
+  }
+  if subject.len() < 3 {
+    return (vec![], vec![]);
+  }
+  let subject_area = signed_area(&subject);
+
+  let mut pieces = Vec::new();
+  for ring in rings.iter().filter(|r| r.outer && r.points.len() >= 3) {
+    match overlay_rings(&subject, &ring.points, false) {
+      Overlay::Crossing(clipped) => pieces.extend(clipped),
+      Overlay::Inside => pieces.push(subject.clone()),
+      Overlay::Contains => pieces.push(ring.points.clone()),
+      Overlay::Disjoint => {}
+    }
+  }
+
+  let mut contained_holes = Vec::new();
+  for hole in rings.iter().filter(|r| !r.outer && r.points.len() >= 3) {
+    let mut remaining = Vec::with_capacity(pieces.len());
+    for piece in pieces {
+      match overlay_rings(&piece, &hole.points, true) {
+        Overlay::Crossing(clipped) => remaining.extend(clipped),
+        Overlay::Inside => {}
+        Overlay::Contains => {
+          contained_holes.push(hole.points.clone());
+          remaining.push(piece);
+        }
+        Overlay::Disjoint => remaining.push(piece),
+      }
+    }
+    pieces = remaining;
+  }
+
+  (
+    // keep the winding order of the input ring, so holes stay holes
+    pieces
+      .into_iter()
+      .filter_map(|piece| to_ring(piece, subject_area))
+      .collect(),
+    contained_holes
+      .into_iter()
+      .filter_map(|hole| to_ring(hole, -subject_area))
+      .collect(),
+  )
+}
+
+// The piece of `exteriors` that contains most vertices of `ring`, with that count.
+fn best_exterior(ring: &Ring, exteriors: &[Vec<FloatPoint>]) -> (usize, usize) {
+  exteriors
+    .iter()
+    .map(|exterior| {
+      ring
+        .points
+        .iter()
+        .filter(|p| point_in_ring(to_float(p), exterior))
+        .count()
+    })
+    .enumerate()
+    .max_by_key(|(_, count)| *count)
+    .unwrap()
+}
+
+// The exterior ring can fall apart into several pieces, each clipped hole goes to the piece that
+// contains most of its vertices. Vertices on the region boundary can land on either side.
+pub fn clip_polygon(polygon: &Polygon, rings: &[ClipRing]) -> Vec<Polygon> {
+  let (exteriors, region_holes) = clip_ring(&polygon.exterior, rings);
+  let mut result: Vec<Polygon> = exteriors
+    .into_iter()
+    .map(|exterior| Polygon {
+      exterior,
+      interiors: vec![],
+    })
+    .collect();
+  if result.is_empty() {
+    return result;
+  }
+  let float_exteriors: Vec<Vec<FloatPoint>> = result
+    .iter()
+    .map(|p| p.exterior.points.iter().map(to_float).collect())
+    .collect();
+
+  // the holes of the region don't touch the pieces, so they are completely inside one of them
+  for hole in region_holes {
+    let (best, _) = best_exterior(&hole, &float_exteriors);
+    result[best].interiors.push(hole);
+  }
+  for interior in polygon.interiors.iter() {
+    // holes of the region inside this hole are already left out along with it
+    for hole in clip_ring(interior, rings).0 {
+      let (best, inside_count) = best_exterior(&hole, &float_exteriors);
+      if inside_count > 0 || result.len() == 1 {
+        result[best].interiors.push(hole);
+      }
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn square(min: f64, max: f64) -> Vec<FloatPoint> {
+    vec![(min, min), (max, min), (max, max), (min, max)]
+  }
+
+  fn points(coords: &[(i32, i32)]) -> Vec<Point> {
+    coords.iter().map(|&(x, y)| Point { x, y }).collect()
+  }
+
+  #[test]
+  fn test_point_in_rings() {
+    let rings = vec![
+      ClipRing {
+        outer: true,
+        points: square(0.0, 10.0),
+      },
+      ClipRing {
+        outer: false,
+        points: square(4.0, 6.0),
+      },
+    ];
+    assert!(point_in_rings((2.0, 2.0), &rings));
+    assert!(!point_in_rings((5.0, 5.0), &rings));
+    assert!(!point_in_rings((12.0, 5.0), &rings));
+  }
+
+  #[test]
+  fn test_clip_line() {
+    // a triangle, so the region isn't just a bounding box
+    let rings = vec![ClipRing {
+      outer: true,
+      points: vec![(0.0, 0.0), (20.0, 0.0), (0.0, 20.0)],
+    }];
+    let line = LineString {
+      points: points(&[(-10, 5), (30, 5)]),
+    };
+    assert_eq!(
+      clip_line(&line, &rings),
+      vec![LineString {
+        points: points(&[(0, 5), (15, 5)])
+      }]
+    );
+
+    // leaves and re-enters through a hole
+    let rings = vec![
+      ClipRing {
+        outer: true,
+        points: square(0.0, 10.0),
+      },
+      ClipRing {
+        outer: false,
+        points: square(4.0, 6.0),
+      },
+    ];
+    let line = LineString {
+      points: points(&[(2, 5), (8, 5), (8, 8)]),
+    };
+    assert_eq!(
+      clip_line(&line, &rings),
+      vec![
+        LineString {
+          points: points(&[(2, 5), (4, 5)])
+        },
+        LineString {
+          points: points(&[(6, 5), (8, 5), (8, 8)])
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn test_clip_ring_to_bbox() {
+    let clipped = clip_ring_to_bbox(&square(-5.0, 5.0), (0.0, 0.0, 10.0, 10.0));
+    assert_eq!(signed_area(&clipped), 25.0);
+    assert!(clip_ring_to_bbox(&square(-5.0, -1.0), (0.0, 0.0, 10.0, 10.0)).is_empty());
+  }
+
+  #[test]
+  fn test_clip_ring() {
+    // a concave "U" shaped region, a bar across it is cut into two pieces
+    let rings = vec![ClipRing {
+      outer: true,
+      points: vec![
+        (0.0, 0.0),
+        (30.0, 0.0),
+        (30.0, 30.0),
+        (20.0, 30.0),
+        (20.0, 10.0),
+        (10.0, 10.0),
+        (10.0, 30.0),
+        (0.0, 30.0),
+      ],
+    }];
+    let bar = Ring {
+      points: points(&[(-5, 20), (35, 20), (35, 25), (-5, 25)]),
+    };
+    let mut clipped = clip_ring(&bar, &rings).0;
+    assert_eq!(clipped.len(), 2);
+    clipped.sort_by_key(|p| p.points.iter().map(|p| p.x).min());
+    for (polygon, min_x) in clipped.iter().zip([0, 20]) {
+      let float_points: Vec<FloatPoint> = polygon.points.iter().map(to_float).collect();
+      assert_eq!(signed_area(&float_points), 50.0);
+      assert_eq!(polygon.points.iter().map(|p| p.x).min(), Some(min_x));
+    }
+
+    // a hole keeps its (reversed) winding order
+    let hole = Ring {
+      points: points(&[(5, 5), (5, 40), (40, 40), (40, 5)]),
+    };
+    let clipped = clip_ring(&hole, &rings).0;
+    assert_eq!(clipped.len(), 1);
+    let float_points: Vec<FloatPoint> = clipped[0].points.iter().map(to_float).collect();
+    assert_eq!(signed_area(&float_points), -(25.0 * 25.0 - 10.0 * 20.0));
+
+    // completely inside, completely outside and touching vertices
+    let inside = Ring {
+      points: points(&[(1, 1), (9, 1), (9, 9), (1, 9)]),
+    };
+    assert_eq!(clip_ring(&inside, &rings).0, vec![inside.clone()]);
+    let outside = Ring {
+      points: points(&[(12, 12), (18, 12), (18, 18), (12, 18)]),
+    };
+    assert!(clip_ring(&outside, &rings).0.is_empty());
+    let touching = Ring {
+      points: points(&[(0, 0), (10, 0), (10, 10), (0, 10)]),
+    };
+    assert_eq!(clip_ring(&touching, &rings).0.len(), 1);
+  }
+
+  #[test]
+  fn test_clip_polygon() {
+    let rings = vec![ClipRing {
+      outer: true,
+      points: vec![
+        (0.0, 0.0),
+        (30.0, 0.0),
+        (30.0, 30.0),
+        (20.0, 30.0),
+        (20.0, 10.0),
+        (10.0, 10.0),
+        (10.0, 30.0),
+        (0.0, 30.0),
+      ],
+    }];
+    // a bar across both arms of the "U", with a hole in each arm and one in between
+    let hole = |min_x: i32| Ring {
+      points: points(&[(min_x, 18), (min_x, 22), (min_x + 6, 22), (min_x + 6, 18)]),
+    };
+    let polygon = Polygon {
+      exterior: Ring {
+        points: points(&[(-5, 15), (35, 15), (35, 28), (-5, 28)]),
+      },
+      interiors: vec![hole(2), hole(12), hole(22)],
+    };
+    let mut clipped = clip_polygon(&polygon, &rings);
+    assert_eq!(clipped.len(), 2);
+    clipped.sort_by_key(|p| p.exterior.points.iter().map(|p| p.x).min());
+    assert_eq!(clipped[0].interiors, vec![hole(2)]);
+    assert_eq!(clipped[1].interiors, vec![hole(22)]);
+  }
+
+  fn area(ring: &Ring) -> f64 {
+    signed_area(
+      &ring
+        .points
+        .iter()
+        .map(to_float)
+        .collect::<Vec<FloatPoint>>(),
+    )
+  }
+
+  #[test]
+  fn test_clip_polygon_to_region_with_hole() {
+    let rings = vec![
+      ClipRing {
+        outer: true,
+        points: square(0.0, 30.0),
+      },
+      ClipRing {
+        outer: false,
+        points: square(10.0, 20.0),
+      },
+    ];
+    let polygon = |min: i32, max: i32| Polygon {
+      exterior: Ring {
+        points: points(&[(min, min), (max, min), (max, max), (min, max)]),
+      },
+      interiors: vec![],
+    };
+
+    // a bar through the hole is cut in two
+    let bar = Polygon {
+      exterior: Ring {
+        points: points(&[(-5, 12), (35, 12), (35, 18), (-5, 18)]),
+      },
+      interiors: vec![],
+    };
+    let clipped = clip_polygon(&bar, &rings);
+    assert_eq!(clipped.len(), 2);
+    for piece in clipped.iter() {
+      assert_eq!(area(&piece.exterior), 60.0);
+      assert!(piece.interiors.is_empty());
+    }
+
+    // the hole becomes an interior ring of a polygon around it
+    let clipped = clip_polygon(&polygon(5, 25), &rings);
+    assert_eq!(clipped.len(), 1);
+    assert_eq!(area(&clipped[0].exterior), 400.0);
+    assert_eq!(clipped[0].interiors.len(), 1);
+    assert_eq!(area(&clipped[0].interiors[0]), -100.0);
+
+    // a polygon overlapping a corner of the hole loses that corner
+    let clipped = clip_polygon(&polygon(5, 15), &rings);
+    assert_eq!(clipped.len(), 1);
+    assert_eq!(area(&clipped[0].exterior), 75.0);
+
+    // nothing is left of a polygon inside the hole
+    assert!(clip_polygon(&polygon(12, 18), &rings).is_empty());
+  }
+}
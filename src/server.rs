@@ -0,0 +1,270 @@
+use mbtiles_tool::reader::read_metadata;
+use mbtiles_tool::tilebelt::{self, Tile};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+  TileJson,
+  Tile(Tile),
+  NotFound,
+}
+
+fn parse_route(url: &str, extension: &str) -> Route {
+  // ignore any query string
+  let path = url.split('?').next().unwrap_or("");
+  if path == "/" || path == "/tiles.json" {
+    return Route::TileJson;
+  }
+
+  let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+  if parts.len() != 3 {
+    return Route::NotFound;
+  }
+  let y_part = match parts[2].strip_suffix(&format!(".{}", extension)) {
+    Some(y_part) => y_part,
+    None => return Route::NotFound,
+  };
+  let (z, x, y) = match (
+    parts[0].parse::<u32>(),
+    parts[1].parse::<u32>(),
+    y_part.parse::<u32>(),
+  ) {
+    (Ok(z), Ok(x), Ok(y)) => (z, x, y),
+    _ => return Route::NotFound,
+  };
+  // tiles outside of the zoom level's grid can never exist
+  if z > 31 || x >= (1 << z) || y >= (1 << z) {
+    return Route::NotFound;
+  }
+  Route::Tile((x, y, z))
+}
+
+fn content_type(format: &str) -> &'static str {
+  match format {
+    "pbf" | "mvt" => "application/x-protobuf",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "webp" => "image/webp",
+    _ => "application/octet-stream",
+  }
+}
+
+fn header(name: &str, value: &str) -> Header {
+  Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn parse_number_list(value: Option<&String>) -> Option<Vec<serde_json::Value>> {
+  value?
+    .split(',')
+    .map(|n| n.trim().parse::<f64>().ok().map(serde_json::Value::from))
+    .collect()
+}
+
+fn build_tilejson(
+  metadata: &HashMap<String, String>,
+  base_url: &str,
+  extension: &str,
+) -> serde_json::Value {
+  let mut tilejson = serde_json::Map::new();
+  tilejson.insert("tilejson".to_string(), "3.0.0".into());
+  tilejson.insert(
+    "tiles".to_string(),
+    serde_json::json!([format!("{}/{{z}}/{{x}}/{{y}}.{}", base_url, extension)]),
+  );
+  tilejson.insert("scheme".to_string(), "xyz".into());
+
+  for key in ["name", "description", "attribution", "version"] {
+    if let Some(value) = metadata.get(key) {
+      tilejson.insert(key.to_string(), value.clone().into());
+    }
+  }
+  for key in ["minzoom", "maxzoom"] {
+    if let Some(value) = metadata.get(key).and_then(|v| v.parse::<u8>().ok()) {
+      tilejson.insert(key.to_string(), value.into());
+    }
+  }
+  for key in ["bounds", "center"] {
+    if let Some(values) = parse_number_list(metadata.get(key)) {
+      tilejson.insert(key.to_string(), values.into());
+    }
+  }
+
+  // vector tilesets keep their layer descriptions in the `json` metadata row
+  if let Some(json) = metadata.get("json") {
+    if let Ok(serde_json::Value::Object(json)) = serde_json::from_str(json) {
+      if let Some(vector_layers) = json.get("vector_layers") {
+        tilejson.insert("vector_layers".to_string(), vector_layers.clone());
+      }
+    }
+  }
+
+  serde_json::Value::Object(tilejson)
+}
+
+fn respond_tilejson(request: Request, metadata: &HashMap<String, String>, extension: &str) {
+  let host = request
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv("Host"))
+    .map(|h| h.value.to_string())
+    .unwrap_or_else(|| "localhost".to_string());
+  let tilejson = build_tilejson(metadata, &format!("http://{}", host), extension);
+  let response = Response::from_data(tilejson.to_string())
+    .with_header(header("Content-Type", "application/json"))
+    .with_header(header("Access-Control-Allow-Origin", "*"));
+  request.respond(response).unwrap_or(());
+}
+
+fn initialize_workers(
+  server: Arc<Server>,
+  input: PathBuf,
+  metadata: Arc<HashMap<String, String>>,
+) -> Vec<thread::JoinHandle<()>> {
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let mut worker_handles = Vec::with_capacity(max_workers);
+
+  for _ in 0..max_workers {
+    let thread_server = server.clone();
+    let thread_input = input.clone();
+    let thread_metadata = metadata.clone();
+    worker_handles.push(thread::spawn(move || {
+      let connection = sqlite::open(thread_input).unwrap();
+      connection.execute("PRAGMA query_only = true;").unwrap();
+      let mut statement = connection
+        .prepare(
+          "
+          SELECT tile_data
+          FROM tiles
+          WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?
+        ",
+        )
+        .unwrap();
+
+      let format = thread_metadata
+        .get("format")
+        .cloned()
+        .unwrap_or_else(|| "pbf".to_string());
+
+      while let Ok(request) = thread_server.recv() {
+        let tile = match parse_route(request.url(), &format) {
+          Route::TileJson => {
+            respond_tilejson(request, &thread_metadata, &format);
+            continue;
+          }
+          Route::NotFound => {
+            request
+              .respond(Response::empty(404).with_header(header("Access-Control-Allow-Origin", "*")))
+              .unwrap_or(());
+            continue;
+          }
+          Route::Tile(tile) => tile,
+        };
+
+        // mbtiles stores rows in TMS order, requests come in as XYZ
+        let tms_tile = tilebelt::flip_x(tile);
+        statement.bind(1, tms_tile.2 as i64).unwrap();
+        statement.bind(2, tms_tile.0 as i64).unwrap();
+        statement.bind(3, tms_tile.1 as i64).unwrap();
+        let tile_data = match statement.next().unwrap() {
+          sqlite::State::Row => Some(statement.read::<Vec<u8>>(0).unwrap()),
+          sqlite::State::Done => None,
+        };
+        statement.reset().unwrap();
+
+        let response = match tile_data {
+          Some(data) => {
+            let gzipped = data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b;
+            let mut response =
+              Response::from_data(data).with_header(header("Content-Type", content_type(&format)));
+            if gzipped {
+              response = response.with_header(header("Content-Encoding", "gzip"));
+            }
+            response
+          }
+          // the tile is a valid coordinate, it just doesn't have any data
+          None => Response::from_data(Vec::new()).with_status_code(204),
+        };
+        request
+          .respond(response.with_header(header("Access-Control-Allow-Origin", "*")))
+          .unwrap_or(());
+      }
+    }));
+  }
+  worker_handles
+}
+
+pub fn serve(input: PathBuf, host: String, port: u16) {
+  let metadata = read_metadata(&input);
+  let server = Arc::new(Server::http((host.as_str(), port)).unwrap());
+
+  println!(
+    "Serving {} at http://{}:{}/ (TileJSON at http://{}:{}/tiles.json)",
+    input.display(),
+    host,
+    port,
+    host,
+    port
+  );
+
+  let worker_handles = initialize_workers(server, input, Arc::new(metadata));
+  for handle in worker_handles {
+    handle.join().unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_route() {
+    assert_eq!(parse_route("/", "pbf"), Route::TileJson);
+    assert_eq!(parse_route("/tiles.json", "pbf"), Route::TileJson);
+    assert_eq!(parse_route("/0/0/0.pbf", "pbf"), Route::Tile((0, 0, 0)));
+    assert_eq!(
+      parse_route("/14/14548/6449.pbf?v=1", "pbf"),
+      Route::Tile((14548, 6449, 14))
+    );
+    assert_eq!(
+      parse_route("/14/14548/6449.png", "png"),
+      Route::Tile((14548, 6449, 14))
+    );
+
+    assert_eq!(parse_route("/14/14548/6449.png", "pbf"), Route::NotFound);
+    assert_eq!(parse_route("/1/2/0.pbf", "pbf"), Route::NotFound);
+    assert_eq!(parse_route("/a/b/c.pbf", "pbf"), Route::NotFound);
+    assert_eq!(parse_route("/favicon.ico", "pbf"), Route::NotFound);
+  }
+
+  #[test]
+  fn test_build_tilejson() {
+    let metadata: HashMap<String, String> = [
+      ("name", "test"),
+      ("minzoom", "0"),
+      ("maxzoom", "14"),
+      ("bounds", "-180,-85.0511,180,85.0511"),
+      ("json", r#"{"vector_layers":[{"id":"water","fields":{}}]}"#),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    let tilejson = build_tilejson(&metadata, "http://localhost:3000", "pbf");
+    assert_eq!(
+      tilejson["tiles"],
+      serde_json::json!(["http://localhost:3000/{z}/{x}/{y}.pbf"])
+    );
+    assert_eq!(tilejson["name"], "test");
+    assert_eq!(tilejson["maxzoom"], 14);
+    assert_eq!(
+      tilejson["bounds"],
+      serde_json::json!([-180.0, -85.0511, 180.0, 85.0511])
+    );
+    assert_eq!(tilejson["vector_layers"][0]["id"], "water");
+    assert!(tilejson.get("center").is_none());
+  }
+}
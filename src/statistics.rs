@@ -1,68 +1,422 @@
 use cli_table::{print_stdout, Table, WithTitle};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use prost::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::thread;
 
-#[derive(Table)]
-struct ZoomLevelStats {
+use crate::pmtiles;
+use crate::reader::{maybe_decompress, Reader, TileData};
+use crate::tilebelt::{flip_x, tile_to_bbox, Tile};
+use crate::vector_tile;
+
+const LARGE_TILE_THRESHOLDS: [u32; 2] = [400_000, 500_000];
+
+// the number of layers listed for each large tile
+const HEAVIEST_LAYER_COUNT: usize = 5;
+
+// Percentiles use the nearest rank, `size_share` is the percentage of the size of all tiles.
+#[derive(Table, Serialize)]
+pub struct ZoomLevelStats {
   #[table(title = "z")]
-  zoom: u8,
+  pub zoom: u8,
   #[table(title = "Tile size (min)")]
-  min_tile_data: u32,
+  pub min_tile_data: u32,
   #[table(title = "Tile size (max)")]
-  max_tile_data: u32,
+  pub max_tile_data: u32,
   #[table(title = "Tile size (average)")]
-  avg_tile_data: f64,
+  pub avg_tile_data: f64,
+  #[table(title = "p50")]
+  pub p50_tile_data: u32,
+  #[table(title = "p90")]
+  pub p90_tile_data: u32,
+  #[table(title = "p99")]
+  pub p99_tile_data: u32,
+  #[table(title = "p99.9")]
+  pub p999_tile_data: u32,
+  #[table(title = "Tile count")]
+  pub tile_count: u64,
+  #[table(title = "Total size")]
+  pub total_tile_data: u64,
+  #[table(title = "Share of size", display_fn = "display_share")]
+  pub size_share: f64,
+}
+
+// The tiles of a zoom level with a size from `min_size` to `max_size`, buckets double in size.
+#[derive(Table, Serialize)]
+pub struct HistogramBucket {
+  #[table(title = "z")]
+  pub zoom: u8,
+  #[table(title = "Tile size (from)")]
+  pub min_size: u32,
+  #[table(title = "Tile size (to)")]
+  pub max_size: u32,
+  #[table(title = "Tile count")]
+  pub tile_count: u64,
+}
+
+// The number of tiles of every size per zoom level. Tiles share few enough distinct sizes to
+// keep them all, so the percentiles are exact and still computed in one pass.
+type SizeDistribution = BTreeMap<u8, BTreeMap<u32, u64>>;
+
+fn display_share(share: &f64) -> String {
+  format!("{:.2}%", share)
+}
+
+#[derive(Table, Serialize)]
+pub struct LargeTileStats {
+  #[table(title = "z")]
+  pub z: u8,
+  #[table(title = "x")]
+  pub x: u64,
+  #[table(title = "y")]
+  pub y: u64,
+  #[table(title = "Tile size")]
+  pub tile_data_length: u32,
+  // the heaviest layers first, only calculated with the layer statistics
+  #[table(skip)]
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub layers: Vec<TileLayerStats>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TileLayerStats {
+  pub layer: String,
+  pub bytes: u64,
+  pub feature_count: u64,
+}
+
+// A layer at a zoom level, summed over all the tiles it is in. Sizes are of the uncompressed
+// layer, since tiles are compressed as a whole.
+#[derive(Table, Serialize)]
+pub struct LayerStats {
+  #[table(title = "z")]
+  pub zoom: u8,
+  #[table(title = "Layer")]
+  pub layer: String,
   #[table(title = "Tile count")]
-  tile_count: u64,
+  pub tile_count: u64,
+  #[table(title = "Size (total)")]
+  pub total_bytes: u64,
+  #[table(title = "Size (max)")]
+  pub max_bytes: u64,
+  #[table(title = "Features")]
+  pub feature_count: u64,
+  #[table(title = "Vertices")]
+  pub vertex_count: u64,
+  #[table(title = "Keys")]
+  pub key_count: u64,
+  #[table(title = "Values")]
+  pub value_count: u64,
+}
+
+// a large tile in the CSV output, which can't nest the tiles under their threshold
+#[derive(Serialize)]
+struct LargeTileRow {
+  threshold: u32,
+  z: u8,
+  x: u64,
+  y: u64,
+  tile_data_length: u32,
 }
 
 #[derive(Table)]
-struct LargeTileStats {
+struct HeavyLayerRow {
   #[table(title = "z")]
   z: u8,
   #[table(title = "x")]
   x: u64,
   #[table(title = "y")]
   y: u64,
+  #[table(title = "Layer")]
+  layer: String,
+  #[table(title = "Size")]
+  bytes: u64,
+  #[table(title = "Features")]
+  feature_count: u64,
+}
+
+// The maximum tile size for the zoom levels from `min_zoom` to `max_zoom`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BudgetRule {
+  pub min_zoom: u8,
+  pub max_zoom: u8,
+  pub max_size: u64,
+}
+
+// A tile larger than the budget of its zoom level, with its lon/lat bounds.
+#[derive(Table, Serialize)]
+pub struct OversizedTile {
+  #[table(title = "z")]
+  pub z: u8,
+  #[table(title = "x")]
+  pub x: u64,
+  #[table(title = "y")]
+  pub y: u64,
   #[table(title = "Tile size")]
-  tile_data_length: u32,
+  pub tile_data_length: u64,
+  #[table(title = "Budget")]
+  pub budget: u64,
+  #[table(title = "West")]
+  pub west: f64,
+  #[table(title = "South")]
+  pub south: f64,
+  #[table(title = "East")]
+  pub east: f64,
+  #[table(title = "North")]
+  pub north: f64,
 }
 
+// Large tiles are listed per size threshold, tiles are XYZ. `oversized_tiles` is only set when
+// a budget was checked.
+#[derive(Serialize)]
 pub struct StatisticsOutput {
-  name: String,
-  zoom_level_stats: Vec<ZoomLevelStats>,
-  large_tile_stats: HashMap<u32, Vec<LargeTileStats>>,
+  pub name: String,
+  pub zoom_level_stats: Vec<ZoomLevelStats>,
+  pub size_histogram: Vec<HistogramBucket>,
+  pub large_tile_stats: BTreeMap<u32, Vec<LargeTileStats>>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub layer_stats: Vec<LayerStats>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub oversized_tiles: Option<Vec<OversizedTile>>,
 }
 
 impl StatisticsOutput {
   pub fn print_cli_table(self) {
     println!("Statistics for {}:", self.name);
     print_stdout(self.zoom_level_stats.with_title()).unwrap();
+    println!("Tile size histogram:");
+    print_stdout(self.size_histogram.with_title()).unwrap();
+
+    if !self.layer_stats.is_empty() {
+      println!("Layers (uncompressed sizes):");
+      print_stdout(self.layer_stats.with_title()).unwrap();
+    }
 
     for (threshold, stats) in self.large_tile_stats {
       println!("Large tiles with size > {} bytes:", threshold);
+      let heavy_layers: Vec<HeavyLayerRow> = stats
+        .iter()
+        .flat_map(|tile| {
+          tile.layers.iter().map(move |layer| HeavyLayerRow {
+            z: tile.z,
+            x: tile.x,
+            y: tile.y,
+            layer: layer.layer.clone(),
+            bytes: layer.bytes,
+            feature_count: layer.feature_count,
+          })
+        })
+        .collect();
       print_stdout(stats.with_title()).unwrap();
+      if !heavy_layers.is_empty() {
+        println!("Heaviest layers of these tiles (uncompressed sizes):");
+        print_stdout(heavy_layers.with_title()).unwrap();
+      }
+    }
+
+    match self.oversized_tiles {
+      Some(tiles) if tiles.is_empty() => println!("All tiles are within the size budget."),
+      Some(tiles) => {
+        println!("Tiles over the size budget:");
+        print_stdout(tiles.with_title()).unwrap();
+      }
+      None => {}
     }
   }
+
+  pub fn print_json(&self) {
+    println!("{}", serde_json::to_string_pretty(self).unwrap());
+  }
+
+  // Tables separated by an empty line: the per-zoom rows, the histogram, the large tiles, then
+  // the layers and the tiles over the budget if they were calculated.
+  pub fn print_csv(&self) {
+    print!("{}", self.to_csv());
+  }
+
+  fn to_csv(&self) -> String {
+    // headers are written up front, so empty tables still have them
+    let mut writer = csv::WriterBuilder::new()
+      .has_headers(false)
+      .from_writer(Vec::new());
+    writer
+      .write_record([
+        "zoom",
+        "min_tile_data",
+        "max_tile_data",
+        "avg_tile_data",
+        "p50_tile_data",
+        "p90_tile_data",
+        "p99_tile_data",
+        "p999_tile_data",
+        "tile_count",
+        "total_tile_data",
+        "size_share",
+      ])
+      .unwrap();
+    for stats in self.zoom_level_stats.iter() {
+      writer.serialize(stats).unwrap();
+    }
+    let mut out = writer.into_inner().unwrap();
+    out.push(b'\n');
+
+    let mut writer = csv::WriterBuilder::new()
+      .has_headers(false)
+      .from_writer(out);
+    writer
+      .write_record(["zoom", "min_size", "max_size", "tile_count"])
+      .unwrap();
+    for bucket in self.size_histogram.iter() {
+      writer.serialize(bucket).unwrap();
+    }
+    let mut out = writer.into_inner().unwrap();
+    out.push(b'\n');
+
+    let mut writer = csv::WriterBuilder::new()
+      .has_headers(false)
+      .from_writer(out);
+    writer
+      .write_record(["threshold", "z", "x", "y", "tile_data_length"])
+      .unwrap();
+    for (threshold, stats) in self.large_tile_stats.iter() {
+      for stats in stats {
+        writer
+          .serialize(LargeTileRow {
+            threshold: *threshold,
+            z: stats.z,
+            x: stats.x,
+            y: stats.y,
+            tile_data_length: stats.tile_data_length,
+          })
+          .unwrap();
+      }
+    }
+    let mut out = writer.into_inner().unwrap();
+
+    if !self.layer_stats.is_empty() {
+      out.push(b'\n');
+      let mut writer = csv::Writer::from_writer(out);
+      for stats in self.layer_stats.iter() {
+        writer.serialize(stats).unwrap();
+      }
+      out = writer.into_inner().unwrap();
+    }
+
+    if let Some(tiles) = &self.oversized_tiles {
+      out.push(b'\n');
+      let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(out);
+      writer
+        .write_record([
+          "z",
+          "x",
+          "y",
+          "tile_data_length",
+          "budget",
+          "west",
+          "south",
+          "east",
+          "north",
+        ])
+        .unwrap();
+      for tile in tiles {
+        writer.serialize(tile).unwrap();
+      }
+      out = writer.into_inner().unwrap();
+    }
+    String::from_utf8(out).unwrap()
+  }
 }
 
-fn calculate_zoom_level_stats(connection: &sqlite::Connection) -> Vec<ZoomLevelStats> {
-  let mut out = Vec::<ZoomLevelStats>::new();
+fn calculate_size_distribution(connection: &sqlite::Connection) -> SizeDistribution {
+  let mut sizes = SizeDistribution::new();
   let mut stmt = connection
-    .prepare("select zoom_level, min(length(tile_data)), max(length(tile_data)), avg(length(tile_data)), count(*) from tiles group by zoom_level order by zoom_level asc;
+    .prepare("select zoom_level, length(tile_data), count(*) from tiles group by zoom_level, length(tile_data);
     ")
     .unwrap();
 
   while let sqlite::State::Row = stmt.next().unwrap() {
-    out.push(ZoomLevelStats {
-      zoom: stmt.read::<i64>(0).unwrap() as u8,
-      min_tile_data: stmt.read::<i64>(1).unwrap() as u32,
-      max_tile_data: stmt.read::<i64>(2).unwrap() as u32,
-      avg_tile_data: stmt.read::<f64>(3).unwrap(),
-      tile_count: stmt.read::<i64>(4).unwrap() as u64,
+    sizes
+      .entry(stmt.read::<i64>(0).unwrap() as u8)
+      .or_default()
+      .insert(
+        stmt.read::<i64>(1).unwrap() as u32,
+        stmt.read::<i64>(2).unwrap() as u64,
+      );
+  }
+  sizes
+}
+
+// The smallest size with at least `per_mille` of the tiles at or below it.
+fn percentile(sizes: &BTreeMap<u32, u64>, tile_count: u64, per_mille: u64) -> u32 {
+  let rank = std::cmp::max((tile_count * per_mille + 999) / 1000, 1);
+  let mut seen = 0;
+  for (size, count) in sizes {
+    seen += count;
+    if seen >= rank {
+      return *size;
+    }
+  }
+  0
+}
+
+// Sizes from 2^(n-1) to 2^n - 1 go in bucket n, empty tiles in bucket 0.
+fn histogram_bucket(size: u32) -> u32 {
+  u32::BITS - size.leading_zeros()
+}
+
+fn calculate_zoom_level_stats(
+  sizes: &SizeDistribution,
+) -> (Vec<ZoomLevelStats>, Vec<HistogramBucket>) {
+  let total_size: u64 = sizes
+    .values()
+    .flatten()
+    .map(|(size, count)| *size as u64 * count)
+    .sum();
+
+  let mut zoom_level_stats = Vec::with_capacity(sizes.len());
+  let mut histogram = Vec::new();
+  for (zoom, sizes) in sizes {
+    let tile_count: u64 = sizes.values().sum();
+    let total_tile_data: u64 = sizes.iter().map(|(size, count)| *size as u64 * count).sum();
+    zoom_level_stats.push(ZoomLevelStats {
+      zoom: *zoom,
+      min_tile_data: *sizes.keys().next().unwrap(),
+      max_tile_data: *sizes.keys().next_back().unwrap(),
+      avg_tile_data: total_tile_data as f64 / tile_count as f64,
+      p50_tile_data: percentile(sizes, tile_count, 500),
+      p90_tile_data: percentile(sizes, tile_count, 900),
+      p99_tile_data: percentile(sizes, tile_count, 990),
+      p999_tile_data: percentile(sizes, tile_count, 999),
+      tile_count,
+      total_tile_data,
+      size_share: if total_size > 0 {
+        total_tile_data as f64 * 100.0 / total_size as f64
+      } else {
+        0.0
+      },
     });
+
+    let mut buckets = BTreeMap::<u32, u64>::new();
+    for (size, count) in sizes {
+      *buckets.entry(histogram_bucket(*size)).or_insert(0) += count;
+    }
+    histogram.extend(buckets.into_iter().map(|(bucket, tile_count)| {
+      let (min_size, max_size) = match bucket {
+        0 => (0, 0),
+        _ => (1 << (bucket - 1), ((1u64 << bucket) - 1) as u32),
+      };
+      HistogramBucket {
+        zoom: *zoom,
+        min_size,
+        max_size,
+        tile_count,
+      }
+    }));
   }
-  out
+  (zoom_level_stats, histogram)
 }
 
 fn calculate_large_tile_stats(
@@ -71,7 +425,7 @@ fn calculate_large_tile_stats(
 ) -> Vec<LargeTileStats> {
   let mut out = Vec::<LargeTileStats>::new();
   let mut stmt = connection
-    .prepare("select zoom_level as z, ((1 << zoom_level) - 1 - tile_row) as x, tile_column as y, length(tile_data) from tiles where length(tile_data) > ? order by zoom_level asc;
+    .prepare("select zoom_level as z, tile_column as x, ((1 << zoom_level) - 1 - tile_row) as y, length(tile_data) from tiles where length(tile_data) > ? order by zoom_level asc;
     ")
     .unwrap();
   stmt.bind(1, threshold as i64).unwrap();
@@ -81,19 +435,220 @@ fn calculate_large_tile_stats(
       x: stmt.read::<i64>(1).unwrap() as u64,
       y: stmt.read::<i64>(2).unwrap() as u64,
       tile_data_length: stmt.read::<i64>(3).unwrap() as u32,
+      layers: vec![],
     });
   }
   out
 }
 
+// The number of vertices in a geometry, MoveTo and LineTo commands have one per parameter pair.
+fn count_vertices(geometry: &[u32]) -> u64 {
+  let mut count = 0;
+  let mut i = 0;
+  while i < geometry.len() {
+    let id = geometry[i] & 0x7;
+    let command_count = (geometry[i] >> 3) as usize;
+    i += 1;
+    if id == 1 || id == 2 {
+      count += command_count as u64;
+      i += command_count * 2;
+    }
+  }
+  count
+}
+
+// The statistics of the tiles read by one worker, merged together at the end.
+#[derive(Default)]
+struct StatisticsAccumulator {
+  sizes: SizeDistribution,
+  large_tile_stats: BTreeMap<u32, Vec<LargeTileStats>>,
+  layer_stats: BTreeMap<(u8, String), LayerStats>,
+  undecodable_tile_count: u64,
+}
+
+impl StatisticsAccumulator {
+  fn add_tile_size(&mut self, zoom: u8, size: u32, count: u64) {
+    *self.sizes.entry(zoom).or_default().entry(size).or_insert(0) += count;
+  }
+
+  fn add_layer(&mut self, zoom: u8, layer: &LayerStats) {
+    let stats = self
+      .layer_stats
+      .entry((zoom, layer.layer.clone()))
+      .or_insert_with(|| LayerStats {
+        zoom,
+        layer: layer.layer.clone(),
+        tile_count: 0,
+        total_bytes: 0,
+        max_bytes: 0,
+        feature_count: 0,
+        vertex_count: 0,
+        key_count: 0,
+        value_count: 0,
+      });
+    stats.tile_count += layer.tile_count;
+    stats.total_bytes += layer.total_bytes;
+    stats.max_bytes = std::cmp::max(stats.max_bytes, layer.max_bytes);
+    stats.feature_count += layer.feature_count;
+    stats.vertex_count += layer.vertex_count;
+    stats.key_count += layer.key_count;
+    stats.value_count += layer.value_count;
+  }
+
+  fn add_tile(&mut self, tile_data: &TileData, thresholds: &[u32], decode_layers: bool) {
+    let (x, y, z) = flip_x(tile_data.tile);
+    let length = tile_data.data.len() as u32;
+    self.add_tile_size(z as u8, length, 1);
+
+    let mut tile_layers = Vec::new();
+    if decode_layers {
+      match vector_tile::Tile::decode(&*maybe_decompress(tile_data.data.to_vec())) {
+        Ok(tile) => {
+          for layer in tile.layers.iter() {
+            let bytes = layer.encoded_len() as u64;
+            self.add_layer(
+              z as u8,
+              &LayerStats {
+                zoom: z as u8,
+                layer: layer.name.clone(),
+                tile_count: 1,
+                total_bytes: bytes,
+                max_bytes: bytes,
+                feature_count: layer.features.len() as u64,
+                vertex_count: layer
+                  .features
+                  .iter()
+                  .map(|f| count_vertices(&f.geometry))
+                  .sum(),
+                key_count: layer.keys.len() as u64,
+                value_count: layer.values.len() as u64,
+              },
+            );
+            tile_layers.push(TileLayerStats {
+              layer: layer.name.clone(),
+              bytes,
+              feature_count: layer.features.len() as u64,
+            });
+          }
+        }
+        Err(_) => self.undecodable_tile_count += 1,
+      }
+      tile_layers.sort_by_key(|l| std::cmp::Reverse(l.bytes));
+      tile_layers.truncate(HEAVIEST_LAYER_COUNT);
+    }
+
+    for threshold in thresholds {
+      if length > *threshold {
+        self
+          .large_tile_stats
+          .entry(*threshold)
+          .or_default()
+          .push(LargeTileStats {
+            z: z as u8,
+            x: x as u64,
+            y: y as u64,
+            tile_data_length: length,
+            layers: tile_layers.clone(),
+          });
+      }
+    }
+  }
+
+  fn merge(&mut self, other: StatisticsAccumulator) {
+    for (zoom, sizes) in other.sizes {
+      for (size, count) in sizes {
+        self.add_tile_size(zoom, size, count);
+      }
+    }
+    for (threshold, stats) in other.large_tile_stats {
+      self
+        .large_tile_stats
+        .entry(threshold)
+        .or_default()
+        .extend(stats);
+    }
+    for ((zoom, _), stats) in other.layer_stats {
+      self.add_layer(zoom, &stats);
+    }
+    self.undecodable_tile_count += other.undecodable_tile_count;
+  }
+
+  fn finish(mut self, name: String, thresholds: &[u32]) -> StatisticsOutput {
+    for threshold in thresholds {
+      self.large_tile_stats.entry(*threshold).or_default();
+    }
+    for stats in self.large_tile_stats.values_mut() {
+      stats.sort_by_key(|s| (s.z, s.x, s.y));
+    }
+    let (zoom_level_stats, size_histogram) = calculate_zoom_level_stats(&self.sizes);
+    StatisticsOutput {
+      name,
+      zoom_level_stats,
+      size_histogram,
+      large_tile_stats: self.large_tile_stats,
+      layer_stats: self.layer_stats.into_values().collect(),
+      oversized_tiles: None,
+    }
+  }
+}
+
+// Reads every tile, for PMTiles archives which can't be queried with SQL or to decode the tiles.
+fn calculate_statistics_from_reader(
+  input: PathBuf,
+  thresholds: &[u32],
+  decode_layers: bool,
+) -> StatisticsOutput {
+  let name = input.to_str().unwrap().to_string();
+  let mut reader = Reader::new(input);
+  let (process_queue_tx, process_queue_rx) =
+    crossbeam_channel::bounded::<TileData>(reader.queue_capacity());
+
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let mut worker_handles = Vec::with_capacity(max_workers);
+  for _ in 0..max_workers {
+    let thread_process_queue_rx = process_queue_rx.clone();
+    let thresholds = thresholds.to_vec();
+    worker_handles.push(thread::spawn(move || {
+      let mut accumulator = StatisticsAccumulator::default();
+      while let Ok(tile_data) = thread_process_queue_rx.recv() {
+        accumulator.add_tile(&tile_data, &thresholds, decode_layers);
+      }
+      accumulator
+    }));
+  }
+
+  for tile_data in reader.iter() {
+    process_queue_tx.send(tile_data).unwrap();
+  }
+  drop(process_queue_tx);
+
+  let mut accumulator = StatisticsAccumulator::default();
+  for handle in worker_handles {
+    accumulator.merge(handle.join().unwrap());
+  }
+  if accumulator.undecodable_tile_count > 0 {
+    eprintln!(
+      "{} tiles could not be decoded and are missing from the layer statistics",
+      accumulator.undecodable_tile_count
+    );
+  }
+  accumulator.finish(name, thresholds)
+}
+
 pub fn calculate_statistics(input: PathBuf) -> StatisticsOutput {
+  let thresholds = LARGE_TILE_THRESHOLDS;
+  if pmtiles::is_pmtiles(&input) {
+    return calculate_statistics_from_reader(input, &thresholds, false);
+  }
+  let name = input.to_str().unwrap().to_string();
+
   let connection = sqlite::open(&input).unwrap();
   connection.execute("PRAGMA query_only = true;").unwrap();
 
-  let zoom_level_stats = calculate_zoom_level_stats(&connection);
+  let (zoom_level_stats, size_histogram) =
+    calculate_zoom_level_stats(&calculate_size_distribution(&connection));
 
-  let thresholds = vec![400_000, 500_000];
-  let large_tile_stats: HashMap<u32, Vec<LargeTileStats>> = thresholds
+  let large_tile_stats: BTreeMap<u32, Vec<LargeTileStats>> = thresholds
     .iter()
     .map(|threshold| {
       (
@@ -104,8 +659,429 @@ pub fn calculate_statistics(input: PathBuf) -> StatisticsOutput {
     .collect();
 
   StatisticsOutput {
-    name: input.to_str().unwrap().to_string(),
+    name,
     zoom_level_stats,
+    size_histogram,
     large_tile_stats,
+    layer_stats: vec![],
+    oversized_tiles: None,
+  }
+}
+
+// Like `calculate_statistics`, and decodes every vector tile to break the sizes down per layer.
+pub fn calculate_layer_statistics(input: PathBuf) -> StatisticsOutput {
+  calculate_statistics_from_reader(input, &LARGE_TILE_THRESHOLDS, true)
+}
+
+// Parses sizes like `500KB`, `2GB` or `1.5GiB` into bytes. KB/MB/GB are powers of 1000, like
+// the large tile thresholds, KiB/MiB/GiB are powers of 1024.
+pub fn parse_size(value: &str) -> u64 {
+  let value = value.trim();
+  let unit_start = value
+    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+    .unwrap_or(value.len());
+  let (number, unit) = value.split_at(unit_start);
+  let number = number
+    .parse::<f64>()
+    .unwrap_or_else(|_| panic!("Invalid size: {}", value));
+  let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "K" | "KB" => 1_000,
+    "M" | "MB" => 1_000_000,
+    "G" | "GB" => 1_000_000_000,
+    "T" | "TB" => 1_000_000_000_000,
+    "KIB" => 1 << 10,
+    "MIB" => 1 << 20,
+    "GIB" => 1 << 30,
+    "TIB" => 1 << 40,
+    _ => panic!("Invalid size unit: {}", unit),
+  };
+  (number * multiplier as f64) as u64
+}
+
+// Parses per-zoom budgets like `z0-6=200KB,z7-14=500KB` or `z14=1MB`.
+pub fn parse_budget(value: &str) -> Vec<BudgetRule> {
+  value
+    .split(',')
+    .map(|rule| {
+      let (zooms, size) = rule
+        .trim()
+        .split_once('=')
+        .unwrap_or_else(|| panic!("Invalid budget: {}", rule));
+      let zooms = zooms
+        .trim()
+        .strip_prefix('z')
+        .unwrap_or_else(|| panic!("Invalid budget zoom levels: {}", zooms));
+      let parse_zoom = |z: &str| {
+        z.trim()
+          .parse::<u8>()
+          .unwrap_or_else(|_| panic!("Invalid budget zoom level: {}", z))
+      };
+      let (min_zoom, max_zoom) = match zooms.split_once('-') {
+        Some((min, max)) => (parse_zoom(min), parse_zoom(max)),
+        None => (parse_zoom(zooms), parse_zoom(zooms)),
+      };
+      if min_zoom > max_zoom {
+        panic!("Invalid budget zoom levels: z{}", zooms);
+      }
+      BudgetRule {
+        min_zoom,
+        max_zoom,
+        max_size: parse_size(size),
+      }
+    })
+    .collect()
+}
+
+// The budget of the first rule that covers the zoom level.
+pub fn budget_for_zoom(rules: &[BudgetRule], zoom: u8) -> Option<u64> {
+  rules
+    .iter()
+    .find(|rule| rule.min_zoom <= zoom && zoom <= rule.max_zoom)
+    .map(|rule| rule.max_size)
+}
+
+fn oversized_tile(rules: &[BudgetRule], tile: Tile, length: u64) -> Option<OversizedTile> {
+  let budget = budget_for_zoom(rules, tile.2 as u8)?;
+  if length <= budget {
+    return None;
+  }
+  let (west, south, east, north) = tile_to_bbox(&tile);
+  Some(OversizedTile {
+    z: tile.2 as u8,
+    x: tile.0 as u64,
+    y: tile.1 as u64,
+    tile_data_length: length,
+    budget,
+    west,
+    south,
+    east,
+    north,
+  })
+}
+
+// Lists the tiles larger than the budget of their zoom level, tiles are XYZ.
+pub fn check_budget(input: &Path, rules: &[BudgetRule]) -> Vec<OversizedTile> {
+  let mut tiles = Vec::new();
+  let min_budget = match rules.iter().map(|rule| rule.max_size).min() {
+    Some(min_budget) => min_budget,
+    None => return tiles,
+  };
+
+  if pmtiles::is_pmtiles(input) {
+    // the directory has the length of every tile, no need to read the tile data
+    let (_, entries) = pmtiles::read_entries(input);
+    for entry in entries.iter().filter(|e| e.length as u64 > min_budget) {
+      for i in 0..entry.run_length as u64 {
+        let (z, x, y) = pmtiles::tile_id_to_zxy(entry.tile_id + i);
+        tiles.extend(oversized_tile(rules, (x, y, z as u32), entry.length as u64));
+      }
+    }
+  } else {
+    let connection = sqlite::open(input).unwrap();
+    connection.execute("PRAGMA query_only = true;").unwrap();
+
+    let mut stmt = connection
+      .prepare("select zoom_level, tile_column, ((1 << zoom_level) - 1 - tile_row) as y, length(tile_data) from tiles where length(tile_data) > ?;
+      ")
+      .unwrap();
+    stmt.bind(1, min_budget as i64).unwrap();
+    while let sqlite::State::Row = stmt.next().unwrap() {
+      let tile = (
+        stmt.read::<i64>(1).unwrap() as u32,
+        stmt.read::<i64>(2).unwrap() as u32,
+        stmt.read::<i64>(0).unwrap() as u32,
+      );
+      tiles.extend(oversized_tile(
+        rules,
+        tile,
+        stmt.read::<i64>(3).unwrap() as u64,
+      ));
+    }
+  }
+
+  tiles.sort_by_key(|t| (t.z, t.x, t.y));
+  tiles
+}
+
+fn add_subtree_size(sizes: &mut HashMap<Tile, u64>, tile: Tile, zoom: u32, length: u64) {
+  let tile = if tile.2 > zoom {
+    let shift = tile.2 - zoom;
+    (tile.0 >> shift, tile.1 >> shift, zoom)
+  } else {
+    tile
+  };
+  *sizes.entry(tile).or_insert(0) += length;
+}
+
+// The total tile_data size of the subtree below every tile down to `zoom`, including the tile
+// itself. Tiles are XYZ.
+pub fn calculate_subtree_sizes(input: &Path, zoom: u32) -> HashMap<Tile, u64> {
+  let mut sizes = HashMap::<Tile, u64>::new();
+
+  if pmtiles::is_pmtiles(input) {
+    // the directory has the length of every tile, no need to read the tile data
+    let (_, entries) = pmtiles::read_entries(input);
+    for entry in entries {
+      for i in 0..entry.run_length as u64 {
+        let (z, x, y) = pmtiles::tile_id_to_zxy(entry.tile_id + i);
+        add_subtree_size(&mut sizes, (x, y, z as u32), zoom, entry.length as u64);
+      }
+    }
+  } else {
+    let connection = sqlite::open(input).unwrap();
+    connection.execute("PRAGMA query_only = true;").unwrap();
+
+    let mut stmt = connection
+      .prepare("select zoom_level, tile_column, ((1 << zoom_level) - 1 - tile_row) as y, length(tile_data) from tiles where zoom_level < ?;
+      ")
+      .unwrap();
+    stmt.bind(1, zoom as i64).unwrap();
+    while let sqlite::State::Row = stmt.next().unwrap() {
+      let tile = (
+        stmt.read::<i64>(1).unwrap() as u32,
+        stmt.read::<i64>(2).unwrap() as u32,
+        stmt.read::<i64>(0).unwrap() as u32,
+      );
+      add_subtree_size(&mut sizes, tile, zoom, stmt.read::<i64>(3).unwrap() as u64);
+    }
+
+    // deeper tiles are summed up per ancestor at `zoom` by sqlite
+    let mut stmt = connection
+      .prepare("select zoom_level, tile_column >> (zoom_level - ?1), ((1 << zoom_level) - 1 - tile_row) >> (zoom_level - ?1), sum(length(tile_data)) from tiles where zoom_level >= ?1 group by 1, 2, 3;
+      ")
+      .unwrap();
+    stmt.bind(1, zoom as i64).unwrap();
+    while let sqlite::State::Row = stmt.next().unwrap() {
+      let tile = (
+        stmt.read::<i64>(1).unwrap() as u32,
+        stmt.read::<i64>(2).unwrap() as u32,
+        zoom,
+      );
+      add_subtree_size(&mut sizes, tile, zoom, stmt.read::<i64>(3).unwrap() as u64);
+    }
+  }
+
+  for z in (1..=zoom).rev() {
+    let level: Vec<(Tile, u64)> = sizes
+      .iter()
+      .filter(|(tile, _)| tile.2 == z)
+      .map(|(tile, size)| (*tile, *size))
+      .collect();
+    for (tile, size) in level {
+      *sizes.entry((tile.0 >> 1, tile.1 >> 1, z - 1)).or_insert(0) += size;
+    }
+  }
+  sizes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_size() {
+    assert_eq!(parse_size("500"), 500);
+    assert_eq!(parse_size("400KB"), 400_000);
+    assert_eq!(parse_size("2GB"), 2_000_000_000);
+    assert_eq!(parse_size("1.5 mb"), 1_500_000);
+    assert_eq!(parse_size("1GiB"), 1 << 30);
+  }
+
+  #[test]
+  fn test_parse_budget() {
+    let rules = parse_budget("z0-6=200KB, z7-14=500KB,z15=1MB");
+    assert_eq!(
+      rules,
+      vec![
+        BudgetRule {
+          min_zoom: 0,
+          max_zoom: 6,
+          max_size: 200_000,
+        },
+        BudgetRule {
+          min_zoom: 7,
+          max_zoom: 14,
+          max_size: 500_000,
+        },
+        BudgetRule {
+          min_zoom: 15,
+          max_zoom: 15,
+          max_size: 1_000_000,
+        },
+      ]
+    );
+    assert_eq!(budget_for_zoom(&rules, 6), Some(200_000));
+    assert_eq!(budget_for_zoom(&rules, 7), Some(500_000));
+    assert_eq!(budget_for_zoom(&rules, 16), None);
+
+    let tile = oversized_tile(&rules, (1, 0, 1), 300_000).unwrap();
+    assert_eq!(
+      (tile.budget, tile.west, tile.south, tile.east),
+      (200_000, 0.0, 0.0, 180.0)
+    );
+    assert!(oversized_tile(&rules, (1, 0, 7), 300_000).is_none());
+  }
+
+  #[test]
+  fn test_count_vertices() {
+    // a polygon: MoveTo(1), LineTo(3), ClosePath, then a second MoveTo(1), LineTo(2)
+    let geometry = [9, 0, 0, 26, 2, 0, 0, 2, 1, 0, 15, 9, 4, 4, 18, 2, 0, 0, 2];
+    assert_eq!(count_vertices(&geometry), 7);
+  }
+
+  #[test]
+  fn test_layer_statistics() {
+    let tile = vector_tile::Tile {
+      layers: vec![
+        vector_tile::tile::Layer {
+          version: 2,
+          name: "small".to_string(),
+          features: vec![vector_tile::tile::Feature {
+            geometry: vec![9, 2, 2],
+            ..Default::default()
+          }],
+          ..Default::default()
+        },
+        vector_tile::tile::Layer {
+          version: 2,
+          name: "large".to_string(),
+          features: vec![
+            vector_tile::tile::Feature {
+              geometry: vec![9, 0, 0, 18, 2, 2, 2, 2],
+              ..Default::default()
+            };
+            10
+          ],
+          keys: vec!["name".to_string()],
+          ..Default::default()
+        },
+      ],
+    };
+    let data = std::sync::Arc::new(tile.encode_to_vec());
+    let mut accumulator = StatisticsAccumulator::default();
+    for tile in [(0, 0, 1), (1, 0, 1)] {
+      accumulator.add_tile(
+        &TileData {
+          tile,
+          data: data.clone(),
+          chunk: None,
+        },
+        &[10],
+        true,
+      );
+    }
+    let output = accumulator.finish("test".to_string(), &[10]);
+
+    assert_eq!(output.zoom_level_stats[0].tile_count, 2);
+    assert_eq!(output.layer_stats.len(), 2);
+    let large = &output.layer_stats[0];
+    assert_eq!(large.layer, "large");
+    assert_eq!(large.tile_count, 2);
+    assert_eq!(large.feature_count, 20);
+    assert_eq!(large.vertex_count, 60);
+    assert_eq!(large.key_count, 2);
+    assert_eq!(large.total_bytes, 2 * tile.layers[1].encoded_len() as u64);
+
+    let large_tiles = &output.large_tile_stats[&10];
+    assert_eq!(large_tiles.len(), 2);
+    // the large tiles are XYZ
+    assert_eq!((large_tiles[0].x, large_tiles[0].y), (0, 1));
+    let layers: Vec<&str> = large_tiles[0]
+      .layers
+      .iter()
+      .map(|l| l.layer.as_str())
+      .collect();
+    assert_eq!(layers, vec!["large", "small"]);
+  }
+
+  #[test]
+  fn test_zoom_level_stats() {
+    // 1000 tiles at z1: 900 of 100 bytes, 90 of 1000 bytes, 9 of 10000 bytes and one of 100000
+    let sizes: SizeDistribution = [
+      (0, [(0, 1)].into_iter().collect()),
+      (
+        1,
+        [(100, 900), (1000, 90), (10000, 9), (100000, 1)]
+          .into_iter()
+          .collect(),
+      ),
+    ]
+    .into_iter()
+    .collect();
+    let (stats, histogram) = calculate_zoom_level_stats(&sizes);
+
+    assert_eq!(stats.len(), 2);
+    let z1 = &stats[1];
+    assert_eq!((z1.min_tile_data, z1.max_tile_data), (100, 100000));
+    assert_eq!(z1.tile_count, 1000);
+    assert_eq!(z1.total_tile_data, 90000 + 90000 + 90000 + 100000);
+    assert_eq!(z1.avg_tile_data, 370.0);
+    assert_eq!(
+      (
+        z1.p50_tile_data,
+        z1.p90_tile_data,
+        z1.p99_tile_data,
+        z1.p999_tile_data
+      ),
+      (100, 100, 1000, 10000)
+    );
+    assert_eq!(z1.size_share, 100.0);
+    assert_eq!(stats[0].size_share, 0.0);
+
+    let buckets: Vec<(u8, u32, u32, u64)> = histogram
+      .iter()
+      .map(|b| (b.zoom, b.min_size, b.max_size, b.tile_count))
+      .collect();
+    assert_eq!(
+      buckets,
+      vec![
+        (0, 0, 0, 1),
+        (1, 64, 127, 900),
+        (1, 512, 1023, 90),
+        (1, 8192, 16383, 9),
+        (1, 65536, 131071, 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_to_csv() {
+    let sizes: SizeDistribution = [(0, [(100, 1), (401, 1)].into_iter().collect())]
+      .into_iter()
+      .collect();
+    let (zoom_level_stats, size_histogram) = calculate_zoom_level_stats(&sizes);
+    let output = StatisticsOutput {
+      name: "test.mbtiles".to_string(),
+      zoom_level_stats,
+      size_histogram,
+      large_tile_stats: [(
+        400,
+        vec![LargeTileStats {
+          z: 0,
+          x: 0,
+          y: 0,
+          tile_data_length: 401,
+          layers: vec![],
+        }],
+      )]
+      .into_iter()
+      .collect(),
+      layer_stats: vec![],
+      oversized_tiles: None,
+    };
+    assert_eq!(
+      output.to_csv(),
+      "zoom,min_tile_data,max_tile_data,avg_tile_data,p50_tile_data,p90_tile_data,\
+       p99_tile_data,p999_tile_data,tile_count,total_tile_data,size_share\n\
+       0,100,401,250.5,100,401,401,401,2,501,100.0\n\
+       \n\
+       zoom,min_size,max_size,tile_count\n\
+       0,64,127,1\n\
+       0,256,511,1\n\
+       \n\
+       threshold,z,x,y,tile_data_length\n\
+       400,0,0,0,401\n"
+    );
   }
 }
@@ -1,11 +1,16 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 use std::time;
 
-use crate::reader::{Reader, EXTENT_CHUNK_TILE_COUNT};
-use crate::tilebelt::{tile_is_ancestor, Tile, TileData};
+use crate::region::Region;
+use crate::writer;
+use mbtiles_tool::checkpoint::Checkpoint;
+use mbtiles_tool::reader::{read_metadata, Reader, TileData, EXTENT_CHUNK_TILE_COUNT};
+use mbtiles_tool::tilebelt::{flip_x, get_children, Tile};
+use mbtiles_tool::{interrupt, pmtiles, statistics};
 
 struct MetadataRow {
   name: String,
@@ -15,16 +20,251 @@ struct MetadataRow {
 #[derive(Serialize, Deserialize)]
 struct SubdivideOutput {
   name: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
   tiles: Vec<Tile>,
+  // a lon/lat bounding box, in order of: west, south, east, north
+  #[serde(skip_serializing_if = "Option::is_none")]
+  bbox: Option<[f64; 4]>,
+  // a GeoJSON file with (Multi)Polygons, relative to the config file
+  #[serde(skip_serializing_if = "Option::is_none")]
+  geojson: Option<PathBuf>,
+  // the zoom level at which the tiles covering `bbox` or `geojson` are computed
+  #[serde(skip_serializing_if = "Option::is_none")]
+  zoom: Option<u32>,
+  // tiles below the zoom of a root tile are only included when this is set
+  #[serde(skip_serializing_if = "Option::is_none")]
+  minzoom: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   maxzoom: Option<u32>,
 }
 
+impl SubdivideOutput {
+  // The root tiles of this output, each with the lowest zoom level to include for it.
+  fn root_tiles(&self, config_dir: &Path) -> Vec<(Tile, u32)> {
+    let mut roots: Vec<(Tile, u32)> = self
+      .tiles
+      .iter()
+      .map(|tile| (*tile, self.minzoom.unwrap_or(tile.2)))
+      .collect();
+
+    let region = match (&self.bbox, &self.geojson) {
+      (Some(bbox), _) => Some(Region::from_bbox((bbox[0], bbox[1], bbox[2], bbox[3]))),
+      (None, Some(geojson)) => Some(Region::from_geojson_file(&config_dir.join(geojson))),
+      (None, None) => None,
+    };
+    if let Some(region) = region {
+      let zoom = self.zoom.or(self.minzoom).unwrap_or_else(|| {
+        panic!(
+          "Output {} needs a `zoom` to compute the tiles covering its region",
+          self.name
+        )
+      });
+      let covering_tiles = region.tile_coverage(zoom as u8).covering_tiles(zoom);
+      println!(
+        "Output {} is covered by {} tiles at z{}",
+        self.name,
+        covering_tiles.len(),
+        zoom
+      );
+      // covering tiles below `zoom` are completely inside the region, they stand in for
+      // all of their descendants at `zoom`
+      let minzoom = self.minzoom.unwrap_or(zoom);
+      roots.extend(covering_tiles.into_iter().map(|tile| (tile, minzoom)));
+    }
+    roots
+  }
+}
+
 #[derive(Serialize, Deserialize)]
 struct SubdivideConfig {
   outputs: Vec<SubdivideOutput>,
 }
 
-pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
+// Finds the outputs a tile belongs to by looking up its ancestors, instead of checking the tile
+// against every root tile of every output.
+struct OutputIndex {
+  // root tiles, with the zoom range and output of every entry; matches the root and its descendants
+  roots: HashMap<Tile, Vec<(u32, u32, usize)>>,
+  // ancestors of root tiles down to the output's minzoom; matches only the tile itself
+  ancestors: HashMap<Tile, Vec<usize>>,
+}
+
+impl OutputIndex {
+  fn new() -> OutputIndex {
+    OutputIndex {
+      roots: HashMap::new(),
+      ancestors: HashMap::new(),
+    }
+  }
+
+  fn insert(&mut self, root: Tile, minzoom: u32, maxzoom: u32, output_idx: usize) {
+    self
+      .roots
+      .entry(root)
+      .or_default()
+      .push((minzoom, maxzoom, output_idx));
+    for z in minzoom..std::cmp::min(root.2, maxzoom + 1) {
+      let shift = root.2 - z;
+      let outputs = self
+        .ancestors
+        .entry((root.0 >> shift, root.1 >> shift, z))
+        .or_default();
+      if !outputs.contains(&output_idx) {
+        outputs.push(output_idx);
+      }
+    }
+  }
+
+  // Every output appears at most once, even with overlapping root tiles.
+  fn lookup(&self, tile: &Tile) -> Vec<usize> {
+    let mut output_idxs = self.ancestors.get(tile).cloned().unwrap_or_default();
+    for z in 0..=tile.2 {
+      let shift = tile.2 - z;
+      let ancestor = (tile.0 >> shift, tile.1 >> shift, z);
+      for (minzoom, maxzoom, i) in self.roots.get(&ancestor).into_iter().flatten() {
+        if *minzoom <= tile.2 && tile.2 <= *maxzoom && !output_idxs.contains(i) {
+          output_idxs.push(*i);
+        }
+      }
+    }
+    output_idxs
+  }
+}
+
+// Subtrees are never split below this zoom. It bounds the number of subtree sizes kept in memory,
+// a single subtree at this zoom is small enough for any practical size limit.
+const MAX_SPLIT_ZOOM: u32 = 10;
+
+pub enum AutoTarget {
+  // this many outputs of roughly equal size
+  Count(usize),
+  // as few outputs as possible, each at most this many bytes
+  MaxSize(u64),
+}
+
+// Position of a subtree along the Hilbert curve at `zoom`, so that neighbouring subtrees
+// end up in the same output.
+fn curve_position(tile: &Tile, zoom: u32) -> u64 {
+  let shift = zoom - tile.2;
+  pmtiles::zxy_to_tile_id(zoom as u8, tile.0 << shift, tile.1 << shift)
+}
+
+// Splits the quadtree until every subtree is at most `leaf_size` bytes, or at `split_zoom`.
+fn split_quadtree(sizes: &HashMap<Tile, u64>, leaf_size: u64, split_zoom: u32) -> Vec<Tile> {
+  let mut leaves = Vec::new();
+  let mut stack = vec![(0, 0, 0)];
+  while let Some(tile) = stack.pop() {
+    let size = sizes.get(&tile).copied().unwrap_or(0);
+    if size == 0 {
+      continue;
+    }
+    if size <= leaf_size || tile.2 >= split_zoom {
+      leaves.push(tile);
+      continue;
+    }
+    stack.extend(get_children(&tile));
+  }
+  leaves.sort_by_key(|tile| curve_position(tile, split_zoom));
+  leaves
+}
+
+// Groups consecutive leaves into outputs.
+fn group_leaves(
+  leaves: &[Tile],
+  sizes: &HashMap<Tile, u64>,
+  target: &AutoTarget,
+) -> Vec<Vec<Tile>> {
+  let total: u64 = leaves.iter().map(|leaf| sizes[leaf]).sum();
+  let mut groups: Vec<Vec<Tile>> = vec![Vec::new()];
+  let mut group_size = 0;
+  let mut accumulated = 0;
+  for leaf in leaves {
+    let size = sizes[leaf];
+    let start_new_group = match target {
+      // cut where the middle of a leaf passes the next multiple of total / count
+      AutoTarget::Count(count) => {
+        groups.len() < *count
+          && (accumulated + size / 2) as f64 > total as f64 * groups.len() as f64 / *count as f64
+      }
+      AutoTarget::MaxSize(max_size) => group_size + size > *max_size,
+    };
+    if start_new_group && !groups.last().unwrap().is_empty() {
+      groups.push(Vec::new());
+      group_size = 0;
+    }
+    groups.last_mut().unwrap().push(*leaf);
+    group_size += size;
+    accumulated += size;
+  }
+  groups
+}
+
+// Computes a size-balanced subdivision of `input` and writes it as a config next to the outputs.
+// Returns the path of the config.
+pub fn write_auto_config(input: &Path, output: &Path, target: AutoTarget) -> PathBuf {
+  let maxzoom = read_metadata(input)
+    .get("maxzoom")
+    .and_then(|z| z.parse::<u32>().ok())
+    .unwrap_or(MAX_SPLIT_ZOOM);
+  let split_zoom = std::cmp::min(maxzoom, MAX_SPLIT_ZOOM);
+
+  println!("Scanning tile sizes down to z{}...", split_zoom);
+  let sizes = statistics::calculate_subtree_sizes(input, split_zoom);
+  let total = sizes.get(&(0, 0, 0)).copied().unwrap_or(0);
+
+  // leaves a quarter of the target size leave enough room to balance the outputs
+  let leaf_size = match target {
+    AutoTarget::Count(count) => total / (count as u64 * 4),
+    AutoTarget::MaxSize(max_size) => max_size / 4,
+  };
+  let leaves = split_quadtree(&sizes, std::cmp::max(leaf_size, 1), split_zoom);
+  let groups = group_leaves(&leaves, &sizes, &target);
+
+  let prefix = input.file_stem().unwrap().to_str().unwrap();
+  let mut outputs = Vec::with_capacity(groups.len());
+  for (i, tiles) in groups.into_iter().enumerate() {
+    let name = format!("{}_{:03}", prefix, i);
+    let size: u64 = tiles.iter().map(|tile| sizes[tile]).sum();
+    println!("{}: {} bytes in {} root tiles", name, size, tiles.len());
+    if let AutoTarget::MaxSize(max_size) = target {
+      if size > max_size {
+        println!(
+          "Warning: {} is larger than the maximum size, its tiles can't be split below z{}",
+          name, split_zoom
+        );
+      }
+    }
+    outputs.push(SubdivideOutput {
+      name,
+      tiles,
+      bbox: None,
+      geojson: None,
+      zoom: None,
+      // every output also gets the low zoom tiles above it, so it can be used on its own
+      minzoom: Some(0),
+      maxzoom: None,
+    });
+  }
+
+  let config_path = output.join("subdivide_config.json");
+  let config = SubdivideConfig { outputs };
+  std::fs::write(&config_path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+  println!("Wrote the subdivision config to {}", config_path.display());
+  config_path
+}
+
+// The checkpoint of the mbtiles outputs, next to them.
+pub fn checkpoint_path(output: &Path) -> PathBuf {
+  output.join("subdivide.checkpoint")
+}
+
+pub fn subdivide(
+  config_path: PathBuf,
+  input: PathBuf,
+  output: PathBuf,
+  format: String,
+  resume: bool,
+) {
   println!(
     "Reading config from {}, input from {} and output to {}",
     config_path.display(),
@@ -35,17 +275,34 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
   let config: SubdivideConfig =
     serde_json::from_reader(std::fs::File::open(&config_path).unwrap()).unwrap();
 
-  let mut reader = Reader::new(input);
+  // PMTiles archives are only written at the end, there is nothing to resume
+  let checkpoint = if format == "pmtiles" {
+    if resume {
+      panic!("Only mbtiles outputs can be resumed");
+    }
+    None
+  } else {
+    Some(Checkpoint::new(checkpoint_path(&output), resume))
+  };
+  let mut reader = match &checkpoint {
+    Some(checkpoint) => Reader::with_checkpoint(input, checkpoint.clone()),
+    None => Reader::new(input),
+  };
   let metadata_rows = reader.read_metadata();
   let metadata_rows_ref = Arc::new(metadata_rows);
 
   let mut output_queue_txs: Vec<crossbeam_channel::Sender<TileData>> = Vec::new();
   let mut output_queue_rxs: Vec<crossbeam_channel::Receiver<TileData>> = Vec::new();
-  let mut tile_to_output_idx_map: Vec<(Tile, u32, usize)> = Vec::new();
+  let mut output_index = OutputIndex::new();
+  let config_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
   let mut output_threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
 
   for (i, output_config) in config.outputs.iter().enumerate() {
-    let (output_queue_tx, output_queue_rx) = crossbeam_channel::bounded::<TileData>(100_000);
+    // the outputs share the memory budget of a single queue
+    let (output_queue_tx, output_queue_rx) = crossbeam_channel::bounded::<TileData>(std::cmp::max(
+      reader.queue_capacity() / config.outputs.len(),
+      64,
+    ));
     let output_thread_queue_rx = output_queue_rx.clone();
 
     let config_maxzoom = output_config.maxzoom.unwrap_or(999);
@@ -53,29 +310,39 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
     output_queue_txs.push(output_queue_tx);
     output_queue_rxs.push(output_queue_rx);
 
-    for tile in &output_config.tiles {
-      tile_to_output_idx_map.push((*tile, config_maxzoom, i));
+    for (tile, minzoom) in output_config.root_tiles(config_dir) {
+      output_index.insert(tile, minzoom, config_maxzoom, i);
     }
 
     let output_thread_metadata_rows = Arc::clone(&metadata_rows_ref);
     let output_config_name = output_config.name.clone();
-    let output_thread_path = output.join(format!("{}.mbtiles", output_config_name));
+    let output_thread_path = output.join(format!("{}.{}", output_config_name, format));
     println!(
       "Spawning thread for output {} to {}",
       output_config_name,
       output_thread_path.display()
     );
+    if format == "pmtiles" {
+      // the zoom range of a PMTiles archive comes from the tiles it contains
+      let output_thread_handle = writer::initialize_writer(
+        output_thread_path,
+        output_thread_queue_rx,
+        (*output_thread_metadata_rows).clone(),
+        false,
+        false,
+      );
+      output_threads.push(output_thread_handle);
+      continue;
+    }
     let output_thread_handle = thread::spawn(move || {
       let mut last_ts = time::Instant::now();
       let mut tile_count = 0;
 
-      let connection = sqlite::open(output_thread_path).unwrap();
+      let connection = sqlite::open(&output_thread_path).unwrap();
+      writer::set_journal_mode(&connection, &output_thread_path, resume);
       connection
         .execute(
           "
-        PRAGMA synchronous = OFF;
-        PRAGMA journal_mode = MEMORY;
-
         CREATE TABLE IF NOT EXISTS metadata (
           name text,
           value text
@@ -99,42 +366,44 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
       let mut insert_stmt = connection
         .prepare(
           "
-        INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+        INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
         VALUES (?, ?, ?, ?)
       ",
         )
         .unwrap();
 
-      let mut max_zoom = 0;
-      let mut min_zoom = 999;
+      let mut uncommitted_chunks = Vec::new();
       while let Ok(work) = output_thread_queue_rx.recv() {
         {
           tile_count += 1;
 
-          max_zoom = std::cmp::max(max_zoom, work.tile.2);
-          min_zoom = std::cmp::min(min_zoom, work.tile.2);
+          let tile = flip_x(work.tile);
 
-          insert_stmt.bind(1, work.tile.2 as i64).unwrap();
-          insert_stmt.bind(2, work.tile.0 as i64).unwrap();
-          insert_stmt.bind(3, work.tile.1 as i64).unwrap();
+          insert_stmt.bind(1, tile.2 as i64).unwrap();
+          insert_stmt.bind(2, tile.0 as i64).unwrap();
+          insert_stmt.bind(3, tile.1 as i64).unwrap();
           insert_stmt.bind(4, &**work.data).unwrap();
 
           insert_stmt.next().unwrap();
           insert_stmt.reset().unwrap();
+          uncommitted_chunks.extend(work.chunk);
 
           if tile_count % EXTENT_CHUNK_TILE_COUNT == 0 {
             connection
               .execute("END TRANSACTION; BEGIN TRANSACTION;")
               .unwrap();
+            uncommitted_chunks.clear();
 
             let ts = time::Instant::now();
             let elapsed = ts.duration_since(last_ts);
             println!(
-              "[{}] {} tiles in {}ms ({:.4}ms/tile)",
+              "[{}] {} tiles in {}ms ({:.4}ms/tile, {}/{} tiles queued)",
               output_config_name,
               tile_count,
               elapsed.as_millis(),
               elapsed.as_millis() as f64 / (EXTENT_CHUNK_TILE_COUNT as f64),
+              output_thread_queue_rx.len(),
+              output_thread_queue_rx.capacity().unwrap(),
             );
             last_ts = ts;
           }
@@ -142,6 +411,15 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
       }
 
       connection.execute("END TRANSACTION;").unwrap();
+      uncommitted_chunks.clear();
+
+      // a resumed output also has the tiles of the earlier run
+      let mut zoom_stmt = connection
+        .prepare("SELECT MIN(zoom_level), MAX(zoom_level) FROM tiles;")
+        .unwrap();
+      zoom_stmt.next().unwrap();
+      let min_zoom = zoom_stmt.read::<Option<i64>>(0).unwrap().unwrap_or(999);
+      let max_zoom = zoom_stmt.read::<Option<i64>>(1).unwrap().unwrap_or(0);
 
       let mut insert_metadata_stmt = connection
         .prepare(
@@ -173,6 +451,7 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
         insert_metadata_stmt.next().unwrap();
         insert_metadata_stmt.reset().unwrap();
       }
+      interrupt::mark_partial_output(&connection);
 
       println!(
         "Output thread {} finished, {} tiles",
@@ -193,21 +472,16 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
 
     let this_tile = (tile_column, flipped_row, zoom_level);
 
-    let tile_to_output_idx_map = tile_to_output_idx_map.iter();
-    for (tile, maxzoom, i) in tile_to_output_idx_map {
-      if zoom_level > *maxzoom {
-        continue;
-      }
-
-      if tile_is_ancestor(&this_tile, tile) {
-        output_queue_txs[*i]
-          .send(TileData {
-            tile: (tile_column, tile_row, zoom_level),
-            data: input_tile.data.clone(),
-          })
-          .unwrap();
-        // don't break here so we can support overlapping outputs
-      }
+    // don't stop at the first output so we can support overlapping outputs
+    let output_idxs = output_index.lookup(&this_tile);
+    for i in output_idxs {
+      output_queue_txs[i]
+        .send(TileData {
+          tile: this_tile,
+          data: input_tile.data.clone(),
+          chunk: input_tile.chunk.clone(),
+        })
+        .unwrap();
     }
   }
 
@@ -216,6 +490,161 @@ pub fn subdivide(config_path: PathBuf, input: PathBuf, output: PathBuf) {
   for output_thread in output_threads {
     output_thread.join().unwrap();
   }
+  if let Some(checkpoint) = checkpoint {
+    checkpoint.finish();
+  }
 
   println!("Done subdivision.");
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // the linear scan the output index replaced, as the reference for its tests
+  fn tile_is_ancestor(tile: &Tile, ancestor: &Tile) -> bool {
+    if tile.2 < ancestor.2 {
+      return false;
+    }
+    let z_diff = tile.2 - ancestor.2;
+    let tile_at_anc_z = (
+      tile.0.checked_shr(z_diff).unwrap_or(0),
+      tile.1.checked_shr(z_diff).unwrap_or(0),
+    );
+
+    tile_at_anc_z.0 == ancestor.0 && tile_at_anc_z.1 == ancestor.1
+  }
+
+  #[test]
+  fn test_tile_is_ancestor() {
+    assert!(tile_is_ancestor(&(0, 0, 0), &(0, 0, 0)));
+    assert!(tile_is_ancestor(&(1, 1, 1), &(0, 0, 0)));
+    assert!(tile_is_ancestor(&(3, 3, 2), &(0, 0, 0)));
+    assert!(tile_is_ancestor(&(3, 3, 3), &(0, 0, 0)));
+
+    assert!(tile_is_ancestor(&(9, 7, 4), &(4, 3, 3)));
+    assert!(!tile_is_ancestor(&(0, 7, 4), &(4, 3, 3)));
+  }
+
+  #[test]
+  fn test_root_tiles() {
+    let config: SubdivideConfig = serde_json::from_str(
+      r#"{
+        "outputs": [
+          { "name": "tiles", "tiles": [[0, 0, 1], [1, 0, 1]], "maxzoom": 6 },
+          { "name": "low", "tiles": [[3, 2, 3]], "minzoom": 1 },
+          { "name": "bbox", "bbox": [5.9, 45.8, 10.5, 47.8], "zoom": 7, "minzoom": 4 }
+        ]
+      }"#,
+    )
+    .unwrap();
+    let config_dir = Path::new(".");
+
+    assert_eq!(
+      config.outputs[0].root_tiles(config_dir),
+      vec![((0, 0, 1), 1), ((1, 0, 1), 1)]
+    );
+    assert_eq!(
+      config.outputs[1].root_tiles(config_dir),
+      vec![((3, 2, 3), 1)]
+    );
+    assert_eq!(
+      config.outputs[2].root_tiles(config_dir),
+      vec![
+        ((66, 44, 7), 4),
+        ((67, 44, 7), 4),
+        ((66, 45, 7), 4),
+        ((67, 45, 7), 4)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_auto_subdivision() {
+    let mut sizes = HashMap::new();
+    // one heavy quadrant at z1, its children at z2 carry all of its weight
+    for (tile, size) in [
+      ((0, 0, 1), 100),
+      ((1, 0, 1), 100),
+      ((0, 1, 1), 100),
+      ((1, 1, 1), 400),
+      ((2, 2, 2), 100),
+      ((3, 2, 2), 100),
+      ((2, 3, 2), 100),
+      ((3, 3, 2), 100),
+    ] {
+      sizes.insert(tile, size);
+    }
+    sizes.insert((0, 0, 0), 700);
+
+    let leaves = split_quadtree(&sizes, 100, 2);
+    assert_eq!(leaves.len(), 7);
+    assert!(!leaves.contains(&(1, 1, 1)));
+
+    let groups = group_leaves(&leaves, &sizes, &AutoTarget::MaxSize(200));
+    assert_eq!(groups.len(), 4);
+    assert!(groups
+      .iter()
+      .all(|g| g.iter().map(|t| sizes[t]).sum::<u64>() <= 200));
+
+    let groups = group_leaves(&leaves, &sizes, &AutoTarget::Count(2));
+    assert_eq!(groups.len(), 2);
+    let group_sizes: Vec<u64> = groups
+      .iter()
+      .map(|g| g.iter().map(|t| sizes[t]).sum())
+      .collect();
+    assert_eq!(group_sizes.iter().sum::<u64>(), 700);
+    assert!(group_sizes.iter().all(|s| *s >= 300));
+  }
+
+  // Checks the index against the linear scan it replaced, with thousands of regions: every z6 tile
+  // is an output, plus outputs that overlap them at other zooms.
+  #[test]
+  fn test_output_index_with_thousands_of_regions() {
+    let mut roots: Vec<(Tile, u32, u32, usize)> = Vec::new();
+    for x in 0..64 {
+      for y in 0..64 {
+        roots.push(((x, y, 6), 6, 999, roots.len()));
+      }
+    }
+    let overview = roots.len();
+    roots.push(((0, 0, 0), 0, 5, overview));
+    for x in 0..16 {
+      roots.push(((x, 3, 4), 2, 8, overview + 1 + x as usize));
+      // the same output again, overlapping its other root
+      roots.push(((x * 4, 13, 6), 2, 8, overview + 1 + x as usize));
+    }
+
+    let mut index = OutputIndex::new();
+    for (tile, minzoom, maxzoom, i) in roots.iter() {
+      index.insert(*tile, *minzoom, *maxzoom, *i);
+    }
+
+    let mut tiles = Vec::new();
+    for z in 0..=9u32 {
+      for x in 0..(1 << z) {
+        for y in 0..(1 << z) {
+          tiles.push((x, y, z));
+        }
+      }
+    }
+    let lookups: Vec<Vec<usize>> = tiles.iter().map(|tile| index.lookup(tile)).collect();
+
+    // the linear scan is too slow for every tile, compare a sample
+    for (tile, outputs) in tiles.iter().zip(lookups.iter()).step_by(97) {
+      let mut expected: Vec<usize> = Vec::new();
+      for (root, minzoom, maxzoom, i) in roots.iter() {
+        if tile.2 < *minzoom || tile.2 > *maxzoom || expected.contains(i) {
+          continue;
+        }
+        if tile_is_ancestor(tile, root) || tile_is_ancestor(root, tile) {
+          expected.push(*i);
+        }
+      }
+      let mut outputs = outputs.clone();
+      outputs.sort_unstable();
+      expected.sort_unstable();
+      assert_eq!(outputs, expected, "outputs of {:?}", tile);
+    }
+  }
+}
@@ -1,27 +1,6 @@
-use std::sync::Arc;
-
 // in order of: x, y, z
 pub type Tile = (u32, u32, u32);
 
-#[derive(Clone)]
-pub struct TileData {
-  pub tile: Tile,
-  pub data: Arc<Vec<u8>>,
-}
-
-pub fn tile_is_ancestor(tile: &Tile, ancestor: &Tile) -> bool {
-  if tile.2 < ancestor.2 {
-    return false;
-  }
-  let z_diff = tile.2 - ancestor.2;
-  let tile_at_anc_z = (
-    tile.0.checked_shr(z_diff).unwrap_or(0),
-    tile.1.checked_shr(z_diff).unwrap_or(0),
-  );
-
-  tile_at_anc_z.0 == ancestor.0 && tile_at_anc_z.1 == ancestor.1
-}
-
 pub fn get_children(tile: &Tile) -> Vec<Tile> {
   vec![
     (tile.0 * 2, tile.1 * 2, tile.2 + 1),
@@ -48,7 +27,7 @@ pub const TILE_RELATIVE_POSITION_TRUTH_TABLE: [(u32, u32); 4] = [(0, 0), (1, 0),
 
 pub fn get_position_in_parent(tile: &Tile) -> ((u32, u32), Tile) {
   let parent = (tile.0 >> 1, tile.1 >> 1, tile.2 - 1);
-  let children = vec![
+  let children = [
     (parent.0 * 2, parent.1 * 2, parent.2 + 1),     // 0 = 0, 0
     (parent.0 * 2 + 1, parent.1 * 2, parent.2 + 1), // 1 = 1, 0
     (parent.0 * 2 + 1, parent.1 * 2 + 1, parent.2 + 1), // 2 = 1, 1
@@ -96,21 +75,40 @@ pub fn flip_x(tile: Tile) -> Tile {
   (tile.0, flipped_row, tile.2)
 }
 
+// web mercator stops at this latitude, beyond it y would go to infinity
+pub const MAX_LATITUDE: f64 = 85.0511287798066;
+
+// Projects lon/lat to web mercator "world" coordinates, where the whole
+// world spans 0..1 on both axes and y points south, like tile rows in XYZ.
+pub fn lng_lat_to_world(lng: f64, lat: f64) -> (f64, f64) {
+  let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+  let x = (lng + 180.0) / 360.0;
+  let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / std::f64::consts::PI) / 2.0;
+  (x, y)
+}
+
+// The inverse of `lng_lat_to_world`.
+pub fn world_to_lng_lat(x: f64, y: f64) -> (f64, f64) {
+  let lng = x * 360.0 - 180.0;
+  let lat = (std::f64::consts::PI * (1.0 - 2.0 * y))
+    .sinh()
+    .atan()
+    .to_degrees();
+  (lng, lat)
+}
+
+// The lon/lat bounds of a XYZ tile, as (west, south, east, north).
+pub fn tile_to_bbox(tile: &Tile) -> (f64, f64, f64, f64) {
+  let scale = (1u64 << tile.2) as f64;
+  let (west, north) = world_to_lng_lat(tile.0 as f64 / scale, tile.1 as f64 / scale);
+  let (east, south) = world_to_lng_lat((tile.0 + 1) as f64 / scale, (tile.1 + 1) as f64 / scale);
+  (west, south, east, north)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
 
-  #[test]
-  fn test_tile_is_ancestor() {
-    assert!(tile_is_ancestor(&(0, 0, 0), &(0, 0, 0)));
-    assert!(tile_is_ancestor(&(1, 1, 1), &(0, 0, 0)));
-    assert!(tile_is_ancestor(&(3, 3, 2), &(0, 0, 0)));
-    assert!(tile_is_ancestor(&(3, 3, 3), &(0, 0, 0)));
-
-    assert!(tile_is_ancestor(&(9, 7, 4), &(4, 3, 3)));
-    assert!(!tile_is_ancestor(&(0, 7, 4), &(4, 3, 3)));
-  }
-
   #[test]
   fn test_get_children() {
     assert_eq!(
@@ -216,4 +214,23 @@ mod tests {
       ((14337, 6528, 14), 1, (1, 1))
     )
   }
+
+  #[test]
+  fn test_lng_lat_to_world() {
+    assert_eq!(lng_lat_to_world(0.0, 0.0), (0.5, 0.5));
+    let (x, y) = lng_lat_to_world(8.62, 47.345);
+    assert_eq!(((x * 16384.0) as u32, (y * 16384.0) as u32), (8584, 5739));
+  }
+
+  #[test]
+  fn test_tile_to_bbox() {
+    let (west, south, east, north) = tile_to_bbox(&(0, 0, 0));
+    assert_eq!((west, east), (-180.0, 180.0));
+    assert!((north - MAX_LATITUDE).abs() < 1e-9);
+    assert!((south + MAX_LATITUDE).abs() < 1e-9);
+
+    let (west, south, east, north) = tile_to_bbox(&(1, 0, 1));
+    assert_eq!((west, south, east), (0.0, 0.0, 180.0));
+    assert!((north - MAX_LATITUDE).abs() < 1e-9);
+  }
 }
@@ -0,0 +1,722 @@
+use crate::vector_tile_ops;
+use mbtiles_tool::vector_tile;
+use mbtiles_tool::{interrupt, pmtiles, reader};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// the report lists at most this many errors and warnings each, all of them are counted though
+const MAX_REPORTED_ISSUES: usize = 1000;
+
+const MAX_ZOOM: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+  Error,
+  Warning,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Issue {
+  #[serde(skip)]
+  severity: Severity,
+  // z/x/y in XYZ order, or the raw TMS row when the coordinates don't fit their zoom
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tile: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  layer: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  feature: Option<usize>,
+  message: String,
+}
+
+impl Issue {
+  fn error(message: String) -> Issue {
+    Issue {
+      severity: Severity::Error,
+      tile: None,
+      layer: None,
+      feature: None,
+      message,
+    }
+  }
+
+  fn warning(message: String) -> Issue {
+    Issue {
+      severity: Severity::Warning,
+      ..Issue::error(message)
+    }
+  }
+
+  fn in_layer(self, layer: &str, feature: Option<usize>) -> Issue {
+    Issue {
+      layer: Some(layer.to_string()),
+      feature,
+      ..self
+    }
+  }
+}
+
+#[derive(Serialize, Default)]
+struct Report {
+  input: String,
+  valid: bool,
+  tiles: u64,
+  error_count: usize,
+  warning_count: usize,
+  errors: Vec<Issue>,
+  warnings: Vec<Issue>,
+}
+
+impl Report {
+  fn add(&mut self, issue: Issue) {
+    let (count, issues) = match issue.severity {
+      Severity::Error => (&mut self.error_count, &mut self.errors),
+      Severity::Warning => (&mut self.warning_count, &mut self.warnings),
+    };
+    *count += 1;
+    if issues.len() < MAX_REPORTED_ISSUES {
+      issues.push(issue);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TileFormat {
+  Vector,
+  Png,
+  Jpg,
+  Webp,
+  Other,
+}
+
+fn parse_numbers(value: &str) -> Option<Vec<f64>> {
+  value
+    .split(',')
+    .map(|v| v.trim().parse::<f64>().ok())
+    .collect()
+}
+
+fn parse_zoom(
+  metadata: &HashMap<String, String>,
+  name: &str,
+  issues: &mut Vec<Issue>,
+) -> Option<i64> {
+  let value = match metadata.get(name) {
+    Some(value) => value,
+    None => {
+      issues.push(Issue::warning(format!("metadata has no {}", name)));
+      return None;
+    }
+  };
+  match value.trim().parse::<i64>() {
+    Ok(zoom) if (0..=MAX_ZOOM).contains(&zoom) => Some(zoom),
+    _ => {
+      issues.push(Issue::error(format!(
+        "metadata {} is not a zoom level: {}",
+        name, value
+      )));
+      None
+    }
+  }
+}
+
+// MBTiles 1.3 requires `name` and `format`, and `json` with a `vector_layers` list for
+// vector tiles. `bounds`, `center`, `minzoom` and `maxzoom` are optional, but have to be valid.
+fn check_metadata(metadata: &HashMap<String, String>) -> (Vec<Issue>, Option<(i64, i64)>) {
+  let mut issues = Vec::new();
+
+  if !metadata.contains_key("name") {
+    issues.push(Issue::error("metadata has no name".to_string()));
+  }
+  if metadata.contains_key(interrupt::PARTIAL_METADATA_KEY) {
+    issues.push(Issue::error(
+      "archive is partial, it was written by an interrupted run".to_string(),
+    ));
+  }
+  match metadata.get("format").map(|f| f.as_str()) {
+    None => issues.push(Issue::error("metadata has no format".to_string())),
+    Some("pbf") | Some("png") | Some("jpg") | Some("webp") => {}
+    Some(format) => issues.push(Issue::warning(format!(
+      "format {} is not one of pbf, png, jpg or webp",
+      format
+    ))),
+  }
+
+  if metadata.get("format").map(|f| f.as_str()) == Some("pbf") {
+    match metadata
+      .get("json")
+      .map(|json| serde_json::from_str::<serde_json::Value>(json))
+    {
+      None => issues.push(Issue::error(
+        "metadata has no json, which is required for pbf tiles".to_string(),
+      )),
+      Some(Err(err)) => issues.push(Issue::error(format!("metadata json is invalid: {}", err))),
+      Some(Ok(json)) => match json.get("vector_layers").and_then(|l| l.as_array()) {
+        None => issues.push(Issue::error(
+          "metadata json has no vector_layers list".to_string(),
+        )),
+        Some(layers) => {
+          for (i, layer) in layers.iter().enumerate() {
+            if !layer.get("id").map_or(false, |id| id.is_string()) {
+              issues.push(Issue::error(format!("vector_layers[{}] has no id", i)));
+            }
+            if !layer.get("fields").map_or(false, |f| f.is_object()) {
+              issues.push(Issue::error(format!("vector_layers[{}] has no fields", i)));
+            }
+          }
+        }
+      },
+    }
+  }
+
+  let minzoom = parse_zoom(metadata, "minzoom", &mut issues);
+  let maxzoom = parse_zoom(metadata, "maxzoom", &mut issues);
+  if let (Some(minzoom), Some(maxzoom)) = (minzoom, maxzoom) {
+    if minzoom > maxzoom {
+      issues.push(Issue::error(format!(
+        "metadata minzoom {} is above maxzoom {}",
+        minzoom, maxzoom
+      )));
+    }
+  }
+
+  let bounds = metadata
+    .get("bounds")
+    .and_then(|bounds| match parse_numbers(bounds) {
+      Some(b) if b.len() == 4 => {
+        if b[0] < -180.0 || b[2] > 180.0 || b[1] < -90.0 || b[3] > 90.0 {
+          issues.push(Issue::error(format!("bounds are out of range: {}", bounds)));
+        } else if b[1] > b[3] {
+          issues.push(Issue::error(format!(
+            "bounds south is above north: {}",
+            bounds
+          )));
+        } else if b[0] > b[2] {
+          issues.push(Issue::warning(format!(
+            "bounds cross the antimeridian: {}",
+            bounds
+          )));
+        }
+        Some(b)
+      }
+      _ => {
+        issues.push(Issue::error(format!(
+          "bounds are not 4 numbers: west,south,east,north: {}",
+          bounds
+        )));
+        None
+      }
+    });
+
+  if let Some(center) = metadata.get("center") {
+    match parse_numbers(center) {
+      Some(c) if c.len() == 3 && c[2].fract() == 0.0 => {
+        if let Some(b) = &bounds {
+          if b[0] <= b[2] && (c[0] < b[0] || c[0] > b[2] || c[1] < b[1] || c[1] > b[3]) {
+            issues.push(Issue::warning(format!(
+              "center is outside of the bounds: {}",
+              center
+            )));
+          }
+        }
+        let zoom = c[2] as i64;
+        if minzoom.map_or(false, |z| zoom < z) || maxzoom.map_or(false, |z| zoom > z) {
+          issues.push(Issue::warning(format!(
+            "center zoom is outside of minzoom and maxzoom: {}",
+            center
+          )));
+        }
+      }
+      _ => issues.push(Issue::error(format!(
+        "center is not longitude,latitude,zoom: {}",
+        center
+      ))),
+    }
+  }
+
+  let zoom_range = match (minzoom, maxzoom) {
+    (Some(minzoom), Some(maxzoom)) => Some((minzoom, maxzoom)),
+    _ => None,
+  };
+  (issues, zoom_range)
+}
+
+fn check_value(value: &vector_tile::tile::Value) -> bool {
+  let set = [
+    value.string_value.is_some(),
+    value.float_value.is_some(),
+    value.double_value.is_some(),
+    value.int_value.is_some(),
+    value.uint_value.is_some(),
+    value.sint_value.is_some(),
+    value.bool_value.is_some(),
+  ];
+  set.iter().filter(|s| **s).count() == 1
+}
+
+// Checks a decoded tile against MVT 2.1: layer versions and names, tags that point into the
+// keys and values of their layer, values with exactly one field and the geometry commands.
+fn check_vector_tile(tile: &vector_tile::Tile) -> Vec<Issue> {
+  let mut issues = Vec::new();
+  let mut names = HashSet::new();
+  for layer in tile.layers.iter() {
+    let name = layer.name.as_str();
+    if name.is_empty() {
+      issues.push(Issue::error("layer has no name".to_string()));
+    } else if !names.insert(name) {
+      issues
+        .push(Issue::error("layer name is used more than once".to_string()).in_layer(name, None));
+    }
+    match layer.version {
+      2 => {}
+      1 => {
+        issues.push(Issue::warning("layer is version 1, not 2".to_string()).in_layer(name, None))
+      }
+      version => issues.push(
+        Issue::error(format!("layer has an unknown version {}", version)).in_layer(name, None),
+      ),
+    }
+    if layer.extent() == 0 {
+      issues.push(Issue::error("layer has an extent of 0".to_string()).in_layer(name, None));
+    }
+    for (i, value) in layer.values.iter().enumerate() {
+      if !check_value(value) {
+        issues.push(
+          Issue::error(format!("value {} doesn't have exactly one field set", i))
+            .in_layer(name, None),
+        );
+      }
+    }
+
+    for (i, feature) in layer.features.iter().enumerate() {
+      if feature.tags.len() % 2 != 0 {
+        issues.push(
+          Issue::error("feature has an odd number of tags".to_string()).in_layer(name, Some(i)),
+        );
+      }
+      for pair in feature.tags.chunks_exact(2) {
+        if pair[0] as usize >= layer.keys.len() {
+          issues.push(
+            Issue::error(format!("tag key {} is out of range", pair[0])).in_layer(name, Some(i)),
+          );
+        }
+        if pair[1] as usize >= layer.values.len() {
+          issues.push(
+            Issue::error(format!("tag value {} is out of range", pair[1])).in_layer(name, Some(i)),
+          );
+        }
+      }
+
+      let geom_type = feature
+        .r#type
+        .unwrap_or(vector_tile::tile::GeomType::Unknown as i32);
+      if geom_type == vector_tile::tile::GeomType::Unknown as i32 {
+        issues.push(
+          Issue::warning("feature has an unknown geometry type".to_string())
+            .in_layer(name, Some(i)),
+        );
+      } else if let Err(err) = vector_tile_ops::check_geometry(geom_type, &feature.geometry) {
+        issues.push(Issue::error(format!("invalid geometry: {}", err)).in_layer(name, Some(i)));
+      }
+    }
+  }
+  issues
+}
+
+fn check_tile_data(data: &[u8], format: TileFormat) -> Vec<Issue> {
+  if data.is_empty() {
+    return vec![Issue::warning("tile is empty".to_string())];
+  }
+  let signature_matches = match format {
+    TileFormat::Vector => {
+      return match vector_tile_ops::decode_tile(data) {
+        Ok(tile) => check_vector_tile(&tile),
+        Err(err) => vec![Issue::error(err.to_string())],
+      };
+    }
+    TileFormat::Png => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+    TileFormat::Jpg => data.starts_with(&[0xff, 0xd8, 0xff]),
+    TileFormat::Webp => data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP",
+    TileFormat::Other => true,
+  };
+  if signature_matches {
+    vec![]
+  } else {
+    vec![Issue::error(
+      "tile data doesn't match the format of the archive".to_string(),
+    )]
+  }
+}
+
+// A tile as stored in the archive: zoom, column and TMS row for mbtiles.
+type StoredTile = (i64, i64, i64, Vec<u8>);
+
+// what a worker found: the number of tiles it checked, their issues and zoom range
+type WorkerResult = (u64, Vec<Issue>, Option<(i64, i64)>);
+
+fn check_stored_tile(
+  (z, x, tms_y, data): StoredTile,
+  format: TileFormat,
+  zoom_range: Option<(i64, i64)>,
+) -> Vec<Issue> {
+  let fits =
+    (0..=MAX_ZOOM).contains(&z) && (0..1 << z).contains(&x) && (0..1 << z).contains(&tms_y);
+  if !fits {
+    return vec![Issue {
+      tile: Some(format!("{}/{}/{} (TMS)", z, x, tms_y)),
+      ..Issue::error("tile coordinates don't fit their zoom level".to_string())
+    }];
+  }
+  let label = format!("{}/{}/{}", z, x, (1 << z) - 1 - tms_y);
+
+  let mut issues = check_tile_data(&data, format);
+  if let Some((minzoom, maxzoom)) = zoom_range {
+    if z < minzoom || z > maxzoom {
+      issues.push(Issue::error(
+        "tile is outside of the metadata minzoom and maxzoom".to_string(),
+      ));
+    }
+  }
+  for issue in issues.iter_mut() {
+    issue.tile = Some(label.clone());
+  }
+  issues
+}
+
+fn read_mbtiles_metadata(
+  connection: &sqlite::Connection,
+) -> Result<HashMap<String, String>, String> {
+  let mut statement = connection
+    .prepare("SELECT name, value FROM metadata;")
+    .map_err(|err| format!("can't read the metadata table: {}", err))?;
+  let mut metadata = HashMap::<String, String>::new();
+  while let Ok(sqlite::State::Row) = statement.next() {
+    let name = statement.read::<String>(0).unwrap_or_default();
+    let value = statement.read::<String>(1).unwrap_or_default();
+    metadata.insert(name, value);
+  }
+  Ok(metadata)
+}
+
+// Sends every tile of the archive to `tiles_tx`, the coordinates as stored for mbtiles and
+// converted to TMS for PMTiles.
+fn read_tiles(input: &Path, tiles_tx: crossbeam_channel::Sender<StoredTile>) -> Result<(), String> {
+  if pmtiles::is_pmtiles(input) {
+    let (header, entries) = pmtiles::try_read_entries(input)?;
+    let mut file = File::open(input).map_err(|err| err.to_string())?;
+    for entry in entries {
+      let mut data = vec![0; entry.length as usize];
+      let offset = header
+        .tile_data_offset
+        .checked_add(entry.offset)
+        .ok_or_else(|| format!("tile data offset {} is out of range", entry.offset))?;
+      file
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|err| format!("can't read tile data: {}", err))?;
+      for tile_id in entry.tile_id..(entry.tile_id + entry.run_length as u64) {
+        let (z, x, y) = pmtiles::tile_id_to_zxy(tile_id);
+        let tms_y = (1i64 << z) - 1 - y as i64;
+        tiles_tx
+          .send((z as i64, x as i64, tms_y, data.clone()))
+          .unwrap();
+      }
+    }
+    return Ok(());
+  }
+
+  let connection = sqlite::open(input).map_err(|err| err.to_string())?;
+  let mut statement = connection
+    .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles;")
+    .map_err(|err| format!("can't read the tiles table: {}", err))?;
+  while let sqlite::State::Row = statement.next().map_err(|err| err.to_string())? {
+    let tile = (
+      statement.read::<i64>(0).unwrap_or(-1),
+      statement.read::<i64>(1).unwrap_or(-1),
+      statement.read::<i64>(2).unwrap_or(-1),
+      statement.read::<Vec<u8>>(3).unwrap_or_default(),
+    );
+    tiles_tx.send(tile).unwrap();
+  }
+  Ok(())
+}
+
+fn validate_archive(input: &Path) -> Report {
+  let mut report = Report {
+    input: input.display().to_string(),
+    ..Default::default()
+  };
+
+  let metadata = if pmtiles::is_pmtiles(input) {
+    pmtiles::try_read_metadata(input)
+  } else {
+    sqlite::open(input)
+      .map_err(|err| format!("not a SQLite database: {}", err))
+      .and_then(|connection| read_mbtiles_metadata(&connection))
+  };
+  let metadata = match metadata {
+    Ok(metadata) => metadata,
+    Err(message) => {
+      report.add(Issue::error(message));
+      return report;
+    }
+  };
+
+  let (metadata_issues, zoom_range) = check_metadata(&metadata);
+  for issue in metadata_issues {
+    report.add(issue);
+  }
+  let format = match metadata.get("format").map(|f| f.as_str()) {
+    None | Some("pbf") | Some("mvt") => TileFormat::Vector,
+    Some("png") => TileFormat::Png,
+    Some("jpg") | Some("jpeg") => TileFormat::Jpg,
+    Some("webp") => TileFormat::Webp,
+    Some(_) => TileFormat::Other,
+  };
+
+  let (tiles_tx, tiles_rx) = crossbeam_channel::bounded::<StoredTile>(reader::queue_capacity(
+    reader::average_tile_size(input),
+  ));
+  let max_workers = std::cmp::max(num_cpus::get().saturating_sub(2), 2);
+  let worker_handles: Vec<thread::JoinHandle<WorkerResult>> = (0..max_workers)
+    .map(|_| {
+      let thread_tiles_rx = tiles_rx.clone();
+      thread::spawn(move || {
+        let mut count = 0;
+        let mut issues = Vec::new();
+        let mut zooms: Option<(i64, i64)> = None;
+        while let Ok(tile) = thread_tiles_rx.recv() {
+          count += 1;
+          let z = tile.0;
+          zooms = Some(zooms.map_or((z, z), |(min, max)| (min.min(z), max.max(z))));
+          issues.extend(check_stored_tile(tile, format, zoom_range));
+        }
+        (count, issues, zooms)
+      })
+    })
+    .collect();
+  drop(tiles_rx);
+
+  if let Err(message) = read_tiles(input, tiles_tx) {
+    report.add(Issue::error(message));
+  }
+
+  let mut tile_zooms: Option<(i64, i64)> = None;
+  for handle in worker_handles {
+    let (count, issues, zooms) = handle.join().unwrap();
+    report.tiles += count;
+    for issue in issues {
+      report.add(issue);
+    }
+    if let Some((min, max)) = zooms {
+      tile_zooms = Some(tile_zooms.map_or((min, max), |(a, b)| (a.min(min), b.max(max))));
+    }
+  }
+
+  match (tile_zooms, zoom_range) {
+    (None, _) => report.add(Issue::warning("archive has no tiles".to_string())),
+    (Some(tiles), Some(metadata)) if tiles != metadata => report.add(Issue::warning(format!(
+      "tiles are from z{} to z{}, metadata says z{} to z{}",
+      tiles.0, tiles.1, metadata.0, metadata.1
+    ))),
+    _ => {}
+  }
+
+  report.valid = report.error_count == 0;
+  report
+}
+
+// Prints a JSON report about the archive, and returns whether it is free of errors.
+pub fn validate(input: PathBuf) -> bool {
+  let report = validate_archive(&input);
+  println!("{}", serde_json::to_string_pretty(&report).unwrap());
+  report.valid
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vector_tile_ops::zz_enc;
+
+  fn metadata(entries: &[(&str, &str)]) -> HashMap<String, String> {
+    entries
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn test_check_metadata() {
+    let (issues, zoom_range) = check_metadata(&metadata(&[
+      ("name", "test"),
+      ("format", "pbf"),
+      ("json", r#"{"vector_layers":[{"id":"roads","fields":{}}]}"#),
+      ("bounds", "-180,-85.0511,180,85.0511"),
+      ("center", "0,0,2"),
+      ("minzoom", "0"),
+      ("maxzoom", "5"),
+    ]));
+    assert!(issues.is_empty(), "{:?}", issues);
+    assert_eq!(zoom_range, Some((0, 5)));
+
+    let (issues, _) = check_metadata(&metadata(&[
+      ("format", "pbf"),
+      ("json", r#"{"vector_layers":[{"fields":{}}]}"#),
+      ("bounds", "10,50,20"),
+      ("center", "0,0,1.5"),
+      ("minzoom", "6"),
+      ("maxzoom", "5"),
+      ("partial", "true"),
+    ]));
+    let errors: Vec<&str> = issues
+      .iter()
+      .filter(|i| i.severity == Severity::Error)
+      .map(|i| i.message.as_str())
+      .collect();
+    assert_eq!(
+      errors,
+      vec![
+        "metadata has no name",
+        "archive is partial, it was written by an interrupted run",
+        "vector_layers[0] has no id",
+        "metadata minzoom 6 is above maxzoom 5",
+        "bounds are not 4 numbers: west,south,east,north: 10,50,20",
+        "center is not longitude,latitude,zoom: 0,0,1.5",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_check_vector_tile() {
+    let layer = vector_tile::tile::Layer {
+      version: 2,
+      name: "roads".to_string(),
+      keys: vec!["name".to_string()],
+      values: vec![
+        vector_tile::tile::Value {
+          string_value: Some("main".to_string()),
+          ..Default::default()
+        },
+        vector_tile::tile::Value::default(),
+      ],
+      features: vec![
+        vector_tile::tile::Feature {
+          r#type: Some(vector_tile::tile::GeomType::Linestring as i32),
+          tags: vec![0, 0],
+          geometry: vec![9, zz_enc(0), zz_enc(0), 10, zz_enc(10), zz_enc(10)],
+          ..Default::default()
+        },
+        vector_tile::tile::Feature {
+          r#type: Some(vector_tile::tile::GeomType::Linestring as i32),
+          tags: vec![1, 2],
+          // a line with a single point
+          geometry: vec![9, zz_enc(0), zz_enc(0)],
+          ..Default::default()
+        },
+      ],
+      extent: Some(4096),
+    };
+    let tile = vector_tile::Tile {
+      layers: vec![layer.clone(), layer],
+    };
+    let messages: Vec<(Option<usize>, String)> = check_vector_tile(&tile)
+      .into_iter()
+      .take(5)
+      .map(|i| (i.feature, i.message))
+      .collect();
+    assert_eq!(
+      messages,
+      vec![
+        (
+          None,
+          "value 1 doesn't have exactly one field set".to_string()
+        ),
+        (Some(1), "tag key 1 is out of range".to_string()),
+        (Some(1), "tag value 2 is out of range".to_string()),
+        (
+          Some(1),
+          "invalid geometry: command at 3 runs past the end of the geometry".to_string()
+        ),
+        (None, "layer name is used more than once".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_check_stored_tile() {
+    let issues = check_stored_tile((2, 1, 4, vec![]), TileFormat::Vector, None);
+    assert_eq!(
+      issues[0].message,
+      "tile coordinates don't fit their zoom level"
+    );
+    assert_eq!(issues[0].tile, Some("2/1/4 (TMS)".to_string()));
+
+    let issues = check_stored_tile((2, 1, 0, vec![1, 2, 3]), TileFormat::Png, Some((0, 1)));
+    let messages: Vec<&str> = issues.iter().map(|i| i.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "tile data doesn't match the format of the archive",
+        "tile is outside of the metadata minzoom and maxzoom"
+      ]
+    );
+    // XYZ coordinates in the report
+    assert_eq!(issues[0].tile, Some("2/1/3".to_string()));
+  }
+
+  #[test]
+  fn test_validate_broken_pmtiles() {
+    let input = std::env::temp_dir().join(format!("broken_{}.pmtiles", std::process::id()));
+    std::fs::write(&input, []).unwrap();
+    let report = validate_archive(&input);
+    assert!(!report.valid);
+    assert_eq!(
+      report.errors[0].message,
+      "Input file is not a PMTiles archive"
+    );
+
+    // the header points at a root directory past the end of the file
+    let header = pmtiles::Header {
+      root_directory_offset: pmtiles::HEADER_LENGTH as u64,
+      root_directory_length: 1000,
+      metadata_offset: pmtiles::HEADER_LENGTH as u64,
+      internal_compression: pmtiles::COMPRESSION_NONE,
+      ..Default::default()
+    };
+    std::fs::write(&input, header.to_bytes()).unwrap();
+    let report = validate_archive(&input);
+    assert!(!report.valid);
+    assert!(report
+      .errors
+      .iter()
+      .any(|issue| issue.message.contains("truncated")));
+
+    // a root directory with a single run of 2 tiles that starts at u64::MAX, uncompressed varints
+    // of the count, tile ID, run length, length and offset + 1
+    let mut root = vec![1];
+    root.extend([0xff; 9]);
+    root.extend([0x01, 2, 1, 1]);
+    let header = pmtiles::Header {
+      root_directory_offset: pmtiles::HEADER_LENGTH as u64,
+      root_directory_length: root.len() as u64,
+      metadata_offset: pmtiles::HEADER_LENGTH as u64,
+      internal_compression: pmtiles::COMPRESSION_NONE,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes();
+    bytes.extend(&root);
+    std::fs::write(&input, bytes).unwrap();
+    let report = validate_archive(&input);
+    assert!(!report.valid);
+    assert!(report
+      .errors
+      .iter()
+      .any(|issue| issue.message.contains("past z31")));
+    std::fs::remove_file(&input).unwrap();
+  }
+}
@@ -1,6 +1,11 @@
-use crate::geom::{LineString, Point, Polygon};
+use crate::geom::{self, Coordinate, LineString, MultiPolygon, Point, Polygon, Ring};
 use crate::lineclip;
+use crate::regionclip::{self, ClipRing};
+use flate2::read::GzDecoder;
 use mbtiles_tool::vector_tile;
+use prost::Message;
+use std::fmt;
+use std::io::prelude::*;
 
 pub fn zz_enc(n: i32) -> u32 {
   ((n << 1) ^ (n >> 31)) as u32
@@ -28,304 +33,534 @@ pub fn encode_command(cmd: Command) -> u32 {
   (cmd.id as u32 & 0x7) | (cmd.count << 3)
 }
 
-pub fn clip_points_to_bbox(points: Vec<Point>, min: i32, max: i32) -> Vec<Point> {
+// Everything that can go wrong when reading a tile or one of its geometries. Offsets are the
+// index of the offending command in the encoded geometry.
+#[derive(Debug)]
+pub enum TileError {
+  Gzip(std::io::Error),
+  Protobuf(prost::DecodeError),
+  UnknownGeometryType(i32),
+  // a command that isn't valid for the geometry type, or not a command at all
+  UnexpectedCommand { id: u8, offset: usize },
+  // a LineTo or ClosePath without a MoveTo to start from
+  MissingMoveTo { offset: usize },
+  // the parameters of a command run past the end of the geometry
+  TruncatedGeometry { offset: usize },
+  CoordinateOverflow { offset: usize },
+  // a command can hold at most 2^29 - 1 parameters
+  TooManyPoints(usize),
+  // a command count that the specification doesn't allow at this point of the geometry
+  InvalidCommandCount { id: u8, count: u32, offset: usize },
+  // a ring without any area, which is neither an exterior nor an interior ring
+  DegenerateRing { offset: usize },
+  // the first ring of a polygon has to be an exterior ring, with a positive area
+  MissingExteriorRing { offset: usize },
+}
+
+impl fmt::Display for TileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TileError::Gzip(err) => write!(f, "invalid gzip data: {}", err),
+      TileError::Protobuf(err) => write!(f, "invalid vector tile: {}", err),
+      TileError::UnknownGeometryType(geom_type) => {
+        write!(f, "unsupported geometry type {}", geom_type)
+      }
+      TileError::UnexpectedCommand { id, offset } => {
+        write!(f, "unexpected command {} at {}", id, offset)
+      }
+      TileError::MissingMoveTo { offset } => {
+        write!(f, "command at {} has no MoveTo to start from", offset)
+      }
+      TileError::TruncatedGeometry { offset } => {
+        write!(f, "command at {} runs past the end of the geometry", offset)
+      }
+      TileError::CoordinateOverflow { offset } => {
+        write!(f, "coordinates of command at {} overflow", offset)
+      }
+      TileError::TooManyPoints(count) => write!(f, "{} points don't fit in one command", count),
+      TileError::InvalidCommandCount { id, count, offset } => {
+        write!(
+          f,
+          "command {} at {} has an invalid count of {}",
+          id, offset, count
+        )
+      }
+      TileError::DegenerateRing { offset } => write!(f, "ring at {} has no area", offset),
+      TileError::MissingExteriorRing { offset } => {
+        write!(
+          f,
+          "polygon at {} doesn't start with an exterior ring",
+          offset
+        )
+      }
+    }
+  }
+}
+
+impl std::error::Error for TileError {}
+
+const MAX_COMMAND_COUNT: usize = (1 << 29) - 1;
+
+// Decompresses the tile if it is gzipped, and decodes it.
+pub fn decode_tile(data: &[u8]) -> Result<vector_tile::Tile, TileError> {
+  if data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b {
+    let mut raw = Vec::with_capacity(data.len() * 2);
+    GzDecoder::new(data)
+      .read_to_end(&mut raw)
+      .map_err(TileError::Gzip)?;
+    return vector_tile::Tile::decode(&*raw).map_err(TileError::Protobuf);
+  }
+  vector_tile::Tile::decode(data).map_err(TileError::Protobuf)
+}
+
+pub fn clip_points_to_bbox<T: Coordinate>(
+  points: Vec<Point<T>>,
+  bbox: (T, T, T, T),
+) -> Vec<Point<T>> {
   points
     .into_iter()
-    .filter(|&Point { x, y }| (min <= x && x <= max && min <= y && y <= max))
+    .filter(|&Point { x, y }| bbox.0 <= x && x <= bbox.2 && bbox.1 <= y && y <= bbox.3)
     .collect()
 }
 
-fn decode_points(geometry: &[u32]) -> Vec<Point> {
-  let mut points = Vec::<Point>::new();
+// A command with its parameters resolved to absolute tile coordinates.
+struct DecodedCommand {
+  id: u8,
+  offset: usize,
+  points: Vec<Point>,
+}
 
-  let mut cursor_x: i32 = 0;
-  let mut cursor_y: i32 = 0;
+// Splits a geometry into its commands, checking that every command is known and complete.
+fn decode_commands(geometry: &[u32]) -> Result<Vec<DecodedCommand>, TileError> {
+  let mut commands = Vec::<DecodedCommand>::new();
 
+  let mut cursor: Point = Point { x: 0, y: 0 };
   let mut i: usize = 0;
 
   while i < geometry.len() {
+    let offset = i;
     let cmd = parse_command(geometry[i]);
-    if cmd.id == 1 {
-      i += 1;
-      let starting_i = i;
-      while i < starting_i + (cmd.count * 2) as usize {
-        let x = zz_dec(geometry[i]);
-        let y = zz_dec(geometry[i + 1]);
-        cursor_x += x;
-        cursor_y += y;
-
-        points.push(Point {
-          x: cursor_x,
-          y: cursor_y,
-        });
+    i += 1;
+    let param_count = match cmd.id {
+      1 | 2 => cmd.count as usize * 2,
+      7 => 0,
+      id => return Err(TileError::UnexpectedCommand { id, offset }),
+    };
+    if geometry.len() - i < param_count {
+      return Err(TileError::TruncatedGeometry { offset });
+    }
 
-        i += 2;
-      }
+    let mut points = Vec::<Point>::with_capacity(param_count / 2);
+    for param in geometry[i..i + param_count].chunks_exact(2) {
+      let x = cursor.x.checked_add(zz_dec(param[0]));
+      let y = cursor.y.checked_add(zz_dec(param[1]));
+      cursor = match (x, y) {
+        (Some(x), Some(y)) => Point { x, y },
+        _ => return Err(TileError::CoordinateOverflow { offset }),
+      };
+      points.push(cursor);
     }
+    i += param_count;
+
+    commands.push(DecodedCommand {
+      id: cmd.id,
+      offset,
+      points,
+    });
   }
-  points
+  Ok(commands)
 }
 
-fn decode_linestrings(geometry: &[u32]) -> Vec<LineString> {
-  let mut lines = Vec::<LineString>::new();
+fn decode_points(geometry: &[u32]) -> Result<Vec<Point>, TileError> {
+  let mut points = Vec::<Point>::new();
+  for cmd in decode_commands(geometry)? {
+    if cmd.id != 1 {
+      return Err(TileError::UnexpectedCommand {
+        id: cmd.id,
+        offset: cmd.offset,
+      });
+    }
+    points.extend(cmd.points);
+  }
+  Ok(points)
+}
 
-  let mut cursor_x: i32 = 0;
-  let mut cursor_y: i32 = 0;
-  let mut i: usize = 0;
+fn decode_linestrings(geometry: &[u32]) -> Result<Vec<LineString>, TileError> {
+  let mut lines = Vec::<LineString>::new();
 
   let mut coord_buffer = Vec::<Point>::new();
 
-  while i < geometry.len() {
-    let cmd = parse_command(geometry[i]);
-    if cmd.id == 1 || cmd.id == 2 {
-      i += 1;
-      let starting_i = i;
-      while i < starting_i + (cmd.count * 2) as usize {
-        let x = zz_dec(geometry[i]);
-        let y = zz_dec(geometry[i + 1]);
-        cursor_x += x;
-        cursor_y += y;
-
-        if cmd.id == 1 {
-          // moveTo in a linestring context means a new line is started at that point
+  for cmd in decode_commands(geometry)? {
+    match cmd.id {
+      1 => {
+        // moveTo in a linestring context means a new line is started at that point
+        for point in cmd.points {
           if !coord_buffer.is_empty() {
             lines.push(LineString {
               points: coord_buffer.clone(),
             });
           }
-          coord_buffer = vec![Point {
-            x: cursor_x,
-            y: cursor_y,
-          }];
-        } else if cmd.id == 2 {
-          coord_buffer.push(Point {
-            x: cursor_x,
-            y: cursor_y,
-          });
+          coord_buffer = vec![point];
         }
-
-        i += 2;
+      }
+      2 => {
+        if coord_buffer.is_empty() {
+          return Err(TileError::MissingMoveTo { offset: cmd.offset });
+        }
+        coord_buffer.extend(cmd.points);
+      }
+      id => {
+        return Err(TileError::UnexpectedCommand {
+          id,
+          offset: cmd.offset,
+        })
       }
     }
   }
+  if !coord_buffer.is_empty() {
+    lines.push(LineString {
+      points: coord_buffer,
+    });
+  }
 
-  lines
+  Ok(lines)
 }
 
-fn decode_polygons(geometry: &[u32]) -> Vec<Polygon> {
-  let mut polygons = Vec::<Polygon>::new();
-
-  let mut cursor_x: i32 = 0;
-  let mut cursor_y: i32 = 0;
-  let mut i: usize = 0;
+fn decode_polygons(geometry: &[u32]) -> Result<MultiPolygon, TileError> {
+  let mut rings = Vec::<Ring>::new();
 
   let mut coord_buffer = Vec::<Point>::new();
 
-  while i < geometry.len() {
-    let cmd = parse_command(geometry[i]);
-    if cmd.id == 1 || cmd.id == 2 {
-      i += 1;
-      let starting_i = i;
-      while i < starting_i + (cmd.count * 2) as usize {
-        let x = zz_dec(geometry[i]);
-        let y = zz_dec(geometry[i + 1]);
-        cursor_x += x;
-        cursor_y += y;
-
-        if cmd.id == 1 {
-          coord_buffer = vec![Point {
-            x: cursor_x,
-            y: cursor_y,
-          }];
-        } else if cmd.id == 2 {
-          coord_buffer.push(Point {
-            x: cursor_x,
-            y: cursor_y,
-          });
+  for cmd in decode_commands(geometry)? {
+    match cmd.id {
+      1 => {
+        if let Some(point) = cmd.points.last() {
+          coord_buffer = vec![*point];
         }
-
-        i += 2;
       }
-    } else if cmd.id == 7 {
-      // close path -- polygon ends here
-      polygons.push(Polygon {
-        points: coord_buffer.clone(),
-      });
-      coord_buffer = vec![];
-
-      i += 1;
+      2 => {
+        if coord_buffer.is_empty() {
+          return Err(TileError::MissingMoveTo { offset: cmd.offset });
+        }
+        coord_buffer.extend(cmd.points);
+      }
+      _ => {
+        // close path -- ring ends here
+        if coord_buffer.is_empty() {
+          return Err(TileError::MissingMoveTo { offset: cmd.offset });
+        }
+        rings.push(Ring {
+          points: coord_buffer.clone(),
+        });
+        coord_buffer = vec![];
+      }
     }
   }
 
-  polygons
+  Ok(geom::classify_rings(rings))
 }
 
-fn encode_points(points: &[Point]) -> Vec<u32> {
-  if points.is_empty() {
-    return vec![];
+// Checks that the commands follow the sequences of the specification for the geometry type,
+// which is stricter than what the decoders accept: lines are a MoveTo of 1 point followed by
+// a LineTo, rings a MoveTo of 1 point, a LineTo of at least 2 points and a ClosePath.
+pub fn check_geometry(geom_type: i32, geometry: &[u32]) -> Result<(), TileError> {
+  let commands = decode_commands(geometry)?;
+  let check_command = |cmd: &DecodedCommand, id: u8, min_count: u32, max_count: u32| {
+    let count = parse_command(geometry[cmd.offset]).count;
+    if cmd.id != id {
+      Err(TileError::UnexpectedCommand {
+        id: cmd.id,
+        offset: cmd.offset,
+      })
+    } else if count < min_count || count > max_count {
+      Err(TileError::InvalidCommandCount {
+        id,
+        count,
+        offset: cmd.offset,
+      })
+    } else {
+      Ok(())
+    }
+  };
+  let truncated = || TileError::TruncatedGeometry {
+    offset: geometry.len(),
+  };
+
+  if geom_type == vector_tile::tile::GeomType::Point as i32 {
+    for cmd in commands.iter() {
+      check_command(cmd, 1, 1, u32::MAX)?;
+    }
+  } else if geom_type == vector_tile::tile::GeomType::Linestring as i32 {
+    for line in commands.chunks(2) {
+      check_command(&line[0], 1, 1, 1)?;
+      check_command(line.get(1).ok_or_else(truncated)?, 2, 1, u32::MAX)?;
+    }
+  } else if geom_type == vector_tile::tile::GeomType::Polygon as i32 {
+    for (i, ring) in commands.chunks(3).enumerate() {
+      check_command(&ring[0], 1, 1, 1)?;
+      let line_to = ring.get(1).ok_or_else(truncated)?;
+      check_command(line_to, 2, 2, u32::MAX)?;
+      check_command(ring.get(2).ok_or_else(truncated)?, 7, 1, 1)?;
+
+      let points = [ring[0].points.clone(), line_to.points.clone()].concat();
+      let area = geom::signed_area(&Ring { points });
+      if area == 0 {
+        return Err(TileError::DegenerateRing {
+          offset: ring[0].offset,
+        });
+      }
+      if i == 0 && area < 0 {
+        return Err(TileError::MissingExteriorRing {
+          offset: ring[0].offset,
+        });
+      }
+    }
+  } else {
+    return Err(TileError::UnknownGeometryType(geom_type));
+  }
+  Ok(())
+}
+
+// Appends a command with its points as deltas from the cursor, which is moved along.
+fn encode_command_points(
+  out: &mut Vec<u32>,
+  id: u8,
+  points: &[Point],
+  cursor: &mut Point,
+) -> Result<(), TileError> {
+  if points.len() > MAX_COMMAND_COUNT {
+    return Err(TileError::TooManyPoints(points.len()));
   }
-  let mut out = vec![encode_command(Command {
-    id: 1,
+  out.push(encode_command(Command {
+    id,
     count: points.len() as u32,
-  })];
-  let mut c_x: Option<i32> = None;
-  let mut c_y: Option<i32> = None;
+  }));
   for point in points {
-    let Point { x, y } = point;
-    if let (Some(cx), Some(cy)) = (c_x, c_y) {
-      out.push(zz_enc(x - cx));
-      out.push(zz_enc(y - cy));
-      c_x = Some(*x);
-      c_y = Some(*y);
-    } else {
-      c_x = Some(*x);
-      c_y = Some(*y);
-      out.push(zz_enc(*x));
-      out.push(zz_enc(*y));
-    }
+    out.push(zz_enc(point.x.wrapping_sub(cursor.x)));
+    out.push(zz_enc(point.y.wrapping_sub(cursor.y)));
+    *cursor = *point;
   }
-  out
+  Ok(())
 }
 
-fn encode_linestrings(linestrings: &[LineString]) -> Vec<u32> {
-  if linestrings.is_empty() {
-    return vec![];
+fn encode_points(points: &[Point]) -> Result<Vec<u32>, TileError> {
+  if points.is_empty() {
+    return Ok(vec![]);
   }
+  let mut out = Vec::<u32>::with_capacity(points.len() * 2 + 1);
+  encode_command_points(&mut out, 1, points, &mut Point { x: 0, y: 0 })?;
+  Ok(out)
+}
+
+fn encode_linestrings(linestrings: &[LineString]) -> Result<Vec<u32>, TileError> {
   let mut out: Vec<u32> = vec![];
-  let mut c_x: Option<i32> = None;
-  let mut c_y: Option<i32> = None;
+  let mut cursor = Point { x: 0, y: 0 };
   for line in linestrings {
-    if line.points.is_empty() {
-      // this line was completely clipped out
+    if line.points.len() < 2 {
+      // this line was completely clipped out, or only a single point of it is left
       continue;
     }
-    let first_point = line.points[0];
-    out.push(encode_command(Command { id: 1, count: 1 }));
-
-    if let (Some(cx), Some(cy)) = (c_x, c_y) {
-      out.push(zz_enc(first_point.x - cx));
-      out.push(zz_enc(first_point.y - cy));
-      c_x = Some(first_point.x);
-      c_y = Some(first_point.y);
-    } else {
-      c_x = Some(first_point.x);
-      c_y = Some(first_point.y);
-      out.push(zz_enc(first_point.x));
-      out.push(zz_enc(first_point.y));
-    }
-
-    out.push(encode_command(Command {
-      id: 2,
-      count: (line.points.len() - 1) as u32,
-    }));
-
-    for point in line.points.iter().skip(1) {
-      if let (Some(cx), Some(cy)) = (c_x, c_y) {
-        out.push(zz_enc(point.x - cx));
-        out.push(zz_enc(point.y - cy));
-        c_x = Some(point.x);
-        c_y = Some(point.y);
-      } else {
-        panic!("shouldn't happen");
-      }
-    }
+    encode_command_points(&mut out, 1, &line.points[..1], &mut cursor)?;
+    encode_command_points(&mut out, 2, &line.points[1..], &mut cursor)?;
   }
-  out
+  Ok(out)
 }
 
-fn encode_polygons(polygons: &[Polygon]) -> Vec<u32> {
+fn encode_polygons(polygons: &[Polygon]) -> Result<Vec<u32>, TileError> {
   let mut out: Vec<u32> = vec![];
-  let mut c_x: Option<i32> = None;
-  let mut c_y: Option<i32> = None;
+  let mut cursor = Point { x: 0, y: 0 };
   for polygon in polygons {
-    let points = &polygon.points;
-    if points.is_empty() {
+    if !geom::is_valid_ring(&polygon.exterior) {
       // this polygon was completely clipped out
       continue;
     }
-    let first_point = points[0];
-    out.push(encode_command(Command { id: 1, count: 1 }));
-    if let (Some(cx), Some(cy)) = (c_x, c_y) {
-      out.push(zz_enc(first_point.x - cx));
-      out.push(zz_enc(first_point.y - cy));
-      c_x = Some(first_point.x);
-      c_y = Some(first_point.y);
-    } else {
-      c_x = Some(first_point.x);
-      c_y = Some(first_point.y);
-      out.push(zz_enc(first_point.x));
-      out.push(zz_enc(first_point.y));
+    // the exterior ring comes first, followed by its holes, in MVT v2 winding order
+    let mut rings = Vec::with_capacity(polygon.interiors.len() + 1);
+    let mut exterior = polygon.exterior.clone();
+    geom::orient_ring(&mut exterior, true);
+    rings.push(exterior);
+    for interior in polygon.interiors.iter() {
+      let mut interior = interior.clone();
+      geom::orient_ring(&mut interior, false);
+      rings.push(interior);
     }
 
-    out.push(encode_command(Command {
-      id: 2,
-      count: (points.len() - 1) as u32,
-    }));
-
-    for point in points.iter().skip(1) {
-      if let (Some(cx), Some(cy)) = (c_x, c_y) {
-        out.push(zz_enc(point.x - cx));
-        out.push(zz_enc(point.y - cy));
-        c_x = Some(point.x);
-        c_y = Some(point.y);
-      } else {
-        panic!("shouldn't happen");
-      }
+    for ring in rings {
+      encode_command_points(&mut out, 1, &ring.points[..1], &mut cursor)?;
+      encode_command_points(&mut out, 2, &ring.points[1..], &mut cursor)?;
+      out.push(encode_command(Command { id: 7, count: 1 }));
     }
+  }
+  Ok(out)
+}
 
-    out.push(encode_command(Command { id: 7, count: 0 }));
+// Applies `f` to every vertex of an encoded geometry, keeping the command structure intact.
+// Vertices are passed to `f` in absolute tile coordinates and re-encoded as deltas.
+pub fn transform_geometry<F: Fn(Point) -> Point>(
+  geometry: &[u32],
+  f: F,
+) -> Result<Vec<u32>, TileError> {
+  let mut out = Vec::<u32>::with_capacity(geometry.len());
+
+  let mut out_cursor = Point { x: 0, y: 0 };
+  for cmd in decode_commands(geometry)? {
+    // keep the original command, ClosePath is written with a count of 0 or 1 in the wild
+    out.push(geometry[cmd.offset]);
+    for point in cmd.points {
+      let transformed = f(point);
+      out.push(zz_enc(transformed.x.wrapping_sub(out_cursor.x)));
+      out.push(zz_enc(transformed.y.wrapping_sub(out_cursor.y)));
+      out_cursor = transformed;
+    }
   }
-  out
+  Ok(out)
 }
 
 // how many bits right the extent should be shifted. For example, a tile with extent 4096 will have a buffer of 256. Extent 256 will have a buffer of 16.
 const CLIP_BUFFER: u8 = 4;
 
-pub fn clip_geometry(geom_type: i32, geometry: &[u32], extent: u32) -> Vec<u32> {
+pub fn clip_geometry(geom_type: i32, geometry: &[u32], extent: u32) -> Result<Vec<u32>, TileError> {
   let buffer_pixels = (extent >> CLIP_BUFFER) as i32;
   let min = -buffer_pixels;
   let max = (extent as i32) + buffer_pixels;
+  clip_geometry_to_bbox(geom_type, geometry, (min, min, max, max))
+}
+
+// Clipped coordinates lie within the bbox, which is in the i32 range of tile coordinates.
+fn narrow_point<T: Coordinate>(point: Point<T>) -> Point {
+  let narrow = |v: T| i32::try_from(v.into()).expect("clipped coordinate out of the tile range");
+  Point {
+    x: narrow(point.x),
+    y: narrow(point.y),
+  }
+}
+
+fn narrow_line<T: Coordinate>(line: LineString<T>) -> LineString {
+  LineString {
+    points: line.points.into_iter().map(narrow_point).collect(),
+  }
+}
+
+// Clips every ring of the polygon on its own, rings that collapse are dropped. Holes are dropped
+// along with their exterior ring.
+fn clip_polygon_to_bbox<T: Coordinate>(
+  polygon: &Polygon<T>,
+  bbox: (T, T, T, T),
+) -> Option<Polygon> {
+  let clip_ring =
+    |ring: &Ring<T>| geom::clean_ring(narrow_line(lineclip::polygonclip(ring.clone(), bbox)));
+  let exterior = clip_ring(&polygon.exterior);
+  if !geom::is_valid_ring(&exterior) {
+    return None;
+  }
+  let interiors = polygon
+    .interiors
+    .iter()
+    .map(clip_ring)
+    .filter(geom::is_valid_ring)
+    .collect();
+  Some(Polygon {
+    exterior,
+    interiors,
+  })
+}
 
+// bbox in order of: min x, min y, max x, max y, in tile coordinates
+pub fn clip_geometry_to_bbox(
+  geom_type: i32,
+  geometry: &[u32],
+  bbox: (i32, i32, i32, i32),
+) -> Result<Vec<u32>, TileError> {
   if geom_type == vector_tile::tile::GeomType::Point as i32 {
-    let points = decode_points(geometry);
-    let clipped = clip_points_to_bbox(points, min, max);
+    let points = decode_points(geometry)?;
+    let clipped = clip_points_to_bbox(points, bbox);
     return encode_points(&clipped);
   } else if geom_type == vector_tile::tile::GeomType::Linestring as i32 {
-    let lines = decode_linestrings(geometry);
+    let lines = decode_linestrings(geometry)?;
     let mut clipped_lines = Vec::<LineString>::new();
     for line in lines {
-      let mut clipped = lineclip::lineclip(line, (min, min, max, max));
+      let mut clipped = lineclip::lineclip(line, bbox);
       clipped_lines.append(&mut clipped);
     }
     return encode_linestrings(&clipped_lines);
   } else if geom_type == vector_tile::tile::GeomType::Polygon as i32 {
-    let polygons = decode_polygons(geometry);
-    let clipped_polygons = polygons
+    let polygons = decode_polygons(geometry)?;
+    let clipped_polygons: MultiPolygon = polygons
+      .iter()
+      .filter_map(|polygon| clip_polygon_to_bbox(polygon, bbox))
+      .collect();
+    return encode_polygons(&clipped_polygons);
+  }
+
+  // features of an unknown type can't be clipped, they are dropped and the rest is clipped as usual
+  Ok(vec![])
+}
+
+// Clips to a region made of rings in tile coordinates, see `regionclip`.
+pub fn clip_geometry_to_rings(
+  geom_type: i32,
+  geometry: &[u32],
+  rings: &[ClipRing],
+) -> Result<Vec<u32>, TileError> {
+  if geom_type == vector_tile::tile::GeomType::Point as i32 {
+    let points = decode_points(geometry)?;
+    return encode_points(&regionclip::clip_points(points, rings));
+  } else if geom_type == vector_tile::tile::GeomType::Linestring as i32 {
+    let lines = decode_linestrings(geometry)?;
+    let clipped_lines: Vec<LineString> = lines
+      .iter()
+      .flat_map(|line| regionclip::clip_line(line, rings))
+      .collect();
+    return encode_linestrings(&clipped_lines);
+  } else if geom_type == vector_tile::tile::GeomType::Polygon as i32 {
+    let polygons = decode_polygons(geometry)?;
+    let clipped_polygons: MultiPolygon = polygons
       .iter()
-      .map(|polygon| lineclip::polygonclip(polygon.clone(), (min, min, max, max)));
-    return encode_polygons(&clipped_polygons.collect::<Vec<Polygon>>());
+      .flat_map(|polygon| regionclip::clip_polygon(polygon, rings))
+      .collect();
+    return encode_polygons(&clipped_polygons);
   }
 
-  panic!("Unsupported geometry type");
+  // features of an unknown type can't be clipped, they are dropped and the rest is clipped as usual
+  Ok(vec![])
 }
 
-fn scale_geometry(geometry: &mut [u32], new_extent: u32, rel_x: u32, rel_y: u32) -> bool {
+// features without a type are of the unknown type, like in the vector tile specification
+fn feature_type(feature: &vector_tile::tile::Feature) -> i32 {
+  feature
+    .r#type
+    .unwrap_or(vector_tile::tile::GeomType::Unknown as i32)
+}
+
+// Moves the first MoveTo, which moves the whole geometry as the rest is relative to it.
+fn scale_geometry(
+  geometry: &mut [u32],
+  new_extent: u32,
+  rel_x: u32,
+  rel_y: u32,
+) -> Result<(), TileError> {
   if geometry.is_empty() {
-    return false;
+    return Ok(());
   }
   let cmd = parse_command(geometry[0]);
-  // non-moveto for first point? I don't know what to do with that
   if cmd.id != 1 {
-    return false;
+    return Err(TileError::MissingMoveTo { offset: 0 });
+  }
+  if cmd.count == 0 || geometry.len() < 3 {
+    return Err(TileError::TruncatedGeometry { offset: 0 });
   }
   let orig_x = zz_dec(geometry[1]);
   let orig_y = zz_dec(geometry[2]);
-  let scaled_x = orig_x - (new_extent * rel_x) as i32;
-  let scaled_y = orig_y - (new_extent * rel_y) as i32;
-  geometry[1] = zz_enc(scaled_x);
-  geometry[2] = zz_enc(scaled_y);
-
-  true
+  let scaled_x = orig_x.checked_sub((new_extent * rel_x) as i32);
+  let scaled_y = orig_y.checked_sub((new_extent * rel_y) as i32);
+  match (scaled_x, scaled_y) {
+    (Some(scaled_x), Some(scaled_y)) => {
+      geometry[1] = zz_enc(scaled_x);
+      geometry[2] = zz_enc(scaled_y);
+      Ok(())
+    }
+    _ => Err(TileError::CoordinateOverflow { offset: 0 }),
+  }
 }
 
 pub fn scale_tile(
@@ -333,13 +568,13 @@ pub fn scale_tile(
   steps: u32,
   rel_x: u32,
   rel_y: u32,
-) -> vector_tile::Tile {
+) -> Result<vector_tile::Tile, TileError> {
   let mut out = tile;
-  for mut layer in out.layers.iter_mut() {
+  for layer in out.layers.iter_mut() {
     if layer.features.is_empty() {
       continue;
     }
-    if layer.extent == None {
+    if layer.extent.is_none() {
       continue;
     }
     let extent = layer.extent.unwrap();
@@ -351,10 +586,126 @@ pub fn scale_tile(
       let mut feature = original_feature.clone();
 
       let mut geometry = feature.geometry.clone();
-      if !scale_geometry(&mut geometry, tgt_tile_size, rel_x, rel_y) {
+      scale_geometry(&mut geometry, tgt_tile_size, rel_x, rel_y)?;
+      let clipped_geometry = clip_geometry(feature_type(&feature), &geometry, tgt_tile_size)?;
+      if clipped_geometry.is_empty() {
+        // this feature was completely clipped out of the tile, so we can remove it
         continue;
       }
-      let clipped_geometry = clip_geometry(feature.r#type.unwrap(), &geometry, tgt_tile_size);
+      feature.geometry = clipped_geometry;
+
+      features.push(feature);
+    }
+
+    layer.features = features;
+  }
+  Ok(out)
+}
+
+// Scales every vertex by 2^steps, moves the child tile at rel_x/rel_y to the origin and clips the
+// geometry to the buffered child tile. The scaled vertices can lie far outside of the i32 range,
+// so they are clipped as i64 and only narrowed to tile coordinates afterwards.
+fn rescale_geometry(
+  geom_type: i32,
+  geometry: &[u32],
+  extent: u32,
+  steps: u32,
+  rel_x: u32,
+  rel_y: u32,
+) -> Result<Vec<u32>, TileError> {
+  let factor = 1i64
+    .checked_shl(steps)
+    .filter(|&factor| factor > 0)
+    .ok_or(TileError::CoordinateOverflow { offset: 0 })?;
+  let offset_x = extent as i64 * rel_x as i64;
+  let offset_y = extent as i64 * rel_y as i64;
+  // within ±2^62 the products of coordinate differences in `lineclip` fit in an i128
+  let scale_point = |p: &Point| -> Result<Point<i64>, TileError> {
+    let scale = |v: i32, offset: i64| {
+      (v as i64)
+        .checked_mul(factor)
+        .and_then(|v| v.checked_sub(offset))
+        .filter(|v| v.abs() <= 1 << 62)
+    };
+    match (scale(p.x, offset_x), scale(p.y, offset_y)) {
+      (Some(x), Some(y)) => Ok(Point { x, y }),
+      _ => Err(TileError::CoordinateOverflow { offset: 0 }),
+    }
+  };
+  let scale_line = |line: &LineString| -> Result<LineString<i64>, TileError> {
+    Ok(LineString {
+      points: line
+        .points
+        .iter()
+        .map(scale_point)
+        .collect::<Result<_, _>>()?,
+    })
+  };
+
+  let buffer_pixels = (extent >> CLIP_BUFFER) as i64;
+  let min = -buffer_pixels;
+  let max = extent as i64 + buffer_pixels;
+  let bbox = (min, min, max, max);
+
+  if geom_type == vector_tile::tile::GeomType::Point as i32 {
+    let points = decode_points(geometry)?
+      .iter()
+      .map(scale_point)
+      .collect::<Result<_, _>>()?;
+    let clipped: Vec<Point> = clip_points_to_bbox(points, bbox)
+      .into_iter()
+      .map(narrow_point)
+      .collect();
+    return encode_points(&clipped);
+  } else if geom_type == vector_tile::tile::GeomType::Linestring as i32 {
+    let mut clipped_lines = Vec::<LineString>::new();
+    for line in decode_linestrings(geometry)? {
+      let clipped = lineclip::lineclip(scale_line(&line)?, bbox);
+      clipped_lines.extend(clipped.into_iter().map(narrow_line));
+    }
+    return encode_linestrings(&clipped_lines);
+  } else if geom_type == vector_tile::tile::GeomType::Polygon as i32 {
+    let mut clipped_polygons = MultiPolygon::new();
+    for polygon in decode_polygons(geometry)? {
+      let scaled = Polygon {
+        exterior: scale_line(&polygon.exterior)?,
+        interiors: polygon
+          .interiors
+          .iter()
+          .map(scale_line)
+          .collect::<Result<_, _>>()?,
+      };
+      clipped_polygons.extend(clip_polygon_to_bbox(&scaled, bbox));
+    }
+    return encode_polygons(&clipped_polygons);
+  }
+
+  // features of an unknown type can't be clipped, they are dropped and the rest is clipped as usual
+  Ok(vec![])
+}
+
+// Like `scale_tile`, but keeps the extent of every layer and scales the coordinates up instead,
+// so overzoomed tiles have the same precision as tiles generated at that zoom.
+pub fn rescale_tile(
+  tile: vector_tile::Tile,
+  steps: u32,
+  rel_x: u32,
+  rel_y: u32,
+) -> Result<vector_tile::Tile, TileError> {
+  let mut out = tile;
+  for layer in out.layers.iter_mut() {
+    let extent = layer.extent();
+
+    let mut features: Vec<vector_tile::tile::Feature> = Vec::with_capacity(layer.features.len());
+    for mut feature in layer.features.drain(..) {
+      let clipped_geometry = rescale_geometry(
+        feature_type(&feature),
+        &feature.geometry,
+        extent,
+        steps,
+        rel_x,
+        rel_y,
+      )?;
       if clipped_geometry.is_empty() {
         // this feature was completely clipped out of the tile, so we can remove it
         continue;
@@ -366,21 +717,247 @@ pub fn scale_tile(
 
     layer.features = features;
   }
-  out
+  Ok(out)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
 
   #[test]
   fn test_scale_geometry() {
     let mut input_geom_1 = vec![9, 50, 34];
-    scale_geometry(&mut input_geom_1, 1024, 0, 0);
+    scale_geometry(&mut input_geom_1, 1024, 0, 0).unwrap();
     assert_eq!(input_geom_1, vec![9, 50, 34]);
 
     let mut input_geom_2 = vec![9, zz_enc(25), zz_enc(17)];
-    scale_geometry(&mut input_geom_2, 1024, 1, 0);
+    scale_geometry(&mut input_geom_2, 1024, 1, 0).unwrap();
     assert_eq!(input_geom_2, vec![9, zz_enc(25 - 1024), zz_enc(17)]);
   }
+
+  #[test]
+  fn test_transform_geometry() {
+    // MoveTo(10, 10) LineTo(+5, +5) ClosePath
+    let geometry = vec![9, zz_enc(10), zz_enc(10), 10, zz_enc(5), zz_enc(5), 15];
+    let doubled = transform_geometry(&geometry, |p| Point {
+      x: p.x * 2,
+      y: p.y * 2,
+    })
+    .unwrap();
+    assert_eq!(
+      doubled,
+      vec![9, zz_enc(20), zz_enc(20), 10, zz_enc(10), zz_enc(10), 15]
+    );
+
+    let unchanged = transform_geometry(&geometry, |p| p).unwrap();
+    assert_eq!(unchanged, geometry);
+  }
+
+  #[test]
+  fn test_clip_polygon_with_holes() {
+    let ring = |coords: &[(i32, i32)]| Ring {
+      points: coords.iter().map(|&(x, y)| Point { x, y }).collect(),
+    };
+    let exterior = ring(&[(-1000, -1000), (1000, -1000), (1000, 1000), (-1000, 1000)]);
+    let hole = ring(&[(100, 100), (100, 200), (200, 200), (200, 100)]);
+    let clipped_hole = ring(&[(-900, -900), (-900, -800), (-800, -800), (-800, -900)]);
+    let geometry = encode_polygons(&[Polygon {
+      exterior,
+      interiors: vec![hole.clone(), clipped_hole],
+    }])
+    .unwrap();
+    let clipped =
+      clip_geometry(vector_tile::tile::GeomType::Polygon as i32, &geometry, 4096).unwrap();
+    let polygons = decode_polygons(&clipped).unwrap();
+    assert_eq!(polygons.len(), 1);
+    assert_eq!(geom::signed_area(&polygons[0].exterior), 2 * 1256 * 1256);
+    // the hole inside the tile is kept with its winding order
+    assert_eq!(polygons[0].interiors, vec![hole]);
+
+    // a ring without any area is dropped
+    let degenerate = vec![
+      9,
+      zz_enc(0),
+      zz_enc(0),
+      18,
+      zz_enc(10),
+      zz_enc(10),
+      zz_enc(10),
+      zz_enc(10),
+      15,
+    ];
+    assert!(decode_polygons(&degenerate).unwrap().is_empty());
+    assert!(clip_geometry(
+      vector_tile::tile::GeomType::Polygon as i32,
+      &degenerate,
+      4096
+    )
+    .unwrap()
+    .is_empty());
+  }
+
+  #[test]
+  fn test_rescale_tile() {
+    // a line across the whole tile, and a point in the top left quarter
+    let line = vector_tile::tile::Feature {
+      r#type: Some(vector_tile::tile::GeomType::Linestring as i32),
+      geometry: vec![9, zz_enc(0), zz_enc(0), 10, zz_enc(4096), zz_enc(4096)],
+      ..Default::default()
+    };
+    let point = vector_tile::tile::Feature {
+      r#type: Some(vector_tile::tile::GeomType::Point as i32),
+      geometry: vec![9, zz_enc(1000), zz_enc(1001)],
+      ..Default::default()
+    };
+    let tile = vector_tile::Tile {
+      layers: vec![vector_tile::tile::Layer {
+        version: 2,
+        name: "test".to_string(),
+        features: vec![line, point],
+        extent: Some(4096),
+        ..Default::default()
+      }],
+    };
+
+    // the bottom right child, 1 zoom level down
+    let rescaled = rescale_tile(tile.clone(), 1, 1, 1).unwrap();
+    let layer = &rescaled.layers[0];
+    assert_eq!(layer.extent, Some(4096));
+    assert_eq!(layer.features.len(), 1);
+    // clipped to the buffer around the child tile
+    assert_eq!(
+      layer.features[0].geometry,
+      vec![
+        9,
+        zz_enc(-256),
+        zz_enc(-256),
+        10,
+        zz_enc(4096 + 256),
+        zz_enc(4096 + 256)
+      ]
+    );
+
+    // the top left grandchild, 2 zoom levels down
+    let rescaled = rescale_tile(tile.clone(), 2, 0, 0).unwrap();
+    let layer = &rescaled.layers[0];
+    assert_eq!(layer.features.len(), 2);
+    assert_eq!(
+      layer.features[1].geometry,
+      vec![9, zz_enc(4000), zz_enc(4004)]
+    );
+
+    // the end of this line is scaled past the i32 range, the line still leaves the child tile
+    // along its own direction
+    let far_line = vector_tile::tile::Feature {
+      r#type: Some(vector_tile::tile::GeomType::Linestring as i32),
+      geometry: vec![9, zz_enc(0), zz_enc(0), 10, zz_enc(40000), zz_enc(20000)],
+      ..Default::default()
+    };
+    let mut far_tile = tile.clone();
+    far_tile.layers[0].features = vec![far_line];
+    let rescaled = rescale_tile(far_tile, 16, 0, 0).unwrap();
+    assert_eq!(
+      rescaled.layers[0].features[0].geometry,
+      vec![9, zz_enc(0), zz_enc(0), 10, zz_enc(4352), zz_enc(2176)]
+    );
+    // scaling this far overflows
+    assert!(matches!(
+      rescale_geometry(
+        vector_tile::tile::GeomType::Point as i32,
+        &[9, zz_enc(i32::MAX), 0],
+        4096,
+        40,
+        0,
+        0
+      ),
+      Err(TileError::CoordinateOverflow { offset: 0 })
+    ));
+
+    // a feature without a type is dropped, the others are still scaled
+    let mut untyped_tile = tile;
+    untyped_tile.layers[0]
+      .features
+      .push(vector_tile::tile::Feature {
+        geometry: vec![9, zz_enc(10), zz_enc(10)],
+        ..Default::default()
+      });
+    let rescaled = rescale_tile(untyped_tile.clone(), 2, 0, 0).unwrap();
+    assert_eq!(rescaled.layers[0].features.len(), 2);
+    let scaled = scale_tile(untyped_tile, 2, 0, 0).unwrap();
+    assert_eq!(scaled.layers[0].features.len(), 2);
+  }
+
+  #[test]
+  fn test_malformed_geometry() {
+    let point = vector_tile::tile::GeomType::Point as i32;
+    let linestring = vector_tile::tile::GeomType::Linestring as i32;
+    let polygon = vector_tile::tile::GeomType::Polygon as i32;
+
+    // a LineTo in a point geometry used to loop forever
+    assert!(matches!(
+      decode_points(&[9, 2, 2, 10, 2, 2]),
+      Err(TileError::UnexpectedCommand { id: 2, offset: 3 })
+    ));
+    assert!(matches!(
+      decode_linestrings(&[9, 2, 2, 10, 2]),
+      Err(TileError::TruncatedGeometry { offset: 3 })
+    ));
+    assert!(matches!(
+      decode_linestrings(&[10, 2, 2]),
+      Err(TileError::MissingMoveTo { offset: 0 })
+    ));
+    assert!(matches!(
+      decode_polygons(&[9, 2, 2, 4]),
+      Err(TileError::UnexpectedCommand { id: 4, offset: 3 })
+    ));
+    assert!(matches!(
+      decode_points(&[17, zz_enc(i32::MAX), 0, zz_enc(1), 0]),
+      Err(TileError::CoordinateOverflow { offset: 0 })
+    ));
+    // a huge count doesn't allocate anything before the geometry is found to be too short
+    assert!(matches!(
+      decode_points(&[u32::MAX - 6, 0, 0]),
+      Err(TileError::TruncatedGeometry { offset: 0 })
+    ));
+    assert!(matches!(
+      transform_geometry(&[9, 2], |p| p),
+      Err(TileError::TruncatedGeometry { offset: 0 })
+    ));
+    assert!(matches!(
+      decode_tile(&[0x1f, 0x8b, 0, 0]),
+      Err(TileError::Gzip(_))
+    ));
+    assert!(matches!(decode_tile(&[0xff]), Err(TileError::Protobuf(_))));
+
+    // random geometries, mostly made of plausible commands so that decoding gets some way in,
+    // must give an error or a result, but never panic or hang
+    let mut rng = StdRng::seed_from_u64(42);
+    for _ in 0..20000 {
+      let len = rng.gen_range(0..24);
+      let geometry: Vec<u32> = (0..len)
+        .map(|_| match rng.gen_range(0..10) {
+          0 => rng.gen(),
+          1 => encode_command(Command {
+            id: rng.gen_range(0..8),
+            count: rng.gen_range(0..4),
+          }),
+          2 => zz_enc(rng.gen_range(i32::MAX - 10..=i32::MAX)),
+          3 | 4 => [9, 10, 15][rng.gen_range(0..3)],
+          _ => zz_enc(rng.gen_range(-5000..5000)),
+        })
+        .collect();
+
+      for geom_type in [point, linestring, polygon] {
+        if let Ok(clipped) = clip_geometry(geom_type, &geometry, 4096) {
+          // whatever comes out of clipping decodes again
+          clip_geometry(geom_type, &clipped, 4096).unwrap();
+        }
+        let _ = rescale_geometry(geom_type, &geometry, 4096, 3, 5, 2);
+        let mut scaled = geometry.clone();
+        let _ = scale_geometry(&mut scaled, 512, 1, 1);
+      }
+    }
+  }
 }
@@ -1,54 +1,151 @@
-use crate::reader::EXTENT_CHUNK_TILE_COUNT;
-use crate::tilebelt;
+use mbtiles_tool::reader::EXTENT_CHUNK_TILE_COUNT;
+use mbtiles_tool::{interrupt, pmtiles, reader, tilebelt};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{thread, time};
 
+const FLAT_SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS tiles (
+    zoom_level INTEGER,
+    tile_column INTEGER,
+    tile_row INTEGER,
+    tile_data blob
+  );
+
+  CREATE UNIQUE INDEX IF NOT EXISTS xyz ON tiles (zoom_level, tile_column, tile_row);
+";
+
+// The schema written by mbutil and tippecanoe: identical tiles are stored once in `images`,
+// and the `tiles` view keeps the standard layout for readers.
+const DEDUPLICATED_SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS map (
+    zoom_level INTEGER,
+    tile_column INTEGER,
+    tile_row INTEGER,
+    tile_id TEXT
+  );
+
+  CREATE TABLE IF NOT EXISTS images (
+    tile_data blob,
+    tile_id TEXT
+  );
+
+  CREATE UNIQUE INDEX IF NOT EXISTS map_index ON map (zoom_level, tile_column, tile_row);
+  CREATE UNIQUE INDEX IF NOT EXISTS images_id ON images (tile_id);
+
+  CREATE VIEW IF NOT EXISTS tiles AS
+    SELECT
+      map.zoom_level AS zoom_level,
+      map.tile_column AS tile_column,
+      map.tile_row AS tile_row,
+      images.tile_data AS tile_data
+    FROM map
+    JOIN images ON images.tile_id = map.tile_id;
+";
+
+// Outputs are written as fast as possible. A resumed output already holds the tiles of an earlier
+// run, so it is checked first, and journaled from then on so another crash only loses the
+// uncommitted tiles, which the checkpoint doesn't list yet.
+pub fn set_journal_mode(connection: &sqlite::Connection, output: &Path, resume: bool) {
+  if !resume {
+    connection
+      .execute("PRAGMA synchronous = OFF; PRAGMA journal_mode = MEMORY;")
+      .unwrap();
+    return;
+  }
+  // the earlier run wasn't journaled, a crash in the middle of a transaction breaks the output
+  let result = connection
+    .prepare("PRAGMA quick_check;")
+    .and_then(|mut stmt| {
+      stmt.next()?;
+      stmt.read::<String>(0)
+    })
+    .unwrap_or_else(|err| err.to_string());
+  if result != "ok" {
+    panic!(
+      "The output {} is corrupt ({}), run again without --resume",
+      output.display(),
+      result
+    );
+  }
+  connection
+    .execute("PRAGMA synchronous = FULL; PRAGMA journal_mode = WAL;")
+    .unwrap();
+}
+
+// PMTiles archives are always deduplicated, `deduplicate` only applies to mbtiles outputs, and so
+// does `resume`.
 pub fn initialize_writer(
   output: PathBuf,
-  queue: crossbeam_channel::Receiver<tilebelt::TileData>,
+  queue: crossbeam_channel::Receiver<reader::TileData>,
   metadata: HashMap<String, String>,
+  deduplicate: bool,
+  resume: bool,
 ) -> thread::JoinHandle<()> {
+  if pmtiles::is_pmtiles(&output) {
+    return pmtiles::initialize_writer(output, queue, metadata);
+  }
+
   thread::spawn(move || {
     let mut last_ts = time::Instant::now();
     let mut tile_count = 0;
+    let mut duplicate_count = 0;
 
-    let connection = sqlite::open(output).unwrap();
+    let connection = sqlite::open(&output).unwrap();
+    set_journal_mode(&connection, &output, resume);
     connection
-      .execute(
+      .execute(format!(
         "
-        PRAGMA synchronous = OFF;
-        PRAGMA journal_mode = MEMORY;
-
         CREATE TABLE IF NOT EXISTS metadata (
           name text,
           value text
         );
 
-        CREATE TABLE IF NOT EXISTS tiles (
-          zoom_level INTEGER,
-          tile_column INTEGER,
-          tile_row INTEGER,
-          tile_data blob
-        );
-
         CREATE UNIQUE INDEX IF NOT EXISTS name ON metadata (name);
-        CREATE UNIQUE INDEX IF NOT EXISTS xyz ON tiles (zoom_level, tile_column, tile_row);
+
+        {}
 
         BEGIN TRANSACTION;
       ",
-      )
+        if deduplicate {
+          DEDUPLICATED_SCHEMA
+        } else {
+          FLAT_SCHEMA
+        }
+      ))
       .unwrap();
 
     let mut insert_stmt = connection
-      .prepare(
+      .prepare(if deduplicate {
         "
-        INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+        INSERT OR REPLACE INTO map (zoom_level, tile_column, tile_row, tile_id)
         VALUES (?, ?, ?, ?)
-      ",
-      )
+      "
+      } else {
+        "
+        INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+        VALUES (?, ?, ?, ?)
+      "
+      })
       .unwrap();
+    // the unique index on tile_id ignores the tiles that are already stored
+    let mut insert_image_stmt = if deduplicate {
+      Some(
+        connection
+          .prepare(
+            "
+            INSERT OR IGNORE INTO images (tile_id, tile_data)
+            VALUES (?, ?)
+          ",
+          )
+          .unwrap(),
+      )
+    } else {
+      None
+    };
 
+    // the chunks of a checkpointed job are only recorded once their tiles are committed
+    let mut uncommitted_chunks = Vec::new();
     while let Ok(work) = queue.recv() {
       tile_count += 1;
 
@@ -57,28 +154,45 @@ pub fn initialize_writer(
       insert_stmt.bind(1, tile.2 as i64).unwrap();
       insert_stmt.bind(2, tile.0 as i64).unwrap();
       insert_stmt.bind(3, tile.1 as i64).unwrap();
-      insert_stmt.bind(4, &**work.data).unwrap();
+      match insert_image_stmt.as_mut() {
+        Some(insert_image_stmt) => {
+          let tile_id = format!("{:x}", md5::compute(&**work.data));
+          insert_image_stmt.bind(1, &*tile_id).unwrap();
+          insert_image_stmt.bind(2, &**work.data).unwrap();
+          insert_image_stmt.next().unwrap();
+          insert_image_stmt.reset().unwrap();
+          if connection.change_count() == 0 {
+            duplicate_count += 1;
+          }
+          insert_stmt.bind(4, &*tile_id).unwrap();
+        }
+        None => insert_stmt.bind(4, &**work.data).unwrap(),
+      }
 
       insert_stmt.next().unwrap();
       insert_stmt.reset().unwrap();
+      uncommitted_chunks.extend(work.chunk);
 
       if tile_count % EXTENT_CHUNK_TILE_COUNT == 0 {
         connection
           .execute("END TRANSACTION; BEGIN TRANSACTION;")
           .unwrap();
+        uncommitted_chunks.clear();
 
         let ts = time::Instant::now();
         let elapsed = ts.duration_since(last_ts);
         println!(
-          "[output] {} tiles in {}ms ({:.4}ms/tile)",
+          "[output] {} tiles in {}ms ({:.4}ms/tile, {} tiles queued)",
           tile_count,
           elapsed.as_millis(),
           elapsed.as_millis() as f64 / (EXTENT_CHUNK_TILE_COUNT as f64),
+          queue.len(),
         );
         last_ts = ts;
       }
     }
     connection.execute("END TRANSACTION;").unwrap();
+    uncommitted_chunks.clear();
 
     let mut insert_metadata_stmt = connection
       .prepare(
@@ -93,8 +207,118 @@ pub fn initialize_writer(
       insert_metadata_stmt.next().unwrap();
       insert_metadata_stmt.reset().unwrap();
     }
+    interrupt::mark_partial_output(&connection);
 
-    println!("Output finished, {} tiles", tile_count);
+    if deduplicate {
+      println!(
+        "Output finished, {} tiles ({} duplicates, {} unique tiles)",
+        tile_count,
+        duplicate_count,
+        tile_count - duplicate_count
+      );
+    } else {
+      println!("Output finished, {} tiles", tile_count);
+    }
     connection.execute("PRAGMA journal_mode = DELETE").unwrap();
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mbtiles_tool::reader::Reader;
+  use std::sync::Arc;
+
+  #[test]
+  fn test_deduplicated_writer() {
+    let output = std::env::temp_dir().join(format!("dedup_{}.mbtiles", std::process::id()));
+    let _ = std::fs::remove_file(&output);
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let handle = initialize_writer(output.clone(), rx, HashMap::new(), true, false);
+    let ocean = Arc::new(vec![1, 2, 3]);
+    for tile in [(0, 0, 1), (1, 0, 1), (0, 1, 1)] {
+      tx.send(reader::TileData {
+        tile,
+        data: ocean.clone(),
+        chunk: None,
+      })
+      .unwrap();
+    }
+    tx.send(reader::TileData {
+      tile: (1, 1, 1),
+      data: Arc::new(vec![4, 5]),
+      chunk: None,
+    })
+    .unwrap();
+    drop(tx);
+    handle.join().unwrap();
+
+    let connection = sqlite::open(&output).unwrap();
+    let mut stmt = connection.prepare("SELECT count(*) FROM images").unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<i64>(0).unwrap(), 2);
+    drop(stmt);
+    drop(connection);
+
+    // the reader goes through the tiles view
+    let mut reader = Reader::new(output.clone());
+    let mut tiles: Vec<(tilebelt::Tile, Vec<u8>)> = reader
+      .iter()
+      .map(|t| (tilebelt::flip_x(t.tile), t.data.to_vec()))
+      .collect();
+    tiles.sort();
+    assert_eq!(
+      tiles,
+      vec![
+        ((0, 0, 1), vec![1, 2, 3]),
+        ((0, 1, 1), vec![1, 2, 3]),
+        ((1, 0, 1), vec![1, 2, 3]),
+        ((1, 1, 1), vec![4, 5]),
+      ]
+    );
+    std::fs::remove_file(&output).unwrap();
+  }
+
+  #[test]
+  fn test_resumable_writer() {
+    let output = std::env::temp_dir().join(format!("resumable_{}.mbtiles", std::process::id()));
+    let _ = std::fs::remove_file(&output);
+
+    // a run, then the run that resumes it
+    for (tile, resume) in [((0, 0, 1), false), ((1, 0, 1), true)] {
+      let (tx, rx) = crossbeam_channel::unbounded();
+      let handle = initialize_writer(output.clone(), rx, HashMap::new(), false, resume);
+      tx.send(reader::TileData {
+        tile,
+        data: Arc::new(vec![1, 2, 3]),
+        chunk: None,
+      })
+      .unwrap();
+      drop(tx);
+      handle.join().unwrap();
+    }
+
+    // the write-ahead log is gone once the output is finished
+    let mut wal = output.as_os_str().to_owned();
+    wal.push("-wal");
+    assert!(!PathBuf::from(wal).exists());
+    let connection = sqlite::open(&output).unwrap();
+    let mut stmt = connection.prepare("SELECT count(*) FROM tiles").unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<i64>(0).unwrap(), 2);
+    drop(stmt);
+    drop(connection);
+    std::fs::remove_file(&output).unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "is corrupt")]
+  fn test_resume_corrupt_output() {
+    let output = std::env::temp_dir().join(format!("corrupt_{}.mbtiles", std::process::id()));
+    std::fs::write(&output, vec![7; 4096]).unwrap();
+    let connection = sqlite::open(&output).unwrap();
+    let _ = std::fs::remove_file(&output);
+    set_journal_mode(&connection, &output, true);
+  }
+}